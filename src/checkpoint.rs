@@ -0,0 +1,169 @@
+// annex/src/checkpoint.rs — durable TaskSetPlan progress under .codex/tasks
+
+use anyhow::{Context, Result, bail};
+use chrono::{Datelike, Utc};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fs, path::{Path, PathBuf}};
+
+use crate::{taskset::{TaskSetPlan, TaskSetSpec}, template::StepOutput};
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RunState { Running, Succeeded, Failed, Cancelled }
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StepRecord {
+    pub index: usize,
+    pub id: Option<String>,
+    pub output: StepOutput,
+    pub finished_at: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TaskRecord {
+    pub state: RunState,
+    pub steps: Vec<StepRecord>,
+    pub started_at: String,
+    pub finished_at: Option<String>,
+}
+
+/// Contents of `checkpoint.json`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub session_id: String,
+    /// Git blob hash of the serialized plan the checkpoint was written for.
+    pub plan_hash: String,
+    /// set_id -> task id -> record
+    pub tasks: BTreeMap<String, BTreeMap<String, TaskRecord>>,
+    /// set_id -> ok, for sets that ran to the end (not cancelled)
+    pub finished_sets: BTreeMap<String, bool>,
+    /// Sets replaced through the confirm gate, by position in the plan.
+    #[serde(default)]
+    pub replaced_sets: BTreeMap<usize, TaskSetSpec>,
+    pub updated_at: String,
+}
+
+/// Checkpoint for one session, stored at `.codex/tasks/YYYY-MM-DD/SESSION-UUID/checkpoint.json`
+/// next to the `set-NN.json` specs. Every update rewrites the file atomically, on the blocking
+/// thread pool so the runner's tasks keep going.
+pub struct CheckpointStore {
+    dir: PathBuf,
+    state: Mutex<Checkpoint>,
+    /// Held from taking a state to writing it, so a newer state is never overwritten by an older one.
+    write: tokio::sync::Mutex<()>,
+}
+
+pub fn plan_hash(plan: &TaskSetPlan) -> Result<String> {
+    let bytes = serde_json::to_vec(plan)?;
+    Ok(git2::Oid::hash_object(git2::ObjectType::Blob, &bytes)?.to_string())
+}
+
+fn now() -> String { Utc::now().to_rfc3339() }
+
+impl CheckpointStore {
+    /// Start a fresh checkpoint for `plan` (overwriting an earlier one for the same session today).
+    pub fn create(workspace_root: &Path, plan: &TaskSetPlan) -> Result<Self> {
+        let today = Utc::now();
+        let day = format!("{:04}-{:02}-{:02}", today.year(), today.month(), today.day());
+        let dir = workspace_root.join(".codex").join("tasks").join(day).join(&plan.session_id);
+        fs::create_dir_all(&dir)?;
+        for (i, set) in plan.sets.iter().enumerate() {
+            fs::write(dir.join(format!("set-{:02}.json", i + 1)), serde_json::to_string_pretty(set)?)?;
+        }
+        let state = Checkpoint { session_id: plan.session_id.clone(), plan_hash: plan_hash(plan)?, updated_at: now(), ..Default::default() };
+        flush(&dir, &serde_json::to_string_pretty(&state)?)?;
+        Ok(Self { dir, state: Mutex::new(state), write: Default::default() })
+    }
+
+    /// Load the most recent checkpoint written for `session_id`, if any.
+    pub fn open(workspace_root: &Path, session_id: &str) -> Result<Option<Self>> {
+        let tasks_dir = workspace_root.join(".codex").join("tasks");
+        if !tasks_dir.exists() { return Ok(None); }
+        let mut days: Vec<PathBuf> = fs::read_dir(&tasks_dir)?.filter_map(|e| e.ok().map(|e| e.path())).collect();
+        days.sort();
+        for day in days.into_iter().rev() {
+            let dir = day.join(session_id);
+            let file = dir.join("checkpoint.json");
+            if file.exists() {
+                let text = fs::read_to_string(&file)?;
+                let state: Checkpoint = serde_json::from_str(&text).with_context(|| format!("parse {}", file.display()))?;
+                return Ok(Some(Self { dir, state: Mutex::new(state), write: Default::default() }));
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn dir(&self) -> &Path { &self.dir }
+    pub fn snapshot(&self) -> Checkpoint { self.state.lock().clone() }
+
+    /// Fail if `plan` is not the plan this checkpoint was written for.
+    pub fn ensure_plan_matches(&self, plan: &TaskSetPlan) -> Result<()> {
+        let expected = self.state.lock().plan_hash.clone();
+        let actual = plan_hash(plan)?;
+        if expected != actual {
+            bail!("plan changed since checkpoint in {} was written (checkpoint {}, plan {})", self.dir.display(), expected, actual);
+        }
+        Ok(())
+    }
+
+    pub fn set_succeeded(&self, set_id: &str) -> bool { self.state.lock().finished_sets.get(set_id) == Some(&true) }
+
+    pub fn task_succeeded(&self, set_id: &str, task_id: &str) -> bool {
+        self.state.lock().tasks.get(set_id).and_then(|t| t.get(task_id)).is_some_and(|r| r.state == RunState::Succeeded)
+    }
+
+    pub fn replaced_sets(&self) -> BTreeMap<usize, TaskSetSpec> { self.state.lock().replaced_sets.clone() }
+
+    pub async fn record_replaced_set(&self, index: usize, set: &TaskSetSpec) -> Result<()> {
+        self.update(|c| { c.replaced_sets.insert(index, set.clone()); }).await
+    }
+
+    pub async fn record_task_start(&self, set_id: &str, task_id: &str) -> Result<()> {
+        self.update(|c| {
+            let rec = TaskRecord { state: RunState::Running, steps: vec![], started_at: now(), finished_at: None };
+            c.tasks.entry(set_id.into()).or_default().insert(task_id.into(), rec);
+        }).await
+    }
+
+    pub async fn record_step(&self, set_id: &str, task_id: &str, index: usize, id: Option<&str>, output: &StepOutput) -> Result<()> {
+        self.update(|c| {
+            if let Some(rec) = c.tasks.get_mut(set_id).and_then(|t| t.get_mut(task_id)) {
+                rec.steps.push(StepRecord { index, id: id.map(Into::into), output: output.clone(), finished_at: now() });
+            }
+        }).await
+    }
+
+    pub async fn record_task_end(&self, set_id: &str, task_id: &str, state: RunState) -> Result<()> {
+        self.update(|c| {
+            if let Some(rec) = c.tasks.get_mut(set_id).and_then(|t| t.get_mut(task_id)) {
+                rec.state = state;
+                rec.finished_at = Some(now());
+            }
+        }).await
+    }
+
+    pub async fn record_set_end(&self, set_id: &str, ok: bool) -> Result<()> {
+        self.update(|c| { c.finished_sets.insert(set_id.into(), ok); }).await
+    }
+
+    async fn update(&self, f: impl FnOnce(&mut Checkpoint)) -> Result<()> {
+        let _write = self.write.lock().await;
+        let json = {
+            let mut state = self.state.lock();
+            f(&mut state);
+            state.updated_at = now();
+            serde_json::to_string_pretty(&*state)?
+        };
+        let dir = self.dir.clone();
+        tokio::task::spawn_blocking(move || flush(&dir, &json)).await?
+    }
+}
+
+/// Write `json` to `dir/checkpoint.json` through a temporary file.
+fn flush(dir: &Path, json: &str) -> Result<()> {
+    let tmp = dir.join("checkpoint.json.tmp");
+    fs::write(&tmp, json)?;
+    fs::rename(&tmp, dir.join("checkpoint.json"))?;
+    Ok(())
+}
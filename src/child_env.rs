@@ -0,0 +1,101 @@
+// annex/src/child_env.rs — child process environment from `[shell]` config
+
+use anyhow::{bail, Context, Result};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+use std::collections::BTreeMap;
+
+use crate::layered_config::ShellConfig;
+
+/// Variables kept by `environment_inherit = "core"`.
+pub const CORE_VARS: &[&str] = &[
+    "HOME", "LOGNAME", "PATH", "SHELL", "USER", "USERNAME", "LANG", "TERM", "TMPDIR", "TEMP", "TMP",
+    "SYSTEMROOT", "COMSPEC", "PATHEXT",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Inherit { None, Core, All }
+
+/// Computes the environment of spawned commands: the inherited variables allowed by
+/// `environment_inherit` (default `all`) minus names matching `env_exclude_patterns`
+/// (case-insensitive globs such as `*KEY*`), with explicit variables layered on top.
+#[derive(Clone, Debug)]
+pub struct ChildEnv {
+    inherit: Inherit,
+    exclude: GlobSet,
+}
+
+impl ChildEnv {
+    pub fn new(cfg: &ShellConfig) -> Result<Self> {
+        let inherit = match cfg.environment_inherit.as_deref() {
+            None | Some("all") => Inherit::All,
+            Some("core") => Inherit::Core,
+            Some("none") => Inherit::None,
+            Some(other) => bail!("shell.environment_inherit must be none, core or all, not '{}'", other),
+        };
+        let mut exclude = GlobSetBuilder::new();
+        for p in &cfg.env_exclude_patterns {
+            exclude.add(GlobBuilder::new(p).case_insensitive(true).build().with_context(|| format!("invalid env_exclude_patterns glob: {}", p))?);
+        }
+        Ok(Self { inherit, exclude: exclude.build()? })
+    }
+
+    /// Filter `parent`, then add `explicit` layers in order; explicit variables are never excluded.
+    pub fn build<'e>(&self, parent: impl IntoIterator<Item = (String, String)>, explicit: impl IntoIterator<Item = &'e BTreeMap<String, String>>) -> BTreeMap<String, String> {
+        let mut env: BTreeMap<String, String> = parent.into_iter().filter(|(k, _)| {
+            let inherited = match self.inherit {
+                Inherit::All => true,
+                Inherit::Core => CORE_VARS.iter().any(|c| c.eq_ignore_ascii_case(k)),
+                Inherit::None => false,
+            };
+            inherited && !self.exclude.is_match(k)
+        }).collect();
+        for layer in explicit {
+            env.extend(layer.iter().map(|(k, v)| (k.clone(), v.clone())));
+        }
+        env
+    }
+
+    /// [`build`](Self::build) over this process's environment overlaid with the session's
+    /// (`HookContext::env`, which hosts often fill from `std::env::vars`, so it is filtered too).
+    pub fn for_child<'e>(&self, session: &BTreeMap<String, String>, explicit: impl IntoIterator<Item = &'e BTreeMap<String, String>>) -> BTreeMap<String, String> {
+        let process = std::env::vars_os().filter_map(|(k, v)| Some((k.into_string().ok()?, v.into_string().ok()?)));
+        self.build(process.chain(session.iter().map(|(k, v)| (k.clone(), v.clone()))), explicit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parent() -> Vec<(String, String)> {
+        [("PATH", "/bin"), ("HOME", "/home/me"), ("OPENAI_API_KEY", "sk"), ("github_token", "gh"), ("KEYBOARD", "us"), ("EDITOR", "vi")]
+            .into_iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    fn env(inherit: Option<&str>, exclude: &[&str]) -> Result<ChildEnv> {
+        ChildEnv::new(&ShellConfig {
+            environment_inherit: inherit.map(String::from),
+            env_exclude_patterns: exclude.iter().map(|p| p.to_string()).collect(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn inherit_modes_and_exclude_globs_shape_the_environment() -> Result<()> {
+        let keys = |e: &BTreeMap<String, String>| e.keys().cloned().collect::<Vec<_>>();
+        let all = env(None, &["*_KEY*", "*TOKEN*"])?.build(parent(), []);
+        assert_eq!(keys(&all), ["EDITOR", "HOME", "KEYBOARD", "PATH"]);
+        let broad = env(Some("all"), &["*KEY*"])?.build(parent(), []);
+        assert_eq!(keys(&broad), ["EDITOR", "HOME", "PATH", "github_token"]);
+
+        let step = BTreeMap::from([("API_KEY".to_string(), "explicit".to_string()), ("PATH".to_string(), "/opt/bin".to_string())]);
+        let core = env(Some("core"), &["*KEY*"])?.build(parent(), [&step]);
+        assert_eq!(keys(&core), ["API_KEY", "HOME", "PATH"]);
+        assert_eq!(core["PATH"], "/opt/bin");
+        assert!(env(Some("none"), &[])?.build(parent(), []).is_empty());
+
+        assert!(env(Some("some"), &[]).is_err());
+        assert!(env(None, &["[unclosed"]).is_err());
+        Ok(())
+    }
+}
@@ -95,7 +95,7 @@ fn git_changed_files(repo: &Repository, root: &Path) -> BTreeSet<PathBuf> {
     // Index (staged)
     if let Ok(idx) = repo.index() {
         for e in idx.iter() {
-            if let Some(path) = std::str::from_utf8(&e.path).ok() {
+            if let Ok(path) = std::str::from_utf8(&e.path) {
                 out.insert(root.join(path));
             }
         }
@@ -208,7 +208,7 @@ impl Compactor {
         let todo_refs = todo_file_set(&todos, &self.workspace_root);
         let scores = score_files(&candidates, &changed, &todo_refs, &recent_exec_files);
         let mut ranked: Vec<(PathBuf, u64)> = scores.into_iter().collect();
-        ranked.sort_by(|a,b| b.1.cmp(&a.1));
+        ranked.sort_by_key(|r| std::cmp::Reverse(r.1));
         let chosen: Vec<PathBuf> = ranked.into_iter().map(|(p,_)| p).take(cfg.compact.max_files).collect();
 
         Ok(CompactResult { chosen_files: chosen, focus_prompt })
@@ -219,10 +219,10 @@ impl Compactor {
         let cfg = self.cfg.get();
         if !cfg.compact.auto_enable { return false; }
         if matches!(stage, AutoCompactStage::EndOfTask) && !cfg.compact.auto_on_task_end { return false; }
-        if let Some(t) = last_compact {
-            if let Ok(elapsed) = t.elapsed() {
-                return elapsed >= Duration::from_secs(cfg.compact.auto_min_interval_secs);
-            }
+        if let Some(t) = last_compact
+            && let Ok(elapsed) = t.elapsed()
+        {
+            return elapsed >= Duration::from_secs(cfg.compact.auto_min_interval_secs);
         }
         true
     }
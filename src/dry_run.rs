@@ -0,0 +1,131 @@
+// annex/src/dry_run.rs — preview a TaskSetPlan without calling any bridge or hook action
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    hooks::{HookEvent, HookMatch},
+    taskset::{git_post_event, TaskSetRunner, TaskSpec, TaskStep},
+};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DryRunReport {
+    pub session_id: String,
+    pub sets: Vec<DryRunSet>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DryRunSet {
+    pub set_id: String,
+    pub title: String,
+    pub mode: String,
+    /// Task ids in the order the scheduler considers them.
+    pub schedule: Vec<String>,
+    pub tasks: Vec<DryRunTask>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DryRunTask {
+    pub id: String,
+    pub name: String,
+    pub depends_on: Vec<String>,
+    /// Profile label shown in the UI ("default" when unset).
+    pub model_label: String,
+    pub model: String,
+    pub base_url: Option<String>,
+    /// Rules matching `task_start` / `task_end`.
+    pub hooks: Vec<HookMatch>,
+    pub steps: Vec<DryRunStep>,
+    pub warnings: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DryRunStep {
+    pub index: usize,
+    pub id: Option<String>,
+    pub kind: String,
+    /// Human-readable form of the step (placeholders are left unresolved).
+    pub summary: String,
+    /// Model a chat step would be sent to.
+    pub model: Option<String>,
+    /// Rules matching the step's pre/post events.
+    pub hooks: Vec<HookMatch>,
+    /// Pre-event rules with `deny_on_fail` that could refuse the step.
+    pub may_deny: Vec<String>,
+}
+
+impl TaskSetRunner<'_> {
+    /// Walk the plan and report resolved models and matching hook rules per task and step.
+    /// Nothing is executed: no `do_chat`/`do_exec`/`do_mcp` calls and no hook actions.
+    pub fn dry_run(&self) -> Result<DryRunReport> {
+        self.plan.validate_with(Some(&self.cfg.get().models.profiles))?;
+        let mut sets = vec![];
+        for set in &self.plan.sets {
+            let set = &set.expand_matrix(&self.ctx.cwd)?;
+            let order = set.schedule_order()?;
+            sets.push(DryRunSet {
+                set_id: set.set_id.clone(),
+                title: set.title.clone(),
+                mode: set.mode.clone(),
+                schedule: order.iter().map(|&i| set.tasks[i].id.clone()).collect(),
+                tasks: set.tasks.iter().map(|t| self.dry_run_task(t)).collect(),
+            });
+        }
+        Ok(DryRunReport { session_id: self.plan.session_id.clone(), sets })
+    }
+
+    fn dry_run_task(&self, t: &TaskSpec) -> DryRunTask {
+        let cfg = self.cfg.get();
+        let model = self.task_model(t);
+        let mut warnings = vec![];
+        let mut profile_check = |p: &str| {
+            if !cfg.models.profiles.contains_key(p) {
+                warnings.push(format!("model profile '{}' is not configured; falls back to '{}'", p, model.name));
+            }
+        };
+        if let Some(p) = &t.model_profile { profile_check(p); }
+
+        let mut hooks = self.hooks.preview(&HookEvent::TaskStart { task_name: t.name.clone() });
+        hooks.extend(self.hooks.preview(&HookEvent::TaskEnd { task_name: t.name.clone(), success: true }));
+
+        let mut steps = vec![];
+        for (index, spec) in t.steps.iter().enumerate() {
+            let (summary, chat_model, post) = match &spec.step {
+                TaskStep::Chat { prompt, model_profile } => {
+                    if let Some(p) = model_profile { profile_check(p); }
+                    let chosen = model_profile.as_ref().and_then(|p| cfg.models.profiles.get(p)).unwrap_or(&model);
+                    (prompt.clone(), Some(chosen.name.clone()),
+                     HookEvent::PostToolUse { tool: "chat".into(), result: serde_json::Value::Null })
+                }
+                TaskStep::Exec { cmd, args, .. } => (format!("{} {}", cmd, args.join(" ")).trim_end().to_string(), None,
+                    HookEvent::PostExec { cmd: cmd.clone(), argv: args.clone(), status: 0, stdout_len: 0, stderr_len: 0, limit: None }),
+                TaskStep::McpCall { server, method, .. } => (format!("{}.{}", server, method), None,
+                    HookEvent::PostMcp { server: server.clone(), method: method.clone(), payload: serde_json::Value::Null }),
+                TaskStep::Git { action } => (action.summary(), None, git_post_event(action, serde_json::Value::Null)),
+                TaskStep::SubAgent { agent, steps } => {
+                    profile_check(agent);
+                    let chosen = cfg.models.profiles.get(agent).unwrap_or(&model);
+                    (format!("sub-agent {}: {} step(s)", agent, steps.len()), Some(chosen.name.clone()),
+                     HookEvent::PostToolUse { tool: "sub_agent".into(), result: serde_json::Value::Null })
+                }
+            };
+            let pre_matches = self.hooks.preview(&spec.step.pre_event());
+            let may_deny = pre_matches.iter().filter(|m| m.deny_on_fail).map(|m| m.rule.clone()).collect();
+            let mut hooks = pre_matches;
+            hooks.extend(self.hooks.preview(&post));
+            steps.push(DryRunStep { index, id: spec.id.clone(), kind: spec.step.kind().into(), summary, model: chat_model, hooks, may_deny });
+        }
+
+        DryRunTask {
+            id: t.id.clone(),
+            name: t.name.clone(),
+            depends_on: t.depends_on.clone(),
+            model_label: t.model_profile.clone().unwrap_or_else(|| "default".into()),
+            model: model.name.clone(),
+            base_url: model.base_url.clone(),
+            hooks,
+            steps,
+            warnings,
+        }
+    }
+}
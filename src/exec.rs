@@ -0,0 +1,343 @@
+// annex/src/exec.rs — default `tokio::process` bridge for exec steps
+
+use anyhow::{Context, Result};
+use std::{collections::{BTreeMap, VecDeque}, path::PathBuf, process::Stdio, sync::{atomic::{AtomicU64, Ordering}, Arc}, time::Duration};
+use tokio::{io::{AsyncRead, AsyncReadExt}, process::{Child, Command}};
+use tokio_util::sync::CancellationToken;
+
+use crate::{limits::{LimitGuard, LimitKind, Limits}, sandbox::Sandbox, taskset::ExecFn};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stream { Stdout, Stderr }
+
+/// Receives each output line (without its newline) as the child produces it.
+pub type LineSink = Arc<dyn Fn(Stream, &str) + Send + Sync>;
+
+/// One command for the exec bridge, as prepared by the runner.
+#[derive(Clone)]
+pub struct ExecRequest {
+    pub cmd: String,
+    pub args: Vec<String>,
+    /// The task's worktree in isolated sets.
+    pub cwd: PathBuf,
+    /// The child's complete environment: `[shell]` inherit/exclude settings applied to this
+    /// process's and the hook context's variables, then the step's own `env`.
+    pub env: BTreeMap<String, String>,
+    /// Restrictions from `[sandbox]`.
+    pub sandbox: Sandbox,
+    /// The task's `limits` over `[limits]`.
+    pub limits: Limits,
+    /// Output lines passed here become `UiEvent::TaskProgress`.
+    pub lines: LineSink,
+}
+
+impl ExecRequest {
+    /// A `Command` for this request with piped output: cwd set, environment replaced by `env`,
+    /// limits and sandbox installed, and killed if dropped, plus the guard to keep until it exits.
+    /// Custom bridges should start from this so the `[shell]`, `[sandbox]` and `[limits]` settings
+    /// keep applying, and hold the child in a [`Terminating`] so a step timeout or cancellation
+    /// lets it exit cleanly first; `max_output_bytes` is theirs to enforce.
+    pub fn command(&self) -> Result<(Command, LimitGuard)> {
+        let mut cmd = Command::new(&self.cmd);
+        cmd.args(&self.args).current_dir(&self.cwd).env_clear().envs(&self.env)
+            .stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped())
+            .kill_on_drop(true);
+        let guard = self.limits.apply(&mut cmd)?;
+        self.sandbox.apply(&mut cmd)?;
+        Ok((cmd, guard))
+    }
+}
+
+/// Result of one exec bridge call. `stdout`/`stderr` are previews; the `_len` fields count
+/// every byte the child wrote.
+#[derive(Clone, Debug, Default)]
+pub struct ExecOutput {
+    pub status: i32,
+    pub stdout: String,
+    pub stderr: String,
+    pub stdout_len: usize,
+    pub stderr_len: usize,
+    /// The limit the command was stopped by.
+    pub limit: Option<LimitKind>,
+}
+
+/// How much of each stream is kept: the first `head` and last `tail` bytes.
+#[derive(Clone, Copy, Debug)]
+pub struct PreviewLimits { pub head: usize, pub tail: usize }
+
+impl Default for PreviewLimits {
+    fn default() -> Self { Self { head: 16 * 1024, tail: 16 * 1024 } }
+}
+
+/// Head+tail capture of a stream; the middle is replaced by a `[... N bytes omitted ...]` line.
+#[derive(Debug)]
+pub struct Preview {
+    limits: PreviewLimits,
+    head: Vec<u8>,
+    tail: VecDeque<u8>,
+    total: usize,
+}
+
+impl Preview {
+    pub fn new(limits: PreviewLimits) -> Self {
+        Self { limits, head: vec![], tail: VecDeque::new(), total: 0 }
+    }
+
+    pub fn push(&mut self, mut bytes: &[u8]) {
+        self.total += bytes.len();
+        let room = self.limits.head.saturating_sub(self.head.len()).min(bytes.len());
+        self.head.extend_from_slice(&bytes[..room]);
+        bytes = &bytes[room..];
+        self.tail.extend(bytes);
+        let excess = self.tail.len().saturating_sub(self.limits.tail);
+        self.tail.drain(..excess);
+    }
+
+    /// Bytes pushed so far, omitted ones included.
+    pub fn len(&self) -> usize { self.total }
+
+    pub fn is_empty(&self) -> bool { self.total == 0 }
+
+    pub fn finish(mut self) -> String {
+        let omitted = self.total - self.head.len() - self.tail.len();
+        let mut out = String::from_utf8_lossy(&self.head).into_owned();
+        if omitted > 0 {
+            if !out.ends_with('\n') { out.push('\n'); }
+            out.push_str(&format!("[... {} bytes omitted ...]\n", omitted));
+        }
+        out.push_str(&String::from_utf8_lossy(self.tail.make_contiguous()));
+        out
+    }
+}
+
+/// Run `req`, streaming its output lines to `req.lines` while it runs. A child that writes more
+/// than `req.limits.max_output_bytes` is killed once the read crossing the limit returns.
+pub async fn run(req: &ExecRequest, limits: PreviewLimits) -> Result<ExecOutput> {
+    let (mut cmd, guard) = req.command()?;
+    let mut child = Terminating::new(cmd.spawn().with_context(|| format!("failed to spawn {}", req.cmd))?);
+    let child = child.child();
+    let (stdout, stderr) = (child.stdout.take(), child.stderr.take());
+    let budget = OutputBudget::new(req.limits.max_output_bytes);
+    let (out, err, status) = tokio::try_join!(
+        capture(stdout, Stream::Stdout, limits, &req.lines, &budget),
+        capture(stderr, Stream::Stderr, limits, &req.lines, &budget),
+        budget.wait(child),
+    )?;
+    let limit = if budget.exceeded() { Some(LimitKind::Output) } else { guard.hit(&status) };
+    Ok(ExecOutput {
+        // Killed by a signal: no exit code.
+        status: status.code().unwrap_or(-1),
+        stdout_len: out.len(),
+        stderr_len: err.len(),
+        stdout: out.finish(),
+        stderr: err.finish(),
+        limit,
+    })
+}
+
+/// How long a child dropped while running gets to exit after `SIGTERM` before it is killed.
+pub const KILL_GRACE: Duration = Duration::from_secs(2);
+
+/// Owns a running child. Dropped before the child exited (a step timeout or cancellation drops
+/// the bridge future), it sends the child `SIGTERM` and kills it [`KILL_GRACE`] later if it is
+/// still running. Outside a tokio runtime it is killed right away (`kill_on_drop`).
+pub struct Terminating(Option<Child>);
+
+impl Terminating {
+    pub fn new(child: Child) -> Self { Self(Some(child)) }
+
+    pub fn child(&mut self) -> &mut Child {
+        self.0.as_mut().expect("the child is only taken on drop")
+    }
+}
+
+impl Drop for Terminating {
+    fn drop(&mut self) {
+        let Some(mut child) = self.0.take() else { return };
+        if !matches!(child.try_wait(), Ok(None)) { return; }
+        let Ok(rt) = tokio::runtime::Handle::try_current() else { return };
+        rt.spawn(async move {
+            #[cfg(unix)]
+            if let Some(pid) = child.id() {
+                // SAFETY: plain kill(2) on our own child, which is not reaped until `child` is.
+                unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) };
+                if tokio::time::timeout(KILL_GRACE, child.wait()).await.is_ok() { return; }
+            }
+            child.kill().await.ok();
+        });
+    }
+}
+
+/// Output both streams of a child may still write; `exceeded` fires once they wrote more.
+pub(crate) struct OutputBudget {
+    max: Option<u64>,
+    used: AtomicU64,
+    exceeded: CancellationToken,
+}
+
+impl OutputBudget {
+    pub(crate) fn new(max: Option<u64>) -> Self {
+        Self { max, used: AtomicU64::new(0), exceeded: CancellationToken::new() }
+    }
+
+    pub(crate) fn exceeded(&self) -> bool { self.exceeded.is_cancelled() }
+
+    /// Wait for `child`, killing it once the budget is used up.
+    pub(crate) async fn wait(&self, child: &mut Child) -> Result<std::process::ExitStatus> {
+        tokio::select! {
+            status = child.wait() => Ok(status?),
+            _ = self.exceeded.cancelled() => {
+                child.kill().await?;
+                Ok(child.wait().await?)
+            }
+        }
+    }
+}
+
+/// Bytes read from a pipe at once; the output budget is checked after every read.
+const CHUNK: usize = 8 * 1024;
+/// Longer lines reach the sink in pieces, so output without newlines is never buffered whole.
+const MAX_LINE: usize = 64 * 1024;
+
+/// Read `pipe` in [`CHUNK`]s into a preview, passing its lines to `lines`, until it closes or
+/// `budget` is used up.
+pub(crate) async fn capture(pipe: Option<impl AsyncRead + Unpin>, stream: Stream, limits: PreviewLimits, lines: &LineSink, budget: &OutputBudget) -> Result<Preview> {
+    let mut preview = Preview::new(limits);
+    let Some(mut pipe) = pipe else { return Ok(preview) };
+    let mut chunk = vec![0; CHUNK];
+    let mut line = vec![];
+    let flush = |line: &mut Vec<u8>| {
+        lines(stream, String::from_utf8_lossy(line).trim_end_matches(['\n', '\r']));
+        line.clear();
+    };
+    loop {
+        let n = tokio::select! {
+            n = pipe.read(&mut chunk) => n?,
+            // The other stream used up the budget; the child is being killed.
+            _ = budget.exceeded.cancelled() => break,
+        };
+        if n == 0 { break; }
+        preview.push(&chunk[..n]);
+        for piece in chunk[..n].split_inclusive(|&b| b == b'\n') {
+            line.extend_from_slice(piece);
+            if piece.ends_with(b"\n") || line.len() >= MAX_LINE { flush(&mut line); }
+        }
+        let used = budget.used.fetch_add(n as u64, Ordering::Relaxed) + n as u64;
+        if budget.max.is_some_and(|max| used > max) {
+            budget.exceeded.cancel();
+            break;
+        }
+    }
+    if !line.is_empty() { flush(&mut line); }
+    Ok(preview)
+}
+
+/// [`run`] as a `TaskSetRunner::do_exec` bridge.
+pub fn tokio_exec(limits: PreviewLimits) -> ExecFn {
+    Arc::new(move |req| Box::pin(async move { run(&req, limits).await }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parking_lot::Mutex;
+
+    #[test]
+    fn preview_keeps_head_and_tail() {
+        let mut p = Preview::new(PreviewLimits { head: 4, tail: 3 });
+        p.push(b"ab");
+        p.push(b"cdefgh\n");
+        p.push(b"xyz");
+        assert_eq!(p.len(), 12);
+        assert_eq!(p.finish(), "abcd\n[... 5 bytes omitted ...]\nxyz");
+        let mut p = Preview::new(PreviewLimits { head: 4, tail: 3 });
+        p.push(b"short");
+        assert_eq!(p.finish(), "short");
+    }
+
+    #[tokio::test]
+    async fn run_streams_lines_and_counts_bytes() -> Result<()> {
+        let seen = Arc::new(Mutex::new(vec![]));
+        let sink: LineSink = { let seen = seen.clone(); Arc::new(move |s, l| seen.lock().push((s, l.to_string()))) };
+        let req = ExecRequest {
+            cmd: "sh".into(),
+            args: vec!["-c".into(), "echo $GREETING; echo oops >&2; seq 1 200; exit 3".into()],
+            cwd: ".".into(),
+            env: BTreeMap::from([("GREETING".to_string(), "hi".to_string()), ("PATH".to_string(), std::env::var("PATH")?)]),
+            sandbox: Sandbox::default(),
+            limits: Limits::default(),
+            lines: sink,
+        };
+        let out = run(&req, PreviewLimits { head: 8, tail: 8 }).await?;
+        assert_eq!(out.status, 3);
+        assert_eq!(out.stderr_len, 5);
+        assert_eq!(out.stderr, "oops\n");
+        assert_eq!(out.stdout_len, 3 + (1..=200).map(|n: u32| n.to_string().len() + 1).sum::<usize>());
+        assert!(out.stdout.starts_with("hi\n1\n2\n3\n[... ") && out.stdout.ends_with("199\n200\n"), "{}", out.stdout);
+        let seen = seen.lock();
+        assert_eq!(seen.len(), 202);
+        assert!(seen.contains(&(Stream::Stderr, "oops".to_string())));
+        assert_eq!(seen.iter().rfind(|(s, _)| *s == Stream::Stdout).map(|(_, l)| l.as_str()), Some("200"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn run_kills_a_child_over_its_output_limit() -> Result<()> {
+        let req = ExecRequest {
+            cmd: "yes".into(),
+            args: vec![],
+            cwd: ".".into(),
+            env: BTreeMap::from([("PATH".to_string(), std::env::var("PATH")?)]),
+            sandbox: Sandbox::default(),
+            limits: Limits { max_output_bytes: Some(1000), ..Default::default() },
+            lines: Arc::new(|_, _| {}),
+        };
+        let out = run(&req, PreviewLimits::default()).await?;
+        assert_eq!(out.limit, Some(LimitKind::Output));
+        assert_ne!(out.status, 0);
+        assert!((1001..=1000 + CHUNK).contains(&out.stdout_len), "{}", out.stdout_len);
+
+        // No newline ever: the limit still fires after one read, and the sink gets the bytes in pieces.
+        let pieces = Arc::new(Mutex::new(vec![]));
+        let req = ExecRequest {
+            cmd: "cat".into(),
+            args: vec!["/dev/zero".into()],
+            lines: { let pieces = pieces.clone(); Arc::new(move |_, l| pieces.lock().push(l.len())) },
+            limits: Limits { max_output_bytes: Some(100_000), ..Default::default() },
+            ..req
+        };
+        let out = run(&req, PreviewLimits::default()).await?;
+        assert_eq!(out.limit, Some(LimitKind::Output));
+        assert!((100_001..=100_000 + CHUNK).contains(&out.stdout_len), "{}", out.stdout_len);
+        assert!(pieces.lock().iter().all(|&n| n <= MAX_LINE + CHUNK) && pieces.lock().iter().sum::<usize>() == out.stdout_len);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn dropped_runs_terminate_their_child_then_kill_it() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let req = |script: &str| -> Result<ExecRequest> { Ok(ExecRequest {
+            cmd: "sh".into(),
+            args: vec!["-c".into(), format!("{}; echo $$ > pid; while :; do sleep 0.05; done", script)],
+            cwd: dir.path().into(),
+            env: BTreeMap::from([("PATH".to_string(), std::env::var("PATH")?)]),
+            sandbox: Sandbox::default(),
+            limits: Limits::default(),
+            lines: Arc::new(|_, _| {}),
+        }) };
+        let polite = req("trap 'echo term > got; exit 0' TERM")?;
+        assert!(tokio::time::timeout(Duration::from_millis(500), run(&polite, PreviewLimits::default())).await.is_err());
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(std::fs::read_to_string(dir.path().join("got"))?, "term\n", "SIGTERM comes first");
+
+        let stubborn = req("trap '' TERM")?;
+        assert!(tokio::time::timeout(Duration::from_millis(500), run(&stubborn, PreviewLimits::default())).await.is_err());
+        let pid: libc::pid_t = std::fs::read_to_string(dir.path().join("pid"))?.trim().parse()?;
+        // SAFETY: signal 0 only checks that the process exists.
+        assert_eq!(unsafe { libc::kill(pid, 0) }, 0, "still within its grace period");
+        tokio::time::sleep(KILL_GRACE + Duration::from_millis(500)).await;
+        assert_ne!(unsafe { libc::kill(pid, 0) }, 0, "killed after the grace period");
+        Ok(())
+    }
+}
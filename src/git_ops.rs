@@ -0,0 +1,350 @@
+// annex/src/git_ops.rs — git2-backed actions for TaskStep::Git
+
+use anyhow::{bail, Context, Result};
+use git2::{build::CheckoutBuilder, BranchType, DiffOptions, IndexAddOption, Oid, Repository, Signature, Status, StatusOptions};
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize};
+use std::path::Path;
+
+/// A git operation; its fields sit next to `"type": "git"` in JSON, tagged by `action`.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum GitAction {
+    /// Changed and untracked files, optionally limited to `paths`.
+    Status { #[serde(default)] paths: Vec<String> },
+    /// Patch of the working tree (including staged changes) against HEAD.
+    Diff { #[serde(default)] paths: Vec<String> },
+    /// Stage `paths` (everything when empty), including deletions.
+    Add { #[serde(default)] paths: Vec<String> },
+    /// Commit the index; `all` stages every change first.
+    Commit { message: String, #[serde(default)] all: bool },
+    /// Create a branch at `start` (HEAD when unset) without switching to it.
+    Branch { name: String, #[serde(default)] start: Option<String> },
+    /// Switch to a branch or detach at a revision; `create` makes the branch from HEAD first.
+    Checkout { target: String, #[serde(default)] create: bool },
+    Stash { #[serde(default)] op: StashOp, #[serde(default)] message: Option<String> },
+    /// Tag `target` (HEAD when unset); annotated when `message` is set.
+    Tag { name: String, #[serde(default)] message: Option<String>, #[serde(default)] target: Option<String> },
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StashOp {
+    #[default]
+    Push,
+    Pop,
+    Apply,
+    Drop,
+}
+
+impl StashOp {
+    pub fn name(&self) -> &'static str {
+        match self { StashOp::Push => "push", StashOp::Pop => "pop", StashOp::Apply => "apply", StashOp::Drop => "drop" }
+    }
+}
+
+impl GitAction {
+    pub fn name(&self) -> &'static str {
+        match self {
+            GitAction::Status { .. } => "status",
+            GitAction::Diff { .. } => "diff",
+            GitAction::Add { .. } => "add",
+            GitAction::Commit { .. } => "commit",
+            GitAction::Branch { .. } => "branch",
+            GitAction::Checkout { .. } => "checkout",
+            GitAction::Stash { .. } => "stash",
+            GitAction::Tag { .. } => "tag",
+        }
+    }
+
+    /// Copy with `f` applied to every string field (paths, message, names, revisions).
+    pub fn try_map_strings(&self, f: &mut dyn FnMut(&str) -> Result<String>) -> Result<GitAction> {
+        let opt = |s: &Option<String>, f: &mut dyn FnMut(&str) -> Result<String>| s.as_deref().map(f).transpose();
+        let paths = |p: &[String], f: &mut dyn FnMut(&str) -> Result<String>| p.iter().map(|x| f(x)).collect::<Result<Vec<_>>>();
+        Ok(match self {
+            GitAction::Status { paths: p } => GitAction::Status { paths: paths(p, f)? },
+            GitAction::Diff { paths: p } => GitAction::Diff { paths: paths(p, f)? },
+            GitAction::Add { paths: p } => GitAction::Add { paths: paths(p, f)? },
+            GitAction::Commit { message, all } => GitAction::Commit { message: f(message)?, all: *all },
+            GitAction::Branch { name, start } => GitAction::Branch { name: f(name)?, start: opt(start, f)? },
+            GitAction::Checkout { target, create } => GitAction::Checkout { target: f(target)?, create: *create },
+            GitAction::Stash { op, message } => GitAction::Stash { op: *op, message: opt(message, f)? },
+            GitAction::Tag { name, message, target } => GitAction::Tag { name: f(name)?, message: opt(message, f)?, target: opt(target, f)? },
+        })
+    }
+
+    /// The action a deprecated `"args": [...]` git step ran, for the plain invocations that have
+    /// one: `status`, `add`, `commit` (`-m`, `-a`, `-am`), `branch`, `checkout` (`-b`), `tag` and `stash`.
+    pub fn from_args(args: &[String]) -> Result<GitAction> {
+        let rest: Vec<String> = args.iter().skip(1).cloned().collect();
+        let plain = !rest.iter().any(|a| a.starts_with('-'));
+        let action = match (args.first().map(String::as_str), rest.as_slice()) {
+            (Some("status"), _) if plain => Some(GitAction::Status { paths: rest }),
+            (Some("add"), _) if plain => Some(GitAction::Add { paths: rest }),
+            (Some("commit"), [flag, message]) if flag == "-m" || flag == "-am" => Some(GitAction::Commit { message: message.clone(), all: flag == "-am" }),
+            (Some("commit"), [a, m, message]) if a == "-a" && m == "-m" => Some(GitAction::Commit { message: message.clone(), all: true }),
+            (Some("branch"), [name]) if plain => Some(GitAction::Branch { name: name.clone(), start: None }),
+            (Some("branch"), [name, start]) if plain => Some(GitAction::Branch { name: name.clone(), start: Some(start.clone()) }),
+            (Some("checkout"), [target]) if plain => Some(GitAction::Checkout { target: target.clone(), create: false }),
+            (Some("checkout"), [b, target]) if b == "-b" && !target.starts_with('-') => Some(GitAction::Checkout { target: target.clone(), create: true }),
+            (Some("tag"), [name]) if plain => Some(GitAction::Tag { name: name.clone(), message: None, target: None }),
+            (Some("tag"), [name, target]) if plain => Some(GitAction::Tag { name: name.clone(), message: None, target: Some(target.clone()) }),
+            (Some("stash"), []) => Some(GitAction::Stash { op: StashOp::Push, message: None }),
+            (Some("stash"), [op]) => serde_json::from_value(serde_json::json!(op)).ok().map(|op| GitAction::Stash { op, message: None }),
+            _ => None,
+        };
+        action.with_context(|| format!(
+            "git step args {:?} have no git action; set \"action\" (status, diff, add, commit, branch, checkout, stash, tag) or run git from an exec step",
+            args,
+        ))
+    }
+
+    /// One-line form for previews, e.g. `git commit -m "fix"`.
+    pub fn summary(&self) -> String {
+        let with_paths = |verb: &str, p: &[String]| format!("git {} {}", verb, p.join(" ")).trim_end().to_string();
+        match self {
+            GitAction::Status { paths } => with_paths("status", paths),
+            GitAction::Diff { paths } => with_paths("diff HEAD", paths),
+            GitAction::Add { paths } if paths.is_empty() => "git add --all".into(),
+            GitAction::Add { paths } => with_paths("add", paths),
+            GitAction::Commit { message, all } => format!("git commit{} -m {:?}", if *all { " -a" } else { "" }, message),
+            GitAction::Branch { name, start } => with_paths("branch", &[Some(name.clone()), start.clone()].into_iter().flatten().collect::<Vec<_>>()),
+            GitAction::Checkout { target, create } => format!("git checkout {}{}", if *create { "-b " } else { "" }, target),
+            GitAction::Stash { op, .. } => format!("git stash {}", op.name()),
+            GitAction::Tag { name, target, .. } => with_paths("tag", &[Some(name.clone()), target.clone()].into_iter().flatten().collect::<Vec<_>>()),
+        }
+    }
+}
+
+/// Deserialize the fields of a git step. The deprecated `{"type": "git", "args": [...]}` form is
+/// read through [`GitAction::from_args`] (its free-form `action` is ignored, as it always was).
+pub fn deserialize_step<'de, D: Deserializer<'de>>(d: D) -> std::result::Result<GitAction, D::Error> {
+    let fields = serde_json::Value::deserialize(d)?;
+    match fields.get("args") {
+        Some(args) => {
+            let args: Vec<String> = serde_json::from_value(args.clone()).map_err(D::Error::custom)?;
+            GitAction::from_args(&args).map_err(D::Error::custom)
+        }
+        None => serde_json::from_value(fields).map_err(D::Error::custom),
+    }
+}
+
+/// What an action did: readable `text` plus the files it touched and the commit it produced or moved to.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GitOutcome {
+    pub text: String,
+    pub files: Vec<String>,
+    pub commit: Option<String>,
+}
+
+/// Run `action` against the repository containing `dir`. Blocking; call from `spawn_blocking`.
+pub fn run(dir: &Path, action: &GitAction) -> Result<GitOutcome> {
+    let mut repo = Repository::discover(dir).with_context(|| format!("no git repository at {}", dir.display()))?;
+    match action {
+        GitAction::Status { paths } => {
+            let lines = status_lines(&repo, paths)?;
+            Ok(GitOutcome {
+                text: lines.iter().map(|(code, p)| format!("{} {}", code, p)).collect::<Vec<_>>().join("\n"),
+                files: lines.into_iter().map(|(_, p)| p).collect(),
+                commit: None,
+            })
+        }
+        GitAction::Diff { paths } => {
+            let head = head_tree(&repo)?;
+            let mut opts = DiffOptions::new();
+            paths.iter().for_each(|p| { opts.pathspec(p); });
+            let diff = repo.diff_tree_to_workdir_with_index(head.as_ref(), Some(&mut opts))?;
+            let mut text = String::new();
+            diff.print(git2::DiffFormat::Patch, |_, _, line| {
+                if matches!(line.origin(), '+' | '-' | ' ') { text.push(line.origin()); }
+                text.push_str(&String::from_utf8_lossy(line.content()));
+                true
+            })?;
+            Ok(GitOutcome { text, files: delta_paths(&diff), commit: None })
+        }
+        GitAction::Add { paths } => {
+            stage(&repo, paths)?;
+            let files = staged(&repo)?;
+            Ok(GitOutcome { text: files.join("\n"), files, commit: None })
+        }
+        GitAction::Commit { message, all } => {
+            if *all { stage(&repo, &[])?; }
+            let files = staged(&repo)?;
+            let parent = repo.head().ok().and_then(|h| h.peel_to_commit().ok());
+            if files.is_empty() && parent.is_some() { bail!("nothing to commit"); }
+            let tree = repo.find_tree(repo.index()?.write_tree()?)?;
+            let sig = signature(&repo)?;
+            let oid = repo.commit(Some("HEAD"), &sig, &sig, message, &tree, &parent.iter().collect::<Vec<_>>())?;
+            Ok(GitOutcome { text: format!("{} {}", short(oid), message.lines().next().unwrap_or_default()), files, commit: Some(oid.to_string()) })
+        }
+        GitAction::Branch { name, start } => {
+            let commit = repo.revparse_single(start.as_deref().unwrap_or("HEAD"))?.peel_to_commit()?;
+            repo.branch(name, &commit, false)?;
+            Ok(GitOutcome { text: format!("branch {} at {}", name, short(commit.id())), files: vec![], commit: Some(commit.id().to_string()) })
+        }
+        GitAction::Checkout { target, create } => {
+            if *create {
+                let head = repo.head()?.peel_to_commit()?;
+                repo.branch(target, &head, false)?;
+            }
+            let (commit, branch) = match repo.find_branch(target, BranchType::Local) {
+                Ok(b) => (b.get().peel_to_commit()?, b.get().name().map(String::from)),
+                Err(_) => (repo.revparse_single(target)?.peel_to_commit()?, None),
+            };
+            repo.checkout_tree(commit.as_object(), Some(CheckoutBuilder::new().safe()))?;
+            match &branch {
+                Some(refname) => repo.set_head(refname)?,
+                None => repo.set_head_detached(commit.id())?,
+            }
+            let text = match branch { Some(_) => format!("switched to {}", target), None => format!("detached at {}", short(commit.id())) };
+            Ok(GitOutcome { text, files: vec![], commit: Some(commit.id().to_string()) })
+        }
+        GitAction::Stash { op, message } => match op {
+            StashOp::Push => {
+                let files = status_lines(&repo, &[])?.into_iter().map(|(_, p)| p).collect();
+                let sig = signature(&repo)?;
+                let oid = repo.stash_save2(&sig, message.as_deref(), None)?;
+                Ok(GitOutcome { text: format!("stashed as {}", short(oid)), files, commit: Some(oid.to_string()) })
+            }
+            StashOp::Pop | StashOp::Apply | StashOp::Drop => {
+                match op {
+                    StashOp::Pop => repo.stash_pop(0, None)?,
+                    StashOp::Apply => repo.stash_apply(0, None)?,
+                    _ => repo.stash_drop(0)?,
+                }
+                let files = status_lines(&repo, &[])?.into_iter().map(|(_, p)| p).collect();
+                Ok(GitOutcome { text: format!("stash {} done", op.name()), files, commit: None })
+            }
+        },
+        GitAction::Tag { name, message, target } => {
+            let obj = repo.revparse_single(target.as_deref().unwrap_or("HEAD"))?;
+            let commit = obj.peel_to_commit()?.id();
+            match message {
+                Some(msg) => { repo.tag(name, &obj, &signature(&repo)?, msg, false)?; }
+                None => { repo.tag_lightweight(name, &obj, false)?; }
+            }
+            Ok(GitOutcome { text: format!("tag {} at {}", name, short(commit)), files: vec![], commit: Some(commit.to_string()) })
+        }
+    }
+}
+
+/// Staged, unstaged and untracked paths of the repository containing `dir`, relative to its root.
+pub fn changed_files(dir: &Path) -> Result<Vec<String>> {
+    let repo = Repository::discover(dir).with_context(|| format!("no git repository at {}", dir.display()))?;
+    Ok(status_lines(&repo, &[])?.into_iter().map(|(_, p)| p).collect())
+}
+
+fn signature(repo: &Repository) -> Result<Signature<'static>> {
+    repo.signature().context("git user.name/user.email are not configured")
+}
+
+fn short(oid: Oid) -> String { oid.to_string()[..7].to_string() }
+
+fn head_tree(repo: &Repository) -> Result<Option<git2::Tree<'_>>> {
+    Ok(match repo.head() {
+        Ok(h) => Some(h.peel_to_tree()?),
+        Err(e) if e.code() == git2::ErrorCode::UnbornBranch || e.code() == git2::ErrorCode::NotFound => None,
+        Err(e) => return Err(e.into()),
+    })
+}
+
+/// `git add --all <paths>`: new and modified files are added, deleted ones removed.
+fn stage(repo: &Repository, paths: &[String]) -> Result<()> {
+    let specs: Vec<&str> = if paths.is_empty() { vec!["."] } else { paths.iter().map(String::as_str).collect() };
+    let mut index = repo.index()?;
+    index.add_all(&specs, IndexAddOption::DEFAULT, None)?;
+    index.update_all(&specs, None)?;
+    index.write()?;
+    Ok(())
+}
+
+/// Paths that differ between HEAD and the index.
+fn staged(repo: &Repository) -> Result<Vec<String>> {
+    let head = head_tree(repo)?;
+    let diff = repo.diff_tree_to_index(head.as_ref(), None, None)?;
+    Ok(delta_paths(&diff))
+}
+
+fn delta_paths(diff: &git2::Diff<'_>) -> Vec<String> {
+    diff.deltas()
+        .filter_map(|d| d.new_file().path().or(d.old_file().path()).map(|p| p.to_string_lossy().into_owned()))
+        .collect()
+}
+
+/// `(code, path)` pairs in `git status --porcelain` style, e.g. `("M ", "src/lib.rs")` or `("??", "new.txt")`.
+fn status_lines(repo: &Repository, paths: &[String]) -> Result<Vec<(String, String)>> {
+    let mut opts = StatusOptions::new();
+    opts.include_untracked(true).recurse_untracked_dirs(true);
+    paths.iter().for_each(|p| { opts.pathspec(p); });
+    let statuses = repo.statuses(Some(&mut opts))?;
+    Ok(statuses.iter().filter_map(|e| {
+        let s = e.status();
+        let code = if s.contains(Status::WT_NEW) { "??".to_string() } else {
+            let index = if s.contains(Status::INDEX_NEW) { 'A' } else if s.contains(Status::INDEX_MODIFIED) { 'M' }
+                else if s.contains(Status::INDEX_DELETED) { 'D' } else if s.contains(Status::INDEX_RENAMED) { 'R' }
+                else if s.contains(Status::INDEX_TYPECHANGE) { 'T' } else { ' ' };
+            let wt = if s.contains(Status::WT_MODIFIED) { 'M' } else if s.contains(Status::WT_DELETED) { 'D' }
+                else if s.contains(Status::WT_RENAMED) { 'R' } else if s.contains(Status::WT_TYPECHANGE) { 'T' } else { ' ' };
+            format!("{}{}", index, wt)
+        };
+        if s.is_ignored() || code == "  " { return None; }
+        Some((code, e.path()?.to_string()))
+    }).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn repo() -> Result<(tempfile::TempDir, Repository)> {
+        let dir = tempfile::tempdir()?;
+        let repo = Repository::init(dir.path())?;
+        let mut cfg = repo.config()?;
+        cfg.set_str("user.name", "Test")?;
+        cfg.set_str("user.email", "test@example.com")?;
+        Ok((dir, repo))
+    }
+
+    #[test]
+    fn add_commit_branch_and_tag() -> Result<()> {
+        let (dir, repo) = repo()?;
+        std::fs::write(dir.path().join("a.txt"), "one\n")?;
+        let status = run(dir.path(), &GitAction::Status { paths: vec![] })?;
+        assert_eq!(status.text, "?? a.txt");
+
+        let add = run(dir.path(), &GitAction::Add { paths: vec![] })?;
+        assert_eq!(add.files, vec!["a.txt".to_string()]);
+        let first = run(dir.path(), &GitAction::Commit { message: "first".into(), all: false })?;
+        assert_eq!(first.commit, Some(repo.head()?.peel_to_commit()?.id().to_string()));
+        assert!(run(dir.path(), &GitAction::Commit { message: "empty".into(), all: false }).is_err());
+
+        std::fs::write(dir.path().join("a.txt"), "two\n")?;
+        let diff = run(dir.path(), &GitAction::Diff { paths: vec![] })?;
+        assert_eq!(diff.files, vec!["a.txt".to_string()]);
+        assert!(diff.text.contains("-one\n+two\n"));
+        let second = run(dir.path(), &GitAction::Commit { message: "second".into(), all: true })?;
+        assert_eq!(second.files, vec!["a.txt".to_string()]);
+
+        run(dir.path(), &GitAction::Tag { name: "v1".into(), message: Some("release".into()), target: None })?;
+        let checkout = run(dir.path(), &GitAction::Checkout { target: "feature".into(), create: true })?;
+        assert_eq!(checkout.commit, second.commit);
+        assert_eq!(repo.head()?.shorthand(), Some("feature"));
+        let detached = run(dir.path(), &GitAction::Checkout { target: first.commit.clone().unwrap(), create: false })?;
+        assert!(detached.text.starts_with("detached at"));
+        assert_eq!(std::fs::read_to_string(dir.path().join("a.txt"))?, "one\n");
+        Ok(())
+    }
+
+    #[test]
+    fn stash_push_and_pop() -> Result<()> {
+        let (dir, _repo) = repo()?;
+        std::fs::write(dir.path().join("a.txt"), "one\n")?;
+        run(dir.path(), &GitAction::Commit { message: "init".into(), all: true })?;
+        std::fs::write(dir.path().join("a.txt"), "dirty\n")?;
+        let pushed = run(dir.path(), &GitAction::Stash { op: StashOp::Push, message: Some("wip".into()) })?;
+        assert_eq!(pushed.files, vec!["a.txt".to_string()]);
+        assert_eq!(std::fs::read_to_string(dir.path().join("a.txt"))?, "one\n");
+        run(dir.path(), &GitAction::Stash { op: StashOp::Pop, message: None })?;
+        assert_eq!(std::fs::read_to_string(dir.path().join("a.txt"))?, "dirty\n");
+        Ok(())
+    }
+}
@@ -0,0 +1,195 @@
+// annex/src/hook_match.rs — payload matchers on hook rules
+//
+//   [[rule]]
+//   name = "no-force-push"
+//   when = ["pre_exec"]
+//   match = { cmd = "git", all = [{ arg = "push" }, { any = [{ arg = "--force" }, { arg = "-f" }] }] }
+//
+// A string is a glob whose `*` also crosses `/`; `{ regex = "..." }` is an unanchored regex.
+// Fields: `cmd` (as written or its file name), `argv` (the arguments joined by spaces), `arg` (any
+// single argument), `tool`, `server`, `method`, `task` and `git` (`pre_commit`, `post_commit`,
+// `pre_push`, `post_push`). Every field given must match, as must every matcher in `all`, at least
+// one in a non-empty `any`, and not the one in `not`. A field the event doesn't carry never matches.
+
+use anyhow::{Context, Result};
+use globset::{GlobBuilder, GlobMatcher};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::hooks::HookEvent;
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Matcher {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cmd: Option<Pattern>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub argv: Option<Pattern>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub arg: Option<Pattern>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool: Option<Pattern>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server: Option<Pattern>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub method: Option<Pattern>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub task: Option<Pattern>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub git: Option<Pattern>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub all: Vec<Matcher>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub any: Vec<Matcher>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub not: Option<Box<Matcher>>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Pattern {
+    Glob(String),
+    Regex { regex: String },
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Field { Cmd, Argv, Arg, Tool, Server, Method, Task, Git }
+
+#[derive(Clone, Debug)]
+enum Compiled { Glob(GlobMatcher), Regex(Regex) }
+
+impl Compiled {
+    fn is_match(&self, s: &str) -> bool {
+        match self {
+            Compiled::Glob(g) => g.is_match(s),
+            Compiled::Regex(r) => r.is_match(s),
+        }
+    }
+}
+
+/// A [`Matcher`] with its patterns compiled, ready to test events.
+#[derive(Clone, Debug)]
+pub struct EventMatcher {
+    fields: Vec<(Field, Compiled)>,
+    all: Vec<EventMatcher>,
+    any: Vec<EventMatcher>,
+    not: Option<Box<EventMatcher>>,
+}
+
+impl Matcher {
+    pub fn compile(&self) -> Result<EventMatcher> {
+        let named = [
+            (Field::Cmd, "cmd", &self.cmd), (Field::Argv, "argv", &self.argv), (Field::Arg, "arg", &self.arg),
+            (Field::Tool, "tool", &self.tool), (Field::Server, "server", &self.server), (Field::Method, "method", &self.method),
+            (Field::Task, "task", &self.task), (Field::Git, "git", &self.git),
+        ];
+        let mut fields = vec![];
+        for (field, name, pattern) in named {
+            let Some(pattern) = pattern else { continue };
+            let compiled = match pattern {
+                Pattern::Glob(g) => Compiled::Glob(GlobBuilder::new(g).build().with_context(|| format!("match.{}: invalid glob '{}'", name, g))?.compile_matcher()),
+                Pattern::Regex { regex } => Compiled::Regex(Regex::new(regex).with_context(|| format!("match.{}: invalid regex '{}'", name, regex))?),
+            };
+            fields.push((field, compiled));
+        }
+        let nested = |ms: &[Matcher]| ms.iter().map(Matcher::compile).collect::<Result<Vec<_>>>();
+        Ok(EventMatcher {
+            fields,
+            all: nested(&self.all)?,
+            any: nested(&self.any)?,
+            not: self.not.as_deref().map(Matcher::compile).transpose()?.map(Box::new),
+        })
+    }
+}
+
+impl EventMatcher {
+    pub fn matches(&self, ev: &HookEvent) -> bool {
+        self.fields.iter().all(|(field, p)| values(ev, *field).iter().any(|v| p.is_match(v)))
+            && self.all.iter().all(|m| m.matches(ev))
+            && (self.any.is_empty() || self.any.iter().any(|m| m.matches(ev)))
+            && !self.not.as_ref().is_some_and(|m| m.matches(ev))
+    }
+}
+
+/// Values of `field` in `ev`; a pattern matches when it matches any of them.
+fn values(ev: &HookEvent, field: Field) -> Vec<String> {
+    use HookEvent::*;
+    match (field, ev) {
+        (Field::Cmd, PreExec { cmd, .. } | PostExec { cmd, .. }) => {
+            let name = std::path::Path::new(cmd).file_name().map(|n| n.to_string_lossy().into_owned());
+            std::iter::once(cmd.clone()).chain(name.filter(|n| n != cmd)).collect()
+        }
+        (Field::Argv, PreExec { argv, .. } | PostExec { argv, .. }) => vec![argv.join(" ")],
+        (Field::Arg, PreExec { argv, .. } | PostExec { argv, .. }) => argv.clone(),
+        (Field::Tool, PreToolUse { tool, .. } | PostToolUse { tool, .. }) => vec![tool.clone()],
+        (Field::Server, PreMcp { server, .. } | PostMcp { server, .. }) => vec![server.clone()],
+        (Field::Method, PreMcp { method, .. } | PostMcp { method, .. }) => vec![method.clone()],
+        (Field::Task, TaskStart { task_name } | TaskProgress { task_name, .. } | TaskEnd { task_name, .. }) => vec![task_name.clone()],
+        (Field::Git, Git { kind }) => vec![kind.as_str().into()],
+        _ => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hooks::GitEvent;
+
+    fn exec(cmd: &str, argv: &[&str]) -> HookEvent {
+        HookEvent::PreExec { cmd: cmd.into(), argv: argv.iter().map(|a| a.to_string()).collect() }
+    }
+
+    #[test]
+    fn matchers_combine_fields_globs_and_regexes() -> Result<()> {
+        let parse = |src: &str| -> Result<EventMatcher> { toml::from_str::<Matcher>(src)?.compile() };
+        let force_push = parse(r#"
+            cmd = "git"
+            all = [{ arg = "push" }, { any = [{ arg = "--force*" }, { arg = "-f" }] }]
+        "#)?;
+        assert!(force_push.matches(&exec("/usr/bin/git", &["push", "-f", "origin"])));
+        assert!(force_push.matches(&exec("git", &["push", "--force-with-lease"])));
+        assert!(!force_push.matches(&exec("git", &["push", "origin"])));
+        assert!(!force_push.matches(&exec("gitk", &["push", "-f"])));
+
+        let rm_rf = parse(r#"argv = { regex = '(^|\s)rm\s+-(rf|fr)\b' }
+not = { argv = "* /tmp/*" }"#)?;
+        assert!(rm_rf.matches(&exec("bash", &["-lc", "cd x && rm -rf /"])));
+        assert!(!rm_rf.matches(&exec("bash", &["-lc", "rm -rf /tmp/build"])));
+        assert!(!rm_rf.matches(&HookEvent::TaskStart { task_name: "rm -rf".into() }), "events without argv never match");
+
+        let mcp = parse(r#"server = "git*"
+method = { regex = "^(push|delete)_" }"#)?;
+        assert!(mcp.matches(&HookEvent::PreMcp { server: "github".into(), method: "push_files".into(), payload: serde_json::Value::Null }));
+        assert!(!mcp.matches(&HookEvent::PreMcp { server: "github".into(), method: "get_file".into(), payload: serde_json::Value::Null }));
+        assert!(parse(r#"git = "pre_*""#)?.matches(&HookEvent::Git { kind: GitEvent::PrePush }));
+        assert!(parse(r#"task = "build*""#)?.matches(&HookEvent::TaskEnd { task_name: "build [linux]".into(), success: true }));
+        assert!(parse("").is_ok_and(|m| m.matches(&exec("ls", &[]))));
+
+        assert!(parse(r#"cmd = { regex = "(" }"#).is_err());
+        assert!(parse(r#"command = "git""#).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn registry_fires_rules_only_for_matching_payloads() -> Result<()> {
+        use crate::{hooks::HookRegistry, layered_config::ConfigManager};
+        let dir = tempfile::tempdir()?;
+        let cfg = std::sync::Arc::new(ConfigManager::for_paths(dir.path().join("s.toml"), dir.path().join("u.toml"), dir.path().join("w.toml"))?);
+        std::fs::write(dir.path().join("guard.toml"), r#"
+[[rule]]
+name = "no-force-push"
+when = ["pre_exec"]
+deny_on_fail = true
+match = { cmd = "git", all = [{ arg = "push" }, { arg = "--force" }] }
+actions = [{ kind = "exec", cmd = "false", args = [] }]
+"#)?;
+        let hooks = HookRegistry::load_from_dirs(cfg.clone(), &[dir.path().to_path_buf()])?;
+        assert_eq!(hooks.preview(&exec("git", &["push", "--force"])).len(), 1);
+        assert!(hooks.preview(&exec("git", &["push"])).is_empty());
+
+        std::fs::write(dir.path().join("guard.toml"), "[[rule]]\nname = \"bad\"\nwhen = [\"pre_exec\"]\nmatch = { cmd = { regex = \"[\" } }\nactions = []\n")?;
+        let err = HookRegistry::load_from_dirs(cfg, &[dir.path().to_path_buf()]).err().context("bad regex accepted")?;
+        assert!(format!("{:#}", err).contains("hook rule 'bad'"), "{:#}", err);
+        Ok(())
+    }
+}
@@ -1,13 +1,20 @@
 // annex/src/hooks.rs — TOML rules engine with plugin handlers
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use async_trait::async_trait;
-use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
-use std::{collections::BTreeMap, fs, path::PathBuf, sync::Arc};
-use tokio::process::Command;
+use std::{collections::BTreeMap, fs, path::{Path, PathBuf}, process::Stdio, sync::Arc, time::Duration};
+use tokio::{io::AsyncWriteExt, process::Command, sync::{watch, Semaphore}};
 
-use crate::layered_config::{ConfigManager, ModelRole};
+use crate::{
+    layered_config::{ConfigManager, ModelRole, ModelTarget},
+    shell_policy::{Decision, ShellPolicy},
+    child_env::ChildEnv,
+    sandbox::Sandbox,
+    limits::LimitKind,
+    exec::{capture, LineSink, OutputBudget, PreviewLimits, Stream},
+    hook_match::{EventMatcher, Matcher},
+};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct HookContext { pub cwd: PathBuf, pub session_id: String, pub env: BTreeMap<String,String> }
@@ -18,7 +25,8 @@ pub enum HookEvent {
     PreToolUse { tool: String, args: serde_json::Value },
     PostToolUse { tool: String, result: serde_json::Value },
     PreExec { cmd: String, argv: Vec<String> },
-    PostExec { cmd: String, argv: Vec<String>, status: i32, stdout_len: usize, stderr_len: usize },
+    /// `limit` names the resource limit that stopped the command, if one did.
+    PostExec { cmd: String, argv: Vec<String>, status: i32, stdout_len: usize, stderr_len: usize, #[serde(default)] limit: Option<LimitKind> },
     PreMcp { server: String, method: String, payload: serde_json::Value },
     PostMcp { server: String, method: String, payload: serde_json::Value },
     TaskStart { task_name: String },
@@ -27,25 +35,235 @@ pub enum HookEvent {
     Git { kind: GitEvent },
 }
 
+impl HookEvent {
+    /// The name rules list in `when`, e.g. `pre_exec`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            HookEvent::PreToolUse{..} => "pre_tool_use",
+            HookEvent::PostToolUse{..} => "post_tool_use",
+            HookEvent::PreExec{..} => "pre_exec",
+            HookEvent::PostExec{..} => "post_exec",
+            HookEvent::PreMcp{..} => "pre_mcp",
+            HookEvent::PostMcp{..} => "post_mcp",
+            HookEvent::TaskStart{..} => "task_start",
+            HookEvent::TaskProgress{..} => "task_progress",
+            HookEvent::TaskEnd{..} => "task_end",
+            HookEvent::Git{..} => "git",
+        }
+    }
+
+    /// `CODEX_HOOK_*` variables exec actions get for the event's key fields.
+    fn env(&self) -> BTreeMap<String, String> {
+        let mut env = BTreeMap::from([("CODEX_HOOK_EVENT".to_string(), self.kind().to_string())]);
+        let mut set = |k: &str, v: String| { env.insert(format!("CODEX_HOOK_{}", k), v); };
+        match self {
+            HookEvent::PreToolUse { tool, .. } | HookEvent::PostToolUse { tool, .. } => set("TOOL", tool.clone()),
+            HookEvent::PreExec { cmd, argv } => {
+                set("CMD", cmd.clone());
+                set("ARGV", argv.join(" "));
+            }
+            HookEvent::PostExec { cmd, argv, status, limit, .. } => {
+                set("CMD", cmd.clone());
+                set("ARGV", argv.join(" "));
+                set("STATUS", status.to_string());
+                if let Some(l) = limit { set("LIMIT", l.as_str().into()); }
+            }
+            HookEvent::PreMcp { server, method, .. } | HookEvent::PostMcp { server, method, .. } => {
+                set("MCP_SERVER", server.clone());
+                set("MCP_METHOD", method.clone());
+            }
+            HookEvent::TaskStart { task_name } => set("TASK_NAME", task_name.clone()),
+            HookEvent::TaskProgress { task_name, status_line } => {
+                set("TASK_NAME", task_name.clone());
+                set("STATUS_LINE", status_line.clone());
+            }
+            HookEvent::TaskEnd { task_name, success } => {
+                set("TASK_NAME", task_name.clone());
+                set("TASK_SUCCESS", success.to_string());
+            }
+            HookEvent::Git { kind } => set("GIT_EVENT", kind.as_str().into()),
+        }
+        env
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum GitEvent { PreCommit, PostCommit, PrePush, PostPush }
 
+impl GitEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            GitEvent::PreCommit => "pre_commit",
+            GitEvent::PostCommit => "post_commit",
+            GitEvent::PrePush => "pre_push",
+            GitEvent::PostPush => "post_push",
+        }
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
-pub enum HookDecision { Continue, Deny { reason: String } }
+pub enum HookDecision {
+    Continue,
+    Deny { reason: String },
+    /// Continue, passing on what hooks had to say (one message per line).
+    Message { text: String },
+    /// Continue with `event` instead of the emitted one, as rewritten by `rules` in order
+    /// (each rule matched and saw the previous rule's version). `text` as in `Message`.
+    Modify { event: HookEvent, rules: Vec<String>, #[serde(default)] text: Option<String> },
+}
+
+/// One field a hook changed, e.g. `argv` or `payload.query`; see [`diff`].
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EventChange {
+    pub path: String,
+    pub before: serde_json::Value,
+    pub after: serde_json::Value,
+}
+
+/// Fields that differ between two versions of an event. Objects are compared key by key;
+/// arrays and other values as a whole.
+pub fn diff(before: &HookEvent, after: &HookEvent) -> Vec<EventChange> {
+    fn walk(path: &str, a: &serde_json::Value, b: &serde_json::Value, out: &mut Vec<EventChange>) {
+        match (a, b) {
+            (serde_json::Value::Object(x), serde_json::Value::Object(y)) => {
+                let keys: std::collections::BTreeSet<&String> = x.keys().chain(y.keys()).collect();
+                for k in keys {
+                    let null = serde_json::Value::Null;
+                    let at = if path.is_empty() { k.clone() } else { format!("{}.{}", path, k) };
+                    walk(&at, x.get(k).unwrap_or(&null), y.get(k).unwrap_or(&null), out);
+                }
+            }
+            _ if a != b => out.push(EventChange { path: path.into(), before: a.clone(), after: b.clone() }),
+            _ => {}
+        }
+    }
+    let mut out = vec![];
+    let value = |e: &HookEvent| serde_json::to_value(e).unwrap_or_default();
+    walk("", &value(before), &value(after), &mut out);
+    out
+}
+
+/// What an exec action reads on stdin.
+#[derive(Serialize)]
+struct HookInput<'a> { rule: &'a str, session_id: &'a str, cwd: &'a Path, event: &'a HookEvent }
+
+/// Optional JSON object an exec action prints on stdout, e.g.
+/// `{"decision": "deny", "reason": "no force pushes"}`. `deny` refuses the event whether or not
+/// the rule has `deny_on_fail`; `allow` makes the action succeed whatever its exit status;
+/// `message` is passed on in [`HookDecision::Message`]. `modify` (or just an `event`) replaces the
+/// event with `event`, an event of the same type; it also succeeds whatever the exit status.
+/// Output that isn't a JSON object is ignored.
+#[derive(Debug, Default, Deserialize)]
+pub struct HookReply {
+    #[serde(default)]
+    pub decision: Option<ReplyDecision>,
+    #[serde(default)]
+    pub reason: Option<String>,
+    #[serde(default)]
+    pub message: Option<String>,
+    #[serde(default)]
+    pub event: Option<HookEvent>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplyDecision { Allow, Deny, Modify }
+
+impl HookReply {
+    pub fn parse(stdout: &str) -> Result<Self> {
+        let text = stdout.trim();
+        if !text.starts_with('{') { return Ok(Self::default()); }
+        let reply: Self = serde_json::from_str(text).context("invalid hook reply on stdout")?;
+        if reply.decision == Some(ReplyDecision::Modify) && reply.event.is_none() {
+            bail!("hook reply says modify but has no event");
+        }
+        Ok(reply)
+    }
+}
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(tag="kind", rename_all="snake_case")]
 pub enum HookAction {
     Exec { cmd: String, args: Vec<String> },
-    Prompt { model_profile: Option<String>, instruction: String, max_tokens: Option<u32> },
+    /// Ask a model (see [`HookModel`]) about the event. Its answer becomes a message, or with
+    /// `answer = "verdict"` is read as `{"decision": "allow"|"deny", "reason": ...}`. When the model
+    /// is missing, fails, takes longer than the action's `timeout_secs` (default 60) or gives no
+    /// verdict, the action takes `fallback`, or fails when there is none.
+    Prompt {
+        model_profile: Option<String>,
+        instruction: String,
+        max_tokens: Option<u32>,
+        #[serde(default)]
+        answer: PromptAnswer,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        fallback: Option<PromptFallback>,
+    },
     Plugin { handler: String, #[serde(default)] config: serde_json::Value },
 }
 
+/// An entry of a rule's `actions`: the action and how it runs, e.g.
+/// `{ kind = "exec", cmd = "notify", args = [], timeout_secs = 10, async = true }`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HookActionSpec {
+    #[serde(flatten)]
+    pub action: HookAction,
+    /// An action still running after this many seconds fails (its command is killed); this counts
+    /// for `deny_on_fail` and is written to `.codex/hooks.log`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_secs: Option<u64>,
+    /// Run in the background, at most `[hooks] max_async` at once, without holding up `emit`.
+    /// Such an action can't deny, rewrite or annotate the event, so loading refuses it on rules
+    /// with `deny_on_fail` or pre events (`pre_*`, `git`); it suits post events like `post_exec`
+    /// and `task_end`. Its failures are written to `.codex/hooks.log`.
+    #[serde(default, rename = "async", skip_serializing_if = "std::ops::Not::not")]
+    pub background: bool,
+}
+
+/// How a prompt action uses the model's answer.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PromptAnswer {
+    /// Passed on as a message.
+    #[default]
+    Annotation,
+    /// Allow or deny the event.
+    Verdict,
+}
+
+/// What a prompt action decides when the model gives no answer; a fallback can't rewrite the
+/// event, so `modify` is refused at load.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PromptFallback { Allow, Deny }
+
+impl From<PromptFallback> for ReplyDecision {
+    fn from(f: PromptFallback) -> Self {
+        match f {
+            PromptFallback::Allow => ReplyDecision::Allow,
+            PromptFallback::Deny => ReplyDecision::Deny,
+        }
+    }
+}
+
+const PROMPT_TIMEOUT_SECS: u64 = 60;
+const MAX_ASYNC: u32 = 4;
+/// stdout and stderr together; an exec action that writes more is killed and fails.
+const MAX_OUTPUT_BYTES: u64 = 1024 * 1024;
+
+/// Sends prompt actions to a model; registered with [`HookRegistry::set_model`].
+#[async_trait]
+pub trait HookModel: Send + Sync {
+    async fn complete(&self, target: &ModelTarget, prompt: &str, max_tokens: Option<u32>) -> Result<String>;
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct HookRule {
     pub name: String,
     pub when: Vec<String>,   // e.g., ["pre_exec","post_exec","task_end"]
-    pub actions: Vec<HookAction>,
+    /// Narrows `when` by the event's payload (see [`crate::hook_match`]).
+    #[serde(default, rename = "match", skip_serializing_if = "Option::is_none")]
+    pub matcher: Option<Matcher>,
+    pub actions: Vec<HookActionSpec>,
     #[serde(default)]
     pub deny_on_fail: bool,
     #[serde(default="default_true")]
@@ -53,14 +271,53 @@ pub struct HookRule {
 }
 fn default_true() -> bool { true }
 
+/// A rule that would fire for an event, as reported by [`HookRegistry::preview`].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HookMatch {
+    pub rule: String,
+    /// One line per action, e.g. `exec: bash -lc ...` or `plugin: audit_log`.
+    pub actions: Vec<String>,
+    /// The rule turns a failing action into `HookDecision::Deny`.
+    pub deny_on_fail: bool,
+}
+
 pub struct HookRegistry {
-    rules: Vec<HookRule>,
-    depth: Arc<Mutex<usize>>,
+    rules: Vec<(HookRule, Option<EventMatcher>)>,
     recursion_limit: usize,
+    actions: Arc<ActionRunner>,
+    /// Slots for async actions.
+    background: Arc<Semaphore>,
+    /// Async actions started and not yet finished.
+    pending: Arc<watch::Sender<usize>>,
+}
+
+tokio::task_local! {
+    /// Nesting of `emit` calls on the current call chain.
+    static EMIT_DEPTH: usize;
+}
+
+fn current_depth() -> usize { EMIT_DEPTH.try_with(|d| *d).unwrap_or(0) }
+
+/// What running an action needs; shared with the actions running in the background.
+#[derive(Clone)]
+struct ActionRunner {
     cfg: Arc<ConfigManager>,
     plugins: BTreeMap<String, Arc<dyn HookActionHandler>>, // by handler name
+    model: Option<Arc<dyn HookModel>>,
+}
+
+/// Error of an action that ran past its `timeout_secs`.
+#[derive(Debug)]
+struct TimedOut { rule: String, secs: u64 }
+
+impl std::fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "hook {} timed out after {}s", self.rule, self.secs)
+    }
 }
 
+impl std::error::Error for TimedOut {}
+
 impl HookRegistry {
     pub fn load_from_dirs(cfg: Arc<ConfigManager>, dirs: &[PathBuf]) -> Result<Self> {
         let mut rules = vec![];
@@ -77,90 +334,304 @@ impl HookRegistry {
                             if let Some(mut v) = f.rule.take() { rules.append(&mut v); }
                             if let Some(mut v) = f.rules.take() { rules.append(&mut v); }
                         }
-                        Err(_) => {
+                        Err(e) => {
                             // Try Vec<HookRule>
                             if let Ok(mut v) = toml::from_str::<Vec<HookRule>>(&text) { rules.append(&mut v); }
-                            else { return Err(anyhow!("invalid hook file: {}", p.display())); }
+                            else { return Err(e.context(format!("invalid hook file: {}", p.display()))); }
                         }
                     }
                 }
             }
         }
+        let rules = rules.into_iter().map(|r| {
+            let matcher = r.matcher.as_ref().map(Matcher::compile).transpose().with_context(|| format!("hook rule '{}'", r.name))?;
+            Ok((r, matcher))
+        }).collect::<Result<_>>()?;
         let recursion_limit = cfg.get().hooks.recursion_limit.unwrap_or(3) as usize;
+        let max_async = cfg.get().hooks.max_async.unwrap_or(MAX_ASYNC).max(1) as usize;
         // Register built-in plugin(s)
-        let mut me = Self { rules, depth: Arc::new(Mutex::new(0)), recursion_limit, cfg, plugins: BTreeMap::new() };
+        let mut me = Self {
+            rules, recursion_limit,
+            actions: Arc::new(ActionRunner { cfg, plugins: BTreeMap::new(), model: None }),
+            background: Arc::new(Semaphore::new(max_async)),
+            pending: Arc::new(watch::Sender::new(0)),
+        };
         me.register_plugin("audit_log", Arc::new(AuditLogPlugin));
+        for (r, _) in &me.rules {
+            for spec in &r.actions {
+                if let HookAction::Plugin { handler, .. } = &spec.action && !me.actions.plugins.contains_key(handler) {
+                    bail!("hook rule '{}': unknown plugin handler: {}", r.name, handler);
+                }
+            }
+            // An async action's outcome arrives after the event went ahead, so it can't guard it.
+            if r.actions.iter().any(|a| a.background) {
+                if r.deny_on_fail { bail!("hook rule '{}': async actions can't deny, so the rule can't set deny_on_fail", r.name); }
+                if let Some(w) = r.when.iter().find(|w| w.starts_with("pre_") || *w == "git") {
+                    bail!("hook rule '{}': async actions can't run on {} events, which wait for the rule's verdict", r.name, w);
+                }
+            }
+        }
         Ok(me)
     }
 
-    pub fn register_plugin(&mut self, name: &str, handler: Arc<dyn HookActionHandler>) { self.plugins.insert(name.into(), handler); }
+    pub fn register_plugin(&mut self, name: &str, handler: Arc<dyn HookActionHandler>) { Arc::make_mut(&mut self.actions).plugins.insert(name.into(), handler); }
+
+    pub fn set_model(&mut self, model: Arc<dyn HookModel>) { Arc::make_mut(&mut self.actions).model = Some(model); }
 
+    /// Wait until the async actions started so far have finished.
+    pub async fn drain(&self) {
+        self.pending.subscribe().wait_for(|n| *n == 0).await.ok();
+    }
+
+    /// Run the rules for `event`. An action that emits again (a plugin, say) nests one level
+    /// deeper on the same call chain; past `recursion_limit` levels `emit` returns `Continue`.
     pub async fn emit(&self, ctx: &HookContext, event: &HookEvent) -> Result<HookDecision> {
-        {
-            let mut d = self.depth.lock();
-            if *d >= self.recursion_limit { return Ok(HookDecision::Continue); }
-            *d += 1;
-        }
-        let res = self.emit_inner(ctx, event).await;
-        *self.depth.lock() -= 1;
-        res
+        let depth = current_depth();
+        if depth >= self.recursion_limit { return Ok(HookDecision::Continue); }
+        // The depth only holds while this future is polled, so an emit dropped halfway (a step
+        // timeout, cancellation) leaves nothing behind, and concurrent tasks don't share it.
+        EMIT_DEPTH.scope(depth + 1, self.emit_inner(ctx, event)).await
+    }
+
+    /// Rules that `emit` would run for `event`, without running any of their actions.
+    pub fn preview(&self, event: &HookEvent) -> Vec<HookMatch> {
+        self.rules.iter().filter(|(r, m)| r.enabled && rule_matches(r, m.as_ref(), event)).map(|(r, _)| HookMatch {
+            rule: r.name.clone(),
+            actions: r.actions.iter().map(|spec| {
+                let line = match &spec.action {
+                    HookAction::Exec { cmd, args } => format!("exec: {} {}", cmd, args.join(" ")).trim_end().to_string(),
+                    HookAction::Prompt { model_profile, .. } => format!("prompt: {}", model_profile.as_deref().unwrap_or("default")),
+                    HookAction::Plugin { handler, .. } => format!("plugin: {}", handler),
+                };
+                if spec.background { format!("{} (async)", line) } else { line }
+            }).collect(),
+            deny_on_fail: r.deny_on_fail,
+        }).collect()
     }
 
     async fn emit_inner(&self, ctx: &HookContext, event: &HookEvent) -> Result<HookDecision> {
-        for r in &self.rules {
+        let mut messages = vec![];
+        // Rules see the event as rewritten by the rules before them.
+        let mut event = std::borrow::Cow::Borrowed(event);
+        let mut modified_by: Vec<String> = vec![];
+        for (r, matcher) in &self.rules {
             if !r.enabled { continue; }
-            if !rule_matches(r, event) { continue; }
-            for a in &r.actions {
-                let res: Result<()> = match a {
-                    HookAction::Exec { cmd, args } => {
-                        let status = Command::new(cmd).args(args).current_dir(&ctx.cwd).status().await?;
-                        if !status.success() { anyhow::bail!("exec failed: {}", r.name); }
-                        Ok(())
-                    }
-                    HookAction::Prompt { model_profile, instruction: _instruction, max_tokens: _ } => {
-                        // Select model only; actual send is host-responsibility
-                        let _model = if let Some(p) = model_profile {
-                            self.cfg.get().models.profiles.get(p).cloned()
-                                .unwrap_or(self.cfg.pick_model(ModelRole::Chat))
-                        } else { self.cfg.pick_model(ModelRole::Chat) };
-                        Ok(())
-                    }
-                    HookAction::Plugin { handler, config } => {
-                        let h = self.plugins.get(handler).ok_or_else(|| anyhow!("unknown plugin handler: {}", handler))?;
-                        h.run(ctx, event, config).await
+            if !rule_matches(r, matcher.as_ref(), &event) { continue; }
+            for spec in &r.actions {
+                if spec.background {
+                    self.spawn(ctx, &r.name, spec, &event);
+                    continue;
+                }
+                let res = match self.actions.run(ctx, &r.name, spec, &event).await {
+                    Ok(reply) => {
+                        messages.extend(reply.message);
+                        if reply.decision == Some(ReplyDecision::Deny) {
+                            return Ok(HookDecision::Deny { reason: reply.reason.unwrap_or_else(|| format!("denied by hook: {}", r.name)) });
+                        }
+                        match reply.event {
+                            Some(new) if new.kind() != event.kind() => Err(anyhow!("hook {} turned a {} event into {}", r.name, event.kind(), new.kind())),
+                            new => Ok(new),
+                        }
                     }
+                    Err(e) => Err(e),
                 };
-                if let Err(e) = res {
-                    if r.deny_on_fail { return Ok(HookDecision::Deny { reason: e.to_string() }); }
+                match res {
+                    Ok(Some(new)) => {
+                        event = std::borrow::Cow::Owned(new);
+                        if !modified_by.contains(&r.name) { modified_by.push(r.name.clone()); }
+                    }
+                    Ok(None) => {}
+                    Err(e) if r.deny_on_fail => return Ok(HookDecision::Deny { reason: e.to_string() }),
+                    Err(_) => {}
+                }
+            }
+        }
+        let text = (!messages.is_empty()).then(|| messages.join("\n"));
+        Ok(match (modified_by.is_empty(), text) {
+            (false, text) => HookDecision::Modify { event: event.into_owned(), rules: modified_by, text },
+            (true, Some(text)) => HookDecision::Message { text },
+            (true, None) => HookDecision::Continue,
+        })
+    }
+
+    /// Run `spec` on a task of its own once a background slot is free.
+    fn spawn(&self, ctx: &HookContext, rule: &str, spec: &HookActionSpec, event: &HookEvent) {
+        let (actions, slots, pending) = (self.actions.clone(), self.background.clone(), self.pending.clone());
+        let (ctx, rule, spec, event) = (ctx.clone(), rule.to_string(), spec.clone(), event.clone());
+        let pending = PendingGuard::new(pending);
+        // Emits from the action count from the depth of the emit that started it.
+        tokio::spawn(EMIT_DEPTH.scope(current_depth(), async move {
+            let _pending = pending;
+            if let Ok(_slot) = slots.acquire().await
+                && let Err(e) = actions.run(&ctx, &rule, &spec, &event).await
+                && !e.is::<TimedOut>()
+            {
+                log_failure(&ctx.cwd, &rule, &event, &e);
+            }
+        }));
+    }
+}
+
+/// One async action counted in [`HookRegistry::drain`]'s counter until dropped, even when the
+/// action panics or its task is cancelled.
+struct PendingGuard(Arc<watch::Sender<usize>>);
+
+impl PendingGuard {
+    fn new(pending: Arc<watch::Sender<usize>>) -> Self {
+        pending.send_modify(|n| *n += 1);
+        Self(pending)
+    }
+}
+
+impl Drop for PendingGuard {
+    fn drop(&mut self) {
+        self.0.send_modify(|n| *n -= 1);
+    }
+}
+
+impl ActionRunner {
+    /// Run one action within its timeout; plugins reply with nothing but a rewritten event.
+    async fn run(&self, ctx: &HookContext, rule: &str, spec: &HookActionSpec, event: &HookEvent) -> Result<HookReply> {
+        match &spec.action {
+            HookAction::Exec { cmd, args } => {
+                self.timed(ctx, rule, event, spec.timeout_secs, self.run_exec(rule, cmd, args, ctx, event)).await
+            }
+            HookAction::Prompt { .. } => self.run_prompt(ctx, rule, spec, event).await,
+            HookAction::Plugin { handler, config } => {
+                let h = self.plugins.get(handler).ok_or_else(|| anyhow!("unknown plugin handler: {}", handler))?;
+                let rewrite = async { h.rewrite(ctx, event, config).await.map(|event| HookReply { event, ..Default::default() }) };
+                self.timed(ctx, rule, event, spec.timeout_secs, rewrite).await
+            }
+        }
+    }
+
+    /// `work`, failing with [`TimedOut`] (and a line in `.codex/hooks.log`) after `secs`.
+    async fn timed<T>(&self, ctx: &HookContext, rule: &str, event: &HookEvent, secs: Option<u64>, work: impl Future<Output = Result<T>>) -> Result<T> {
+        let Some(secs) = secs else { return work.await };
+        match tokio::time::timeout(Duration::from_secs(secs), work).await {
+            Ok(res) => res,
+            Err(_) => {
+                let e = anyhow::Error::new(TimedOut { rule: rule.into(), secs });
+                log_failure(&ctx.cwd, rule, event, &e);
+                Err(e)
+            }
+        }
+    }
+
+    /// Run a prompt action: the instruction and the event go to the model, whose answer becomes the
+    /// reply's message or, for verdicts, its decision and reason.
+    async fn run_prompt(&self, ctx: &HookContext, rule: &str, spec: &HookActionSpec, event: &HookEvent) -> Result<HookReply> {
+        let HookAction::Prompt { model_profile, instruction, max_tokens, answer, fallback } = &spec.action else {
+            unreachable!("run_prompt only runs prompt actions")
+        };
+        let target = model_profile.as_ref()
+            .and_then(|p| self.cfg.get().models.profiles.get(p).cloned())
+            .unwrap_or_else(|| self.cfg.pick_model(ModelRole::Chat));
+        let mut prompt = format!("{}\n\nEvent:\n{}", instruction, serde_json::to_string_pretty(event)?);
+        if *answer == PromptAnswer::Verdict {
+            prompt.push_str("\n\nAnswer with a JSON object only: {\"decision\": \"allow\" or \"deny\", \"reason\": \"...\"}");
+        }
+        let secs = spec.timeout_secs.unwrap_or(PROMPT_TIMEOUT_SECS);
+        let res = match &self.model {
+            None => Err(anyhow!("no hook model is registered")),
+            Some(model) => self.timed(ctx, rule, event, Some(secs), model.complete(&target, &prompt, *max_tokens)).await,
+        };
+        let reply = res.and_then(|text| match answer {
+            PromptAnswer::Annotation => Ok(HookReply { message: Some(text.trim().to_string()), ..Default::default() }),
+            PromptAnswer::Verdict => {
+                // Models like to wrap JSON in prose or code fences.
+                let json = text.find('{').zip(text.rfind('}')).filter(|(a, b)| a < b).map(|(a, b)| &text[a..=b]);
+                let reply = json.map(HookReply::parse).transpose()?.unwrap_or_default();
+                match reply.decision {
+                    Some(ReplyDecision::Allow | ReplyDecision::Deny) => Ok(HookReply { event: None, ..reply }),
+                    _ => Err(anyhow!("no allow/deny verdict in the model's answer: {}", text.trim())),
                 }
             }
+        });
+        match (reply, fallback) {
+            (Ok(reply), _) => Ok(reply),
+            (Err(e), Some(decision)) => Ok(HookReply { decision: Some((*decision).into()), reason: Some(format!("{:#}", e)), ..Default::default() }),
+            (Err(e), None) => Err(e),
+        }
+    }
+
+    /// Run an exec action with the event as JSON on stdin and `CODEX_HOOK_*` variables set, and
+    /// read its reply from stdout. Fails when the shell policy refuses the command, when it writes
+    /// more than [`MAX_OUTPUT_BYTES`], or when it exits non-zero without replying `allow`.
+    async fn run_exec(&self, rule: &str, cmd: &str, args: &[String], ctx: &HookContext, event: &HookEvent) -> Result<HookReply> {
+        let cfg = self.cfg.get();
+        // Hooks run unattended: a command that would need approval is refused.
+        let check = ShellPolicy::new(&cfg.shell).check(cmd, args);
+        if check.decision != Decision::Allow {
+            return Err(anyhow!("exec blocked by shell policy: {}", check.reason()));
+        }
+        let mut vars = event.env();
+        vars.insert("CODEX_HOOK_RULE".into(), rule.into());
+        vars.insert("CODEX_SESSION_ID".into(), ctx.session_id.clone());
+        let env = ChildEnv::new(&cfg.shell)?.for_child(&ctx.env, [&vars]);
+        let mut command = Command::new(cmd);
+        command.args(args).current_dir(&ctx.cwd).env_clear().envs(env)
+            .stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped())
+            .kill_on_drop(true);
+        Sandbox::new(&cfg.sandbox, &ctx.cwd)?.apply(&mut command)?;
+        let mut child = command.spawn().with_context(|| format!("failed to spawn {}", cmd))?;
+        let input = serde_json::to_vec(&HookInput { rule, session_id: &ctx.session_id, cwd: &ctx.cwd, event })?;
+        let mut stdin = child.stdin.take();
+        let write = async move {
+            // A hook that exits without reading its input is fine.
+            if let Some(stdin) = &mut stdin { stdin.write_all(&input).await.ok(); }
+        };
+        let (stdout, stderr) = (child.stdout.take(), child.stderr.take());
+        let budget = OutputBudget::new(Some(MAX_OUTPUT_BYTES));
+        // Output under the budget is kept whole.
+        let keep = PreviewLimits { head: MAX_OUTPUT_BYTES as usize, tail: 0 };
+        let ignore: LineSink = Arc::new(|_, _| {});
+        let ((), stdout, stderr, status) = tokio::try_join!(
+            async { write.await; Ok(()) },
+            capture(stdout, Stream::Stdout, keep, &ignore, &budget),
+            capture(stderr, Stream::Stderr, keep, &ignore, &budget),
+            budget.wait(&mut child),
+        )?;
+        if budget.exceeded() {
+            bail!("exec failed: {}: wrote more than {} bytes", rule, MAX_OUTPUT_BYTES);
+        }
+        let reply = HookReply::parse(&stdout.finish())?;
+        let explicit = matches!(reply.decision, Some(ReplyDecision::Allow | ReplyDecision::Modify)) || reply.event.is_some();
+        if status.success() || explicit { return Ok(reply); }
+        let stderr = stderr.finish();
+        match stderr.lines().rfind(|l| !l.trim().is_empty()) {
+            Some(last) => Err(anyhow!("exec failed: {}: {}", rule, last.trim())),
+            None => Err(anyhow!("exec failed: {}", rule)),
         }
-        Ok(HookDecision::Continue)
+    }
+}
+
+/// Append a failed or timed-out action to `.codex/hooks.log` under the hook's cwd.
+fn log_failure(cwd: &Path, rule: &str, event: &HookEvent, e: &anyhow::Error) {
+    use std::io::Write;
+    let log = cwd.join(".codex").join("hooks.log");
+    if let Some(dir) = log.parent() { fs::create_dir_all(dir).ok(); }
+    if let Ok(mut f) = fs::OpenOptions::new().create(true).append(true).open(&log) {
+        writeln!(f, "{} {} {}: {:#}", chrono::Utc::now().to_rfc3339(), rule, event.kind(), e).ok();
     }
 }
 
 #[derive(Default, Deserialize)]
 struct HookRulesFile { rule: Option<Vec<HookRule>>, rules: Option<Vec<HookRule>> }
 
-fn rule_matches(rule: &HookRule, ev: &HookEvent) -> bool {
-    let ty = match ev {
-        HookEvent::PreToolUse{..} => "pre_tool_use",
-        HookEvent::PostToolUse{..} => "post_tool_use",
-        HookEvent::PreExec{..} => "pre_exec",
-        HookEvent::PostExec{..} => "post_exec",
-        HookEvent::PreMcp{..} => "pre_mcp",
-        HookEvent::PostMcp{..} => "post_mcp",
-        HookEvent::TaskStart{..} => "task_start",
-        HookEvent::TaskProgress{..} => "task_progress",
-        HookEvent::TaskEnd{..} => "task_end",
-        HookEvent::Git{..} => "git",
-    };
-    rule.when.iter().any(|w| w == ty)
+fn rule_matches(rule: &HookRule, matcher: Option<&EventMatcher>, ev: &HookEvent) -> bool {
+    rule.when.iter().any(|w| w == ev.kind()) && matcher.is_none_or(|m| m.matches(ev))
 }
 
 #[async_trait]
 pub trait HookActionHandler: Send + Sync {
     async fn run(&self, ctx: &HookContext, ev: &HookEvent, config: &serde_json::Value) -> Result<()>;
+
+    /// Called by the registry; may return a rewritten event of the same type for later rules and
+    /// the emitter. The default runs [`run`](Self::run) and keeps the event.
+    async fn rewrite(&self, ctx: &HookContext, ev: &HookEvent, config: &serde_json::Value) -> Result<Option<HookEvent>> {
+        self.run(ctx, ev, config).await.map(|()| None)
+    }
 }
 
 /// Built-in plugin: append a compact event line to .codex/audit.log
@@ -176,3 +647,188 @@ impl HookActionHandler for AuditLogPlugin {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parking_lot::Mutex;
+
+    /// Answers every prompt with `answer` after `delay`, remembering the prompts it saw.
+    struct FakeModel { answer: String, delay: Duration, prompts: Mutex<Vec<String>> }
+
+    #[async_trait]
+    impl HookModel for FakeModel {
+        async fn complete(&self, _target: &ModelTarget, prompt: &str, _max_tokens: Option<u32>) -> Result<String> {
+            self.prompts.lock().push(prompt.to_string());
+            tokio::time::sleep(self.delay).await;
+            Ok(self.answer.clone())
+        }
+    }
+
+    /// A registry with workspace config `config` and the rules in `rules`.
+    fn registry(dir: &Path, config: &str, rules: &str) -> Result<HookRegistry> {
+        fs::write(dir.join("w.toml"), config)?;
+        let cfg = Arc::new(ConfigManager::for_paths(dir.join("s.toml"), dir.join("u.toml"), dir.join("w.toml"))?);
+        fs::write(dir.join("rules.toml"), rules)?;
+        HookRegistry::load_from_dirs(cfg, &[dir.to_path_buf()])
+    }
+
+    fn context(dir: &Path) -> HookContext {
+        HookContext { cwd: dir.into(), session_id: "s1".into(), env: BTreeMap::new() }
+    }
+
+    async fn emit_with(rule: &str, answer: &str, delay: Duration) -> Result<(HookDecision, Vec<String>)> {
+        let dir = tempfile::tempdir()?;
+        let mut hooks = registry(dir.path(), "", rule)?;
+        let model = Arc::new(FakeModel { answer: answer.into(), delay, prompts: Mutex::new(vec![]) });
+        hooks.set_model(model.clone());
+        let decision = hooks.emit(&context(dir.path()), &HookEvent::PreExec { cmd: "rm".into(), argv: vec!["-rf".into(), "/".into()] }).await?;
+        let prompts = model.prompts.lock().clone();
+        Ok((decision, prompts))
+    }
+
+    #[test]
+    fn unknown_plugin_handlers_are_rejected_at_load() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let rule = r#"
+[[rule]]
+name = "audit"
+when = ["pre_exec"]
+actions = [{ kind = "plugin", handler = "audit_logg" }]
+"#;
+        let err = registry(dir.path(), "", rule).err().context("unknown handler accepted")?;
+        assert_eq!(err.to_string(), "hook rule 'audit': unknown plugin handler: audit_logg");
+        registry(dir.path(), "", &rule.replace("audit_logg", "audit_log"))?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn prompt_actions_ask_the_model_for_verdicts_and_annotations() -> Result<()> {
+        let verdict = r#"
+[[rule]]
+name = "review"
+when = ["pre_exec"]
+actions = [{ kind = "prompt", instruction = "Is this command safe?", max_tokens = 200, answer = "verdict" }]
+"#;
+        let (decision, prompts) = emit_with(verdict, "Sure.\n```json\n{\"decision\": \"deny\", \"reason\": \"wipes the disk\"}\n```", Duration::ZERO).await?;
+        assert!(matches!(&decision, HookDecision::Deny { reason } if reason == "wipes the disk"), "{:?}", decision);
+        assert!(prompts[0].starts_with("Is this command safe?") && prompts[0].contains("\"argv\"") && prompts[0].contains("\"decision\""), "{}", prompts[0]);
+
+        let (decision, _) = emit_with(verdict, "I'm not sure.", Duration::ZERO).await?;
+        assert!(matches!(decision, HookDecision::Continue), "no verdict and no fallback fails the action without deny_on_fail");
+
+        let note = verdict.replace(", answer = \"verdict\"", "");
+        let (decision, _) = emit_with(&note, "  Deletes everything.\n", Duration::ZERO).await?;
+        assert!(matches!(&decision, HookDecision::Message { text } if text == "Deletes everything."), "{:?}", decision);
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_models_time_out_into_the_fallback() -> Result<()> {
+        let rule = r#"
+[[rule]]
+name = "review"
+when = ["pre_exec"]
+actions = [{ kind = "prompt", instruction = "Is this command safe?", answer = "verdict", timeout_secs = 5, fallback = "deny" }]
+"#;
+        let (decision, _) = emit_with(rule, r#"{"decision": "allow"}"#, Duration::from_secs(10)).await?;
+        assert!(matches!(&decision, HookDecision::Deny { reason } if reason.contains("timed out after 5s")), "{:?}", decision);
+        let (decision, _) = emit_with(rule, r#"{"decision": "allow"}"#, Duration::from_secs(1)).await?;
+        assert!(matches!(decision, HookDecision::Continue), "{:?}", decision);
+
+        let dir = tempfile::tempdir()?;
+        let err = registry(dir.path(), "", &rule.replace("fallback = \"deny\"", "fallback = \"modify\"")).err().context("modify fallback accepted")?;
+        assert!(format!("{:#}", err).contains("unknown variant `modify`, expected `allow` or `deny`"), "{:#}", err);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn slow_actions_time_out_as_failures_and_are_logged() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let hooks = registry(dir.path(), "", r#"
+[[rule]]
+name = "slow-check"
+when = ["pre_exec"]
+deny_on_fail = true
+actions = [{ kind = "exec", cmd = "sleep", args = ["5"], timeout_secs = 1 }]
+"#)?;
+        let started = std::time::Instant::now();
+        let decision = hooks.emit(&context(dir.path()), &HookEvent::PreExec { cmd: "make".into(), argv: vec![] }).await?;
+        assert!(started.elapsed() < Duration::from_secs(4), "the hook was not stopped");
+        assert!(matches!(&decision, HookDecision::Deny { reason } if reason == "hook slow-check timed out after 1s"), "{:?}", decision);
+        let log = fs::read_to_string(dir.path().join(".codex/hooks.log"))?;
+        assert!(log.trim_end().ends_with("slow-check pre_exec: hook slow-check timed out after 1s"), "{}", log);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn async_actions_run_in_the_background_a_few_at_a_time() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let hooks = registry(dir.path(), "[hooks]\nmax_async = 1\n", r#"
+[[rule]]
+name = "notify"
+when = ["task_end"]
+actions = [
+    { kind = "exec", cmd = "sh", args = ["-c", "echo start >> runs; sleep 0.2; echo end >> runs"], async = true },
+    { kind = "exec", cmd = "sh", args = ["-c", "echo start >> runs; sleep 0.2; echo end >> runs"], async = true },
+    { kind = "exec", cmd = "false", args = [], async = true },
+]
+"#)?;
+        let event = HookEvent::TaskEnd { task_name: "build".into(), success: true };
+        assert_eq!(hooks.preview(&event)[0].actions[2], "exec: false (async)");
+        let decision = hooks.emit(&context(dir.path()), &event).await?;
+        assert!(matches!(decision, HookDecision::Continue), "async failures never deny: {:?}", decision);
+        assert!(!dir.path().join("runs").exists(), "emit waited for an async action");
+
+        hooks.drain().await;
+        assert_eq!(fs::read_to_string(dir.path().join("runs"))?, "start\nend\nstart\nend\n", "max_async = 1 runs one at a time");
+        let log = fs::read_to_string(dir.path().join(".codex/hooks.log"))?;
+        assert!(log.contains("notify task_end: exec failed: notify"), "{}", log);
+
+        let rule = "[[rule]]\nname = \"guard\"\nwhen = [\"task_end\"]\nactions = [{ kind = \"exec\", cmd = \"check\", args = [], async = true }]\n";
+        let err = registry(dir.path(), "", &rule.replace("actions", "deny_on_fail = true\nactions")).err().context("deny_on_fail accepted")?;
+        assert_eq!(err.to_string(), "hook rule 'guard': async actions can't deny, so the rule can't set deny_on_fail");
+        let err = registry(dir.path(), "", &rule.replace("\"task_end\"", "\"task_end\", \"pre_exec\"")).err().context("pre_exec accepted")?;
+        assert_eq!(err.to_string(), "hook rule 'guard': async actions can't run on pre_exec events, which wait for the rule's verdict");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn exec_actions_that_write_too_much_are_killed_and_fail() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let hooks = registry(dir.path(), "", r#"
+[[rule]]
+name = "chatty"
+when = ["pre_exec"]
+deny_on_fail = true
+actions = [{ kind = "exec", cmd = "yes", args = [], timeout_secs = 10 }]
+"#)?;
+        let decision = hooks.emit(&context(dir.path()), &HookEvent::PreExec { cmd: "make".into(), argv: vec![] }).await?;
+        assert!(matches!(&decision, HookDecision::Deny { reason } if reason == "exec failed: chatty: wrote more than 1048576 bytes"), "{:?}", decision);
+        Ok(())
+    }
+
+    struct PanickingPlugin;
+
+    #[async_trait]
+    impl HookActionHandler for PanickingPlugin {
+        async fn run(&self, _ctx: &HookContext, _ev: &HookEvent, _config: &serde_json::Value) -> Result<()> {
+            panic!("plugin bug")
+        }
+    }
+
+    #[tokio::test]
+    async fn drain_does_not_wait_for_async_actions_that_panicked() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut hooks = registry(dir.path(), "", r#"
+[[rule]]
+name = "audit"
+when = ["task_end"]
+actions = [{ kind = "plugin", handler = "audit_log", async = true }]
+"#)?;
+        hooks.register_plugin("audit_log", Arc::new(PanickingPlugin));
+        hooks.emit(&context(dir.path()), &HookEvent::TaskEnd { task_name: "build".into(), success: true }).await?;
+        tokio::time::timeout(Duration::from_secs(5), hooks.drain()).await.context("drain hung on a panicked action")?;
+        Ok(())
+    }
+}
@@ -30,21 +30,19 @@ pub struct Config {
     pub sessions: SessionsConfig,
     pub hooks: HooksConfig,
     pub slash: SlashConfigMeta,
+    pub tasks: TasksConfig,
+    pub limits: LimitsConfig,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, Default, PartialEq, Eq)]
 #[serde(rename_all = "snake_case")]
 pub enum ApprovalMode {
+    #[default]
     OnRequest,
     OnFailure,
     UnlessTrusted,
     Never,
 }
-impl Default for ApprovalMode {
-    fn default() -> Self {
-        Self::OnRequest
-    }
-}
 
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
 #[serde(default)]
@@ -75,6 +73,8 @@ pub struct ModelTarget {
     /// Name of env var carrying an API token (if provider uses bearer tokens)
     pub api_token_env: Option<String>, // e.g. ANTHROPIC_API_KEY or custom token
     pub extra_headers: BTreeMap<String, String>,
+    /// Max number of tasks using this target that may run at once (unbounded if unset)
+    pub max_concurrency: Option<usize>,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -98,7 +98,7 @@ pub struct SandboxConfig {
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
 #[serde(default)]
 pub struct ShellConfig {
-    pub approval: ApprovalMode,
+    pub approval: ApprovalMode, // layers left at the default keep the approval mode of lower ones
     pub allowlist_roots: Vec<String>, // e.g., ["git","rg","ls","cat","cargo"]
     pub denylist_roots: Vec<String>,
     pub environment_inherit: Option<String>, // "none" | "core" | "all"
@@ -169,6 +169,8 @@ pub struct HooksConfig {
     pub recursion_limit: Option<u32>,
     /// Additional lookup dirs for hooks/*.toml
     pub dirs: Vec<PathBuf>,
+    /// Async hook actions that may run at once (default 4); later ones wait for a slot.
+    pub max_async: Option<u32>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
@@ -178,6 +180,32 @@ pub struct SlashConfigMeta {
     pub dirs: Vec<PathBuf>,
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct TasksConfig {
+    /// Global cap on concurrently running tasks in a parallel set (unbounded if unset)
+    pub max_parallel: Option<usize>,
+}
+
+/// Resource limits for exec steps; a task's `limits` table overrides them field by field.
+#[derive(Clone, Debug, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct LimitsConfig {
+    pub cpu_secs: Option<u64>,
+    /// Needs a cgroup v2 with the `memory` controller; exec steps fail to start without one.
+    pub memory_mb: Option<u64>,
+    /// Needs a cgroup v2 with the `pids` controller; exec steps fail to start without one.
+    pub max_processes: Option<u64>,
+    /// stdout and stderr together; the command is killed when it writes more.
+    pub max_output_bytes: Option<u64>,
+}
+
+impl LimitsConfig {
+    pub fn is_empty(&self) -> bool {
+        self.cpu_secs.is_none() && self.memory_mb.is_none() && self.max_processes.is_none() && self.max_output_bytes.is_none()
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
 #[serde(default)]
 pub struct McpConfig {
@@ -238,7 +266,9 @@ fn merge(a: &mut Config, b: &Config) {
         a.sandbox.writable_roots = b.sandbox.writable_roots.clone();
     }
 
-    a.shell.approval = b.shell.approval.clone();
+    if b.shell.approval != ApprovalMode::default() {
+        a.shell.approval = b.shell.approval.clone();
+    }
     if !b.shell.allowlist_roots.is_empty() {
         a.shell.allowlist_roots = b.shell.allowlist_roots.clone();
     }
@@ -297,12 +327,34 @@ fn merge(a: &mut Config, b: &Config) {
     if !b.hooks.dirs.is_empty() {
         a.hooks.dirs = b.hooks.dirs.clone();
     }
+    if b.hooks.max_async.is_some() {
+        a.hooks.max_async = b.hooks.max_async;
+    }
 
     // slash
     if !b.slash.dirs.is_empty() {
         a.slash.dirs = b.slash.dirs.clone();
     }
 
+    // tasks
+    if b.tasks.max_parallel.is_some() {
+        a.tasks.max_parallel = b.tasks.max_parallel;
+    }
+
+    // limits
+    if b.limits.cpu_secs.is_some() {
+        a.limits.cpu_secs = b.limits.cpu_secs;
+    }
+    if b.limits.memory_mb.is_some() {
+        a.limits.memory_mb = b.limits.memory_mb;
+    }
+    if b.limits.max_processes.is_some() {
+        a.limits.max_processes = b.limits.max_processes;
+    }
+    if b.limits.max_output_bytes.is_some() {
+        a.limits.max_output_bytes = b.limits.max_output_bytes;
+    }
+
     // MCP servers
     for (k, v) in &b.mcp.servers {
         a.mcp.servers.insert(k.clone(), v.clone());
@@ -439,7 +491,7 @@ impl ConfigManager {
     pub fn apply_runtime_overlay(&self, patch: Config) -> Result<()> {
         {
             let mut rt = self.runtime_overlay.write();
-            merge(&mut *rt, &patch);
+            merge(&mut rt, &patch);
         }
         self.reload_all()
     }
@@ -475,10 +527,10 @@ impl ConfigManager {
             ModelRole::MetaPrompt => Some("meta_prompt"),
             ModelRole::TaskStatus => Some("task_status"),
         };
-        if let Some(k) = key {
-            if let Some(t) = cfg.models.overrides.get(k) {
-                return t.clone();
-            }
+        if let Some(k) = key
+            && let Some(t) = cfg.models.overrides.get(k)
+        {
+            return t.clone();
         }
         cfg.models.default.clone()
     }
@@ -3,8 +3,22 @@
 pub mod layered_config;     // layered TOML config + model routing
 pub mod session_logs;       // JSON / JSONL session logs (+ purge and resume)
 pub mod hooks;              // TOML-defined hooks (exec/prompt/plugin) + recursion limit
+pub mod hook_match;         // payload matchers (globs/regexes, all/any/not) on hook rules
+pub mod shell_policy;       // allow/ask/deny for exec commands from [shell] config
+pub mod child_env;          // child process environment from [shell] inherit/exclude settings
+pub mod sandbox;            // Landlock + seccomp sandbox for spawned commands from [sandbox] config
+pub mod limits;             // rlimit / cgroup v2 resource limits for exec steps from [limits] config
+pub mod exec;               // default tokio::process exec bridge (streamed lines, head+tail preview)
 pub mod slash;              // TOML-defined slash commands/macros/builtins
 pub mod taskset;            // Task Sets: parallel/seq, live status, per-task model
+pub mod template;           // {{steps.<id>.<field>}} placeholders in task steps
+pub mod checkpoint;         // durable TaskSetPlan progress + resume
+pub mod report;             // structured TaskSetPlan run results
+pub mod git_ops;            // git2-backed TaskStep::Git actions
+pub mod worktree;           // per-task git worktrees for isolated task sets
+pub mod when;               // `when` conditions on tasks and task steps
+pub mod matrix;             // `matrix` task expansion (value lists, workspace globs)
+pub mod dry_run;            // side-effect-free TaskSetPlan preview (models, hooks)
 pub mod todo;               // TODO store in JSON
 pub mod compact;            // manual/auto compaction
 #[cfg(feature = "acp")]
@@ -14,7 +28,17 @@ pub mod acp_server;         // ACP server skeleton bridging to codex task/todo/h
 pub use layered_config::{ConfigManager, Config, Scope, ModelRole, ModelTarget};
 pub use session_logs::{SessionLogWriter, SessionEvent};
 pub use hooks::{HookRegistry, HookDecision, HookEvent, HookContext};
+pub use shell_policy::{ShellPolicy, ExecApproval};
+pub use child_env::ChildEnv;
+pub use sandbox::Sandbox;
+pub use limits::{Limits, LimitKind};
+pub use exec::{ExecOutput, ExecRequest, tokio_exec};
 pub use slash::SlashRegistry;
-pub use taskset::{TaskSetRunner, TaskSpec, TaskStep, TaskSetSpec, TaskSetPlan, TaskStatus};
+pub use taskset::{TaskSetRunner, TaskSpec, TaskStep, StepSpec, TaskSetSpec, TaskSetPlan, TaskStatus, SuccessCriteria, OnError, CancellationToken, SetConfirm, ConfirmDecision};
 pub use todo::{TodoStore, TodoItem, TodoStatus};
 pub use compact::{Compactor, AutoCompactStage};
+pub use checkpoint::CheckpointStore;
+pub use dry_run::DryRunReport;
+pub use git_ops::GitAction;
+pub use matrix::MatrixAxis;
+pub use report::{TaskSetReport, SetReport, TaskReport, StepReport, TaskOutcome};
@@ -0,0 +1,284 @@
+// annex/src/limits.rs — CPU, memory, process and output limits for exec steps from [limits] config
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::{fmt, process::ExitStatus};
+use tokio::process::Command;
+
+use crate::layered_config::LimitsConfig;
+
+/// The limit that stopped a command.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LimitKind {
+    CpuTime,
+    Memory,
+    Processes,
+    Output,
+}
+
+impl LimitKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LimitKind::CpuTime => "cpu_time",
+            LimitKind::Memory => "memory",
+            LimitKind::Processes => "processes",
+            LimitKind::Output => "output",
+        }
+    }
+}
+
+impl fmt::Display for LimitKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LimitKind::CpuTime => "cpu time",
+            LimitKind::Memory => "memory",
+            LimitKind::Processes => "process count",
+            LimitKind::Output => "output size",
+        })
+    }
+}
+
+/// Limits for one exec step: the task's `limits` over `[limits]`, field by field.
+/// CPU time is an rlimit (the child gets `SIGXCPU`). Memory and process count go to a cgroup v2
+/// made for the command when this process may create one with the `memory`/`pids` controllers.
+/// Without one, both are refused: `RLIMIT_AS` makes allocations fail in ways that cannot be told
+/// apart from other errors, and `RLIMIT_NPROC` counts every process of the user, not just the
+/// command's. Output size is enforced by the exec bridge reading the child's pipes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Limits {
+    pub cpu_secs: Option<u64>,
+    pub memory_bytes: Option<u64>,
+    pub max_processes: Option<u64>,
+    /// stdout and stderr together.
+    pub max_output_bytes: Option<u64>,
+}
+
+impl Limits {
+    pub fn new(global: &LimitsConfig, task: Option<&LimitsConfig>) -> Self {
+        let pick = |f: fn(&LimitsConfig) -> Option<u64>| task.and_then(f).or(f(global));
+        Self {
+            cpu_secs: pick(|c| c.cpu_secs),
+            memory_bytes: pick(|c| c.memory_mb).map(|mb| mb.saturating_mul(1024 * 1024)),
+            max_processes: pick(|c| c.max_processes),
+            max_output_bytes: pick(|c| c.max_output_bytes),
+        }
+    }
+
+    /// Whether anything besides output size needs installing in the child.
+    fn needs_os(&self) -> bool {
+        self.cpu_secs.is_some() || self.memory_bytes.is_some() || self.max_processes.is_some()
+    }
+
+    /// Install the limits in `cmd`'s child right before it execs (before the sandbox, which may
+    /// hide the cgroup tree). Keep the guard until the child has exited, then ask it which limit
+    /// was hit; dropping it kills whatever is left in the cgroup and removes it.
+    pub fn apply(&self, cmd: &mut Command) -> Result<LimitGuard> {
+        if !self.needs_os() { return Ok(LimitGuard::default()); }
+        self.apply_os(cmd)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn apply_os(&self, cmd: &mut Command) -> Result<LimitGuard> {
+        let cgroup = cgroup::Cgroup::create(self);
+        let (cg_memory, cg_pids) = cgroup.as_ref().map_or((false, false), |c| (c.memory, c.pids));
+        let mut rlimits = vec![];
+        if let Some(secs) = self.cpu_secs {
+            // The soft limit sends SIGXCPU; the hard one a second later kills a child that ignores it.
+            rlimits.push((libc::RLIMIT_CPU, secs, secs.saturating_add(1)));
+        }
+        if self.memory_bytes.is_some() && !cg_memory {
+            anyhow::bail!("limits.memory_mb needs a cgroup v2 with the memory controller delegated to this process; \
+                RLIMIT_AS would fail allocations without the hit being reported");
+        }
+        if self.max_processes.is_some() && !cg_pids {
+            anyhow::bail!("limits.max_processes needs a cgroup v2 with the pids controller delegated to this process; \
+                RLIMIT_NPROC would count every process of the user, not just the command's");
+        }
+        let procs = cgroup.as_ref().map(|c| c.procs.clone());
+        // SAFETY: the closure runs between fork and exec; it only calls setrlimit/open/write/close
+        // on values prepared above.
+        unsafe {
+            cmd.pre_exec(move || {
+                for &(resource, soft, hard) in &rlimits {
+                    let lim = libc::rlimit { rlim_cur: soft as libc::rlim_t, rlim_max: hard as libc::rlim_t };
+                    if libc::setrlimit(resource, &lim) != 0 { return Err(std::io::Error::last_os_error()); }
+                }
+                if let Some(procs) = &procs {
+                    // Writing 0 to `cgroup.procs` moves the writer, i.e. this child.
+                    let fd = libc::open(procs.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+                    if fd < 0 { return Err(std::io::Error::last_os_error()); }
+                    let written = libc::write(fd, b"0".as_ptr().cast(), 1);
+                    let err = std::io::Error::last_os_error();
+                    libc::close(fd);
+                    if written != 1 { return Err(err); }
+                }
+                Ok(())
+            });
+        }
+        Ok(LimitGuard { cgroup, cpu: self.cpu_secs.is_some() })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn apply_os(&self, _cmd: &mut Command) -> Result<LimitGuard> {
+        anyhow::bail!("cpu, memory and process limits are only supported on Linux (rlimits and cgroups v2)")
+    }
+}
+
+/// Keeps a command's cgroup alive while it runs; see [`Limits::apply`].
+#[derive(Debug, Default)]
+pub struct LimitGuard {
+    #[cfg(target_os = "linux")]
+    cgroup: Option<cgroup::Cgroup>,
+    #[cfg(target_os = "linux")]
+    cpu: bool,
+}
+
+impl LimitGuard {
+    /// Which limit stopped a child that exited with `status`, if one did.
+    pub fn hit(&self, status: &ExitStatus) -> Option<LimitKind> {
+        self.hit_os(status)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn hit_os(&self, status: &ExitStatus) -> Option<LimitKind> {
+        use std::os::unix::process::ExitStatusExt;
+        if self.cpu && status.signal() == Some(libc::SIGXCPU) { return Some(LimitKind::CpuTime); }
+        let cg = self.cgroup.as_ref()?;
+        if cg.memory && cg.event("memory.events", "oom_kill") > 0 { return Some(LimitKind::Memory); }
+        if cg.pids && cg.event("pids.events", "max") > 0 { return Some(LimitKind::Processes); }
+        None
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn hit_os(&self, _status: &ExitStatus) -> Option<LimitKind> { None }
+}
+
+#[cfg(target_os = "linux")]
+mod cgroup {
+    use std::{ffi::CString, fs, os::unix::ffi::OsStrExt, path::{Path, PathBuf}};
+
+    use super::Limits;
+
+    const ROOT: &str = "/sys/fs/cgroup";
+
+    /// A cgroup v2 made for one command, under the cgroup of this process.
+    #[derive(Debug)]
+    pub(super) struct Cgroup {
+        dir: PathBuf,
+        /// `<dir>/cgroup.procs`, ready for the child to open.
+        pub(super) procs: CString,
+        pub(super) memory: bool,
+        pub(super) pids: bool,
+    }
+
+    impl Cgroup {
+        /// `None` when cgroups v2 are not mounted, not delegated to us, or lack the controllers
+        /// the limits need; rlimits are used instead.
+        pub(super) fn create(limits: &Limits) -> Option<Self> {
+            // Only the unified (v2) hierarchy has `cgroup.controllers` at its root.
+            if !Path::new(ROOT).join("cgroup.controllers").exists() { return None; }
+            let own = fs::read_to_string("/proc/self/cgroup").ok()?;
+            let rel = own.lines().find_map(|l| l.strip_prefix("0::"))?;
+            let parent = Path::new(ROOT).join(rel.trim_start_matches('/'));
+            // Enabling controllers fails while processes live in `parent` (unless it is the root);
+            // they may already be enabled, so the outcome is read from the new cgroup below.
+            let wanted: Vec<&str> = [(limits.memory_bytes.is_some(), "+memory"), (limits.max_processes.is_some(), "+pids")]
+                .into_iter().filter_map(|(on, c)| on.then_some(c)).collect();
+            if wanted.is_empty() { return None; }
+            fs::write(parent.join("cgroup.subtree_control"), wanted.join(" ")).ok();
+
+            let dir = parent.join(format!("annex-exec-{}", uuid::Uuid::new_v4()));
+            fs::create_dir(&dir).ok()?;
+            let mut cg = Self { procs: CString::new(dir.join("cgroup.procs").as_os_str().as_bytes()).ok()?, dir, memory: false, pids: false };
+            let controllers = fs::read_to_string(cg.dir.join("cgroup.controllers")).unwrap_or_default();
+            let has = |c: &str| controllers.split_whitespace().any(|x| x == c);
+            if let Some(bytes) = limits.memory_bytes && has("memory") {
+                cg.memory = fs::write(cg.dir.join("memory.max"), bytes.to_string()).is_ok();
+                fs::write(cg.dir.join("memory.swap.max"), "0").ok();
+            }
+            if let Some(n) = limits.max_processes && has("pids") {
+                cg.pids = fs::write(cg.dir.join("pids.max"), n.to_string()).is_ok();
+            }
+            // Dropping `cg` removes the directory again.
+            (cg.memory || cg.pids).then_some(cg)
+        }
+
+        /// Counter `key` of the flat-keyed file `file`, e.g. `oom_kill` in `memory.events`.
+        pub(super) fn event(&self, file: &str, key: &str) -> u64 {
+            fs::read_to_string(self.dir.join(file)).unwrap_or_default().lines()
+                .find_map(|l| l.strip_prefix(key)?.trim().parse().ok())
+                .unwrap_or(0)
+        }
+    }
+
+    impl Drop for Cgroup {
+        fn drop(&mut self) {
+            // Processes the command left behind keep the cgroup busy; `cgroup.kill` needs Linux 5.14.
+            if fs::write(self.dir.join("cgroup.kill"), "1").is_err() {
+                for pid in fs::read_to_string(self.dir.join("cgroup.procs")).unwrap_or_default().lines().filter_map(|l| l.parse().ok()) {
+                    // SAFETY: plain kill(2) on pids listed by the kernel.
+                    unsafe { libc::kill(pid, libc::SIGKILL); }
+                }
+            }
+            fs::remove_dir(&self.dir).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn task_limits_override_global_ones_field_by_field() {
+        let global = LimitsConfig { cpu_secs: Some(60), memory_mb: Some(2048), max_output_bytes: Some(1 << 20), ..Default::default() };
+        let task = LimitsConfig { memory_mb: Some(512), max_processes: Some(64), ..Default::default() };
+        assert_eq!(Limits::new(&global, Some(&task)), Limits {
+            cpu_secs: Some(60), memory_bytes: Some(512 << 20), max_processes: Some(64), max_output_bytes: Some(1 << 20),
+        });
+        assert_eq!(Limits::new(&LimitsConfig::default(), None), Limits::default());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn cpu_limit_is_reported() -> Result<()> {
+        let limits = Limits { cpu_secs: Some(1), ..Default::default() };
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "while :; do :; done"]);
+        let guard = limits.apply(&mut cmd)?;
+        let status = cmd.status().await?;
+        assert_eq!(guard.hit(&status), Some(LimitKind::CpuTime));
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn process_limit_needs_a_pids_cgroup() -> Result<()> {
+        let limits = Limits { max_processes: Some(16), ..Default::default() };
+        let mut cmd = Command::new("true");
+        match limits.apply(&mut cmd) {
+            Ok(guard) => {
+                assert!(guard.cgroup.as_ref().is_some_and(|c| c.pids));
+                assert!(cmd.status().await?.success());
+            }
+            Err(e) => assert!(e.to_string().contains("RLIMIT_NPROC would count every process of the user"), "{}", e),
+        }
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn memory_limit_needs_a_memory_cgroup() -> Result<()> {
+        let limits = Limits { memory_bytes: Some(64 << 20), ..Default::default() };
+        let mut cmd = Command::new("true");
+        match limits.apply(&mut cmd) {
+            Ok(guard) => {
+                assert!(guard.cgroup.as_ref().is_some_and(|c| c.memory));
+                assert!(cmd.status().await?.success());
+            }
+            Err(e) => assert!(e.to_string().contains("limits.memory_mb needs a cgroup v2"), "{}", e),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,144 @@
+// annex/src/matrix.rs — expand `matrix` tasks into one concrete task per combination
+
+use anyhow::{Context, Result};
+use globset::GlobBuilder;
+use ignore::WalkBuilder;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, path::Path};
+
+use crate::{
+    taskset::{TaskSetSpec, TaskSpec},
+    template,
+};
+
+/// Values of one matrix axis, read in steps as `{{matrix.<axis>}}`.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum MatrixAxis {
+    Values(Vec<String>),
+    /// Workspace paths matching `glob` (relative to the run's cwd, `.gitignore` respected),
+    /// e.g. `{ glob = "crates/*" }` for one task per crate.
+    Glob { glob: String },
+}
+
+impl MatrixAxis {
+    fn values(&self, root: &Path) -> Result<Vec<String>> {
+        let glob = match self {
+            MatrixAxis::Values(v) => return Ok(v.clone()),
+            MatrixAxis::Glob { glob } => glob,
+        };
+        let matcher = GlobBuilder::new(glob).literal_separator(true).build()
+            .with_context(|| format!("invalid glob: {}", glob))?.compile_matcher();
+        let mut out = vec![];
+        for entry in WalkBuilder::new(root).hidden(false).follow_links(false).git_ignore(true).build() {
+            let entry = entry?;
+            let Ok(rel) = entry.path().strip_prefix(root) else { continue };
+            if !rel.as_os_str().is_empty() && matcher.is_match(rel) {
+                out.push(rel.to_string_lossy().replace('\\', "/"));
+            }
+        }
+        out.sort();
+        Ok(out)
+    }
+}
+
+impl TaskSetSpec {
+    /// Replace every task that has a `matrix` by one task per combination of axis values, in
+    /// declaration order. Generated ids are `<id>-<value>-...` (axes sorted by name, values with
+    /// characters other than `[A-Za-z0-9_.-]` replaced by `-`), so they stay stable across runs.
+    /// A `depends_on` naming a matrix task waits for all of its expansions.
+    pub fn expand_matrix(&self, root: &Path) -> Result<TaskSetSpec> {
+        let mut tasks = vec![];
+        let mut expanded: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+        for t in &self.tasks {
+            if t.matrix.is_empty() {
+                tasks.push(t.clone());
+                continue;
+            }
+            let mut combos = vec![BTreeMap::new()];
+            for (axis, values) in &t.matrix {
+                let values = values.values(root).with_context(|| format!("task '{}': matrix axis '{}'", t.id, axis))?;
+                combos = combos.into_iter()
+                    .flat_map(|c| values.iter().map(move |v| { let mut c = c.clone(); c.insert(axis.as_str(), v.clone()); c }))
+                    .collect();
+            }
+            let ids = expanded.entry(t.id.as_str()).or_default();
+            for values in combos {
+                let task = instantiate(t, &values).with_context(|| format!("task '{}'", t.id))?;
+                ids.push(task.id.clone());
+                tasks.push(task);
+            }
+        }
+        for t in &mut tasks {
+            t.depends_on = t.depends_on.iter()
+                .flat_map(|d| expanded.get(d.as_str()).cloned().unwrap_or_else(|| vec![d.clone()]))
+                .collect();
+        }
+        Ok(TaskSetSpec { tasks, ..self.clone() })
+    }
+}
+
+fn instantiate(t: &TaskSpec, values: &BTreeMap<&str, String>) -> Result<TaskSpec> {
+    let sub = |s: &str| template::render(s, "matrix", |axis| values.get(axis).cloned());
+    let suffix: Vec<String> = values.values().map(|v| {
+        v.chars().map(|c| if c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-') { c } else { '-' }).collect()
+    }).collect();
+    let mut name = sub(&t.name)?;
+    if name == t.name {
+        name = format!("{} [{}]", t.name, values.values().cloned().collect::<Vec<_>>().join(", "));
+    }
+    let mut task = TaskSpec {
+        id: format!("{}-{}", t.id, suffix.join("-")),
+        name,
+        model_profile: t.model_profile.as_deref().map(sub).transpose()?,
+        status_line: t.status_line.as_deref().map(sub).transpose()?,
+        when: t.when.as_deref().map(sub).transpose()?,
+        matrix: BTreeMap::new(),
+        ..t.clone()
+    };
+    for spec in &mut task.steps {
+        spec.step = spec.step.try_map_strings(&mut |s| sub(s))?;
+        spec.when = spec.when.as_deref().map(sub).transpose()?;
+    }
+    Ok(task)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matrix_tasks_expand_with_stable_ids_and_glob_axes() -> Result<()> {
+        let root = tempfile::tempdir()?;
+        for c in ["alpha", "beta"] { std::fs::create_dir_all(root.path().join("crates").join(c).join("src"))?; }
+        let set: TaskSetSpec = serde_json::from_value(serde_json::json!({
+            "set_id": "s", "title": "t", "mode": "parallel",
+            "tasks": [
+                { "id": "test", "name": "test {{matrix.crate}} on {{matrix.os}}",
+                  "matrix": { "os": ["linux", "macos"], "crate": { "glob": "crates/*" } },
+                  "steps": [{ "type": "exec", "cmd": "cargo", "args": ["test", "--manifest-path", "{{matrix.crate}}/Cargo.toml"],
+                              "when": "env.OS == '{{matrix.os}}'" }] },
+                { "id": "lint", "name": "lint", "matrix": { "target": ["x86_64-unknown-linux-gnu"] },
+                  "steps": [{ "type": "exec", "cmd": "clippy", "args": [] }] },
+                { "id": "report", "name": "report", "depends_on": ["test", "lint"], "steps": [{ "type": "chat", "prompt": "done" }] },
+            ],
+        }))?;
+        set.validate()?;
+        let out = set.expand_matrix(root.path())?;
+        out.validate()?;
+        let ids: Vec<&str> = out.tasks.iter().map(|t| t.id.as_str()).collect();
+        assert_eq!(ids, ["test-crates-alpha-linux", "test-crates-alpha-macos", "test-crates-beta-linux", "test-crates-beta-macos",
+            "lint-x86_64-unknown-linux-gnu", "report"]);
+        assert_eq!(out.tasks[1].name, "test crates/alpha on macos");
+        assert_eq!(out.tasks[4].name, "lint [x86_64-unknown-linux-gnu]");
+        let step = serde_json::to_value(&out.tasks[2].steps[0])?;
+        assert_eq!(step["args"][2], "crates/beta/Cargo.toml");
+        assert_eq!(step["when"], "env.OS == 'linux'");
+        assert_eq!(out.tasks[5].depends_on.len(), 5);
+
+        let mut bad = set.clone();
+        bad.tasks[0].name = "{{matrix.arch}}".into();
+        assert!(bad.validate().unwrap_err().to_string().contains("matrix.arch"));
+        Ok(())
+    }
+}
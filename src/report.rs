@@ -0,0 +1,154 @@
+// annex/src/report.rs — structured results of a TaskSetRunner run
+
+use serde::{Deserialize, Serialize};
+
+use crate::{limits::LimitKind, template::StepOutput, worktree::MergeResult};
+
+/// Step outputs kept in a report are cut to this many characters.
+pub const OUTPUT_LIMIT: usize = 2000;
+
+/// What `TaskSetRunner::run` returns; also written as `taskset-report.json` next to the session log.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct TaskSetReport {
+    pub session_id: String,
+    /// Every set that ran succeeded and none was skipped, aborted or cancelled.
+    pub ok: bool,
+    pub cancelled: bool,
+    pub duration_ms: u64,
+    /// Sets in the order they ran; sets finished in an earlier run (checkpoint) are left out.
+    pub sets: Vec<SetReport>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SetReport {
+    pub set_id: String,
+    pub title: String,
+    pub ok: bool,
+    pub cancelled: bool,
+    pub duration_ms: u64,
+    /// Declaration order, including skipped tasks.
+    pub tasks: Vec<TaskReport>,
+    /// The `TaskStatus` model's reply to this report.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<String>,
+    /// Why there is no summary: the `TaskStatus` model call failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Why the set's end is missing from the checkpoint; resuming runs the set again.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub checkpoint_error: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum TaskOutcome {
+    Succeeded,
+    Failed,
+    Cancelled,
+    /// Never started (failed dependency, aborted set or cancellation).
+    Skipped { reason: String },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TaskReport {
+    pub id: String,
+    pub name: String,
+    pub model: String,
+    pub outcome: TaskOutcome,
+    pub duration_ms: u64,
+    /// Succeeded in an earlier run and was not run again.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub from_checkpoint: bool,
+    pub steps: Vec<StepReport>,
+    /// Reasons given by hooks or the shell policy for refusing one of the task's steps.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hook_denials: Vec<String>,
+    /// Bridge error or unmet success criteria that failed the task.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Fate of the task's branch in a set with `worktrees`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub worktree: Option<MergeResult>,
+}
+
+impl TaskReport {
+    pub fn new(id: &str, name: &str, model: &str, outcome: TaskOutcome) -> Self {
+        Self {
+            id: id.into(), name: name.into(), model: model.into(), outcome,
+            duration_ms: 0, from_checkpoint: false, steps: vec![], hook_denials: vec![], error: None, worktree: None,
+        }
+    }
+
+    pub fn succeeded(&self) -> bool { self.outcome == TaskOutcome::Succeeded }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StepReport {
+    pub index: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub kind: String,
+    pub ok: bool,
+    pub attempts: u32,
+    pub duration_ms: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+    /// stdout, chat reply or MCP response, truncated to [`OUTPUT_LIMIT`].
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub output: String,
+    /// Files a git step touched.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub files: Vec<String>,
+    /// Commit a git step created or moved to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commit: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub denied: Option<String>,
+    /// Resource limit that stopped an exec step.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<LimitKind>,
+    /// Not run because its `when` was false.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub skipped: bool,
+    /// Steps a sub-agent step ran.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub nested: Vec<StepReport>,
+}
+
+impl StepReport {
+    pub fn new(index: usize, id: Option<String>, kind: &str) -> Self {
+        Self { index, id, kind: kind.into(), ok: false, attempts: 0, duration_ms: 0, exit_code: None, output: String::new(), files: vec![], commit: None, denied: None, limit: None, skipped: false, nested: vec![] }
+    }
+
+    pub fn fill_from(&mut self, out: &StepOutput) {
+        self.ok = out.ok;
+        self.exit_code = out.exit_code;
+        self.files = out.files.clone();
+        self.commit = out.commit.clone();
+        self.limit = out.limit;
+        let text = if !out.stdout.is_empty() { out.stdout.clone() }
+            else if !out.reply.is_empty() { out.reply.clone() }
+            else if !out.response.is_null() { out.response.to_string() }
+            else { String::new() };
+        self.output = truncate(&text, OUTPUT_LIMIT);
+    }
+}
+
+/// `text` cut to at most `max` characters, with a marker when something was dropped.
+pub fn truncate(text: &str, max: usize) -> String {
+    match text.char_indices().nth(max) {
+        Some((end, _)) => format!("{}… [truncated {} bytes]", &text[..end], text.len() - end),
+        None => text.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncate_respects_char_boundaries() {
+        assert_eq!(truncate("héllo", 10), "héllo");
+        assert_eq!(truncate("héllo", 2), "hé… [truncated 3 bytes]");
+    }
+}
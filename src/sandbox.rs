@@ -0,0 +1,205 @@
+// annex/src/sandbox.rs — Landlock + seccomp sandbox for spawned commands from [sandbox] config
+
+use anyhow::{bail, Result};
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+
+use crate::layered_config::SandboxConfig;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SandboxMode {
+    /// No restrictions (also used when `mode` is unset).
+    #[default]
+    DangerFullAccess,
+    /// The whole filesystem is readable; nothing is writable except `/dev/null`.
+    ReadOnly,
+    /// Like `ReadOnly`, plus the working directory, `writable_roots` and the temp dir are writable.
+    WorkspaceWrite,
+}
+
+impl SandboxMode {
+    pub fn parse(mode: Option<&str>) -> Result<Self> {
+        Ok(match mode {
+            None | Some("danger_full_access") => SandboxMode::DangerFullAccess,
+            Some("read_only") => SandboxMode::ReadOnly,
+            Some("workspace_write") => SandboxMode::WorkspaceWrite,
+            Some(other) => bail!("sandbox.mode must be danger_full_access, read_only or workspace_write, not '{}'", other),
+        })
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SandboxMode::DangerFullAccess => "danger_full_access",
+            SandboxMode::ReadOnly => "read_only",
+            SandboxMode::WorkspaceWrite => "workspace_write",
+        }
+    }
+}
+
+/// Restrictions for one spawned command, resolved from `[sandbox]` and the command's cwd.
+/// Outside `danger_full_access`, writes are confined with Landlock and, unless
+/// `network_access = true`, sockets other than Unix ones are refused with seccomp.
+#[derive(Clone, Debug, Default)]
+pub struct Sandbox {
+    pub mode: SandboxMode,
+    /// Roots writable under `workspace_write`; relative `writable_roots` are resolved against cwd.
+    pub writable: Vec<PathBuf>,
+    pub network: bool,
+}
+
+impl Sandbox {
+    pub fn new(cfg: &SandboxConfig, cwd: &Path) -> Result<Self> {
+        let mode = SandboxMode::parse(cfg.mode.as_deref())?;
+        let mut writable = vec![];
+        if mode == SandboxMode::WorkspaceWrite {
+            writable.push(cwd.to_path_buf());
+            writable.extend(cfg.writable_roots.iter().map(|r| cwd.join(r)));
+            writable.push(std::env::temp_dir());
+        }
+        Ok(Self { mode, writable, network: cfg.network_access.unwrap_or(false) })
+    }
+
+    pub fn is_active(&self) -> bool { self.mode != SandboxMode::DangerFullAccess }
+
+    /// Install the sandbox in `cmd`'s child right before it execs. Fails up front, with the
+    /// reason, when the running kernel cannot enforce it. Every spawn of `cmd` is sandboxed.
+    pub fn apply(&self, cmd: &mut Command) -> Result<()> {
+        if !self.is_active() { return Ok(()); }
+        self.apply_os(cmd)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn apply_os(&self, cmd: &mut Command) -> Result<()> {
+        use anyhow::Context;
+        use landlock::{path_beneath_rules, Access, AccessFs, CompatLevel, Compatible, Ruleset, RulesetAttr, RulesetCreatedAttr, ABI};
+
+        // LANDLOCK_CREATE_RULESET_VERSION: returns the ABI version, or fails without Landlock.
+        let abi = unsafe { libc::syscall(libc::SYS_landlock_create_ruleset, std::ptr::null::<libc::c_void>(), 0usize, 1u32) };
+        if abi < 1 {
+            bail!("sandbox mode '{}' needs Landlock, which this kernel does not provide (Linux 5.13+ with landlock in the lsm= list): {}",
+                self.mode.as_str(), std::io::Error::last_os_error());
+        }
+        let abi = ABI::V5;
+        let mut ruleset = Ruleset::default()
+            .set_compatibility(CompatLevel::HardRequirement)
+            .handle_access(AccessFs::from_all(ABI::V1))?
+            .set_compatibility(CompatLevel::BestEffort)
+            .handle_access(AccessFs::from_all(abi))?
+            .create()?
+            .add_rules(path_beneath_rules(["/"], AccessFs::from_read(abi)))?
+            .add_rules(path_beneath_rules(["/dev/null"], AccessFs::from_all(abi)))?;
+        if !self.writable.is_empty() {
+            ruleset = ruleset.add_rules(path_beneath_rules(&self.writable, AccessFs::from_all(abi)))?;
+        }
+        let network = if self.network { None } else { Some(network_filter().context("cannot build the seccomp network filter")?) };
+
+        let mut ruleset = Some(ruleset);
+        // SAFETY: the closure runs between fork and exec; it only issues prctl/landlock/seccomp
+        // syscalls on state prepared above.
+        unsafe {
+            cmd.pre_exec(move || {
+                // Only the forked child's copy is emptied, so each spawn finds the ruleset. Should it
+                // ever be gone, the child must not run unconfined.
+                let ruleset = ruleset.take().ok_or_else(|| std::io::Error::other("sandbox already applied; build a new Command"))?;
+                ruleset.restrict_self().map_err(std::io::Error::other)?;
+                if let Some(filter) = &network {
+                    seccompiler::apply_filter(filter).map_err(std::io::Error::other)?;
+                }
+                Ok(())
+            });
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn apply_os(&self, _cmd: &mut Command) -> Result<()> {
+        bail!("sandbox mode '{}' is only supported on Linux (Landlock and seccomp)", self.mode.as_str())
+    }
+}
+
+/// Refuse `socket()` for every domain but `AF_UNIX`, and io_uring (which could open sockets
+/// without that syscall).
+#[cfg(target_os = "linux")]
+fn network_filter() -> Result<seccompiler::BpfProgram> {
+    use seccompiler::{SeccompAction, SeccompCmpArgLen, SeccompCmpOp, SeccompCondition, SeccompFilter, SeccompRule};
+    use std::collections::BTreeMap;
+
+    let not_unix = SeccompRule::new(vec![SeccompCondition::new(0, SeccompCmpArgLen::Dword, SeccompCmpOp::Ne, libc::AF_UNIX as u64)?])?;
+    let rules = BTreeMap::from([
+        (libc::SYS_socket, vec![not_unix]),
+        (libc::SYS_io_uring_setup, vec![]),
+    ]);
+    let filter = SeccompFilter::new(rules, SeccompAction::Allow, SeccompAction::Errno(libc::EPERM as u32), std::env::consts::ARCH.try_into()?)?;
+    Ok(filter.try_into()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn sh(sandbox: &Sandbox, cwd: &Path, script: &str) -> Result<(bool, String)> {
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", script]).current_dir(cwd);
+        sandbox.apply(&mut cmd)?;
+        let out = cmd.output().await?;
+        Ok((out.status.success(), String::from_utf8_lossy(&out.stderr).into_owned()))
+    }
+
+    #[tokio::test]
+    async fn sandbox_confines_writes_or_explains_why_it_cannot() -> Result<()> {
+        let (work, other) = (tempfile::tempdir()?, tempfile::tempdir_in(Path::new(env!("CARGO_MANIFEST_DIR")).join("target"))?);
+        // `other` is outside the temp dir, which workspace_write leaves writable.
+        let cfg = |mode: &str| SandboxConfig { mode: Some(mode.into()), ..Default::default() };
+        let ro = Sandbox::new(&cfg("read_only"), work.path())?;
+        let ww = Sandbox::new(&cfg("workspace_write"), work.path())?;
+        assert!(ww.writable.contains(&work.path().to_path_buf()));
+        assert!(!Sandbox::new(&cfg("danger_full_access"), work.path())?.is_active());
+        assert!(Sandbox::new(&cfg("jail"), work.path()).is_err());
+
+        let outside = format!("echo x > {}/f", other.path().display());
+        match sh(&ro, work.path(), "cat /etc/hostname >/dev/null; echo x > f").await {
+            Err(e) => {
+                assert!(e.to_string().contains("Landlock"), "{}", e);
+                return Ok(());
+            }
+            Ok((ok, _)) => assert!(!ok, "read_only allowed a write"),
+        }
+        assert!(sh(&ww, work.path(), "echo x > f").await?.0);
+        assert!(!sh(&ww, work.path(), &outside).await?.0);
+        assert!(sh(&Sandbox::default(), work.path(), &outside).await?.0);
+
+        let mut twice = Command::new("sh");
+        twice.args(["-c", "echo x > g"]).current_dir(work.path());
+        ro.apply(&mut twice)?;
+        for _ in 0..2 {
+            let out = twice.output().await?;
+            assert!(!out.status.success(), "a second spawn ran without the sandbox");
+            assert!(String::from_utf8_lossy(&out.stderr).contains("Permission denied"), "{}", String::from_utf8_lossy(&out.stderr));
+        }
+        assert!(!work.path().join("g").exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sandbox_refuses_network_sockets_unless_allowed() -> Result<()> {
+        let work = tempfile::tempdir()?;
+        let cfg = |network: bool| SandboxConfig { mode: Some("workspace_write".into()), network_access: Some(network), ..Default::default() };
+        let dir = work.path();
+        let connect = |sandbox: Sandbox| async move {
+            let mut cmd = Command::new("bash");
+            cmd.args(["-c", "exec 3<>/dev/tcp/127.0.0.1/9"]).current_dir(dir);
+            sandbox.apply(&mut cmd)?;
+            let out = cmd.output().await?;
+            anyhow::Ok((out.status.success(), String::from_utf8_lossy(&out.stderr).into_owned()))
+        };
+        let (ok, stderr) = match connect(Sandbox::new(&cfg(false), work.path())?).await {
+            // Without Landlock there is no sandbox to test; the other test checks the error.
+            Err(e) if e.to_string().contains("Landlock") => return Ok(()),
+            res => res?,
+        };
+        assert!(!ok && stderr.contains("Operation not permitted"), "{}", stderr);
+        let (_, stderr) = connect(Sandbox::new(&cfg(true), work.path())?).await?;
+        assert!(!stderr.contains("Operation not permitted"), "{}", stderr);
+        Ok(())
+    }
+}
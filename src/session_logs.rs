@@ -5,7 +5,7 @@ use chrono::{Datelike, Utc};
 use serde::{Deserialize, Serialize};
 use std::{fs, io::Write, path::{Path, PathBuf}};
 
-use crate::layered_config::ConfigManager;
+use crate::{hooks::EventChange, layered_config::ConfigManager};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
@@ -14,6 +14,9 @@ pub enum SessionEvent {
     ModelMsg { model: String, content: String },
     Exec { cmd: String, argv: Vec<String>, status: i32, cwd: String },
     FileRef { path: String, reason: String },
+    StepRetry { set_id: String, task_id: String, step: usize, attempt: u32, reason: String },
+    /// Hook `rules` rewrote a step's pre-event; the step ran with the changed fields.
+    HookModify { set_id: String, task_id: String, step: usize, rules: Vec<String>, changes: Vec<EventChange> },
     Meta { key: String, value: serde_json::Value },
 }
 
@@ -28,7 +31,6 @@ struct OutEvent {
 pub struct SessionLogWriter {
     root_dir: PathBuf,
     _session_id: String,
-    #[allow(dead_code)]
     day_dir: PathBuf,
     json_file: PathBuf,
     jsonl_file: PathBuf,
@@ -98,15 +100,24 @@ impl SessionLogWriter {
             let d = e?.path();
             if !d.is_dir() { continue; }
             let md = fs::metadata(&d)?;
-            if let Ok(modified) = md.modified() {
-                if now.duration_since(modified).unwrap_or(Duration::ZERO) > Duration::from_secs(86400 * keep_days as u64) {
-                    let _ = fs::remove_dir_all(&d);
-                }
+            if let Ok(modified) = md.modified()
+                && now.duration_since(modified).unwrap_or(Duration::ZERO) > Duration::from_secs(86400 * keep_days as u64)
+            {
+                let _ = fs::remove_dir_all(&d);
             }
         }
         Ok(())
     }
 
+    /// Write `value` as pretty JSON to `name` in the session's log directory (redacted like events).
+    pub fn write_artifact(&self, name: &str, value: &impl Serialize) -> Result<PathBuf> {
+        let path = self.day_dir.join(name);
+        let redacted = redact_json(serde_json::to_value(value)?)?;
+        fs::write(&path, serde_json::to_string_pretty(&redacted)?)?;
+        Ok(path)
+    }
+
+    pub fn dir(&self) -> &Path { &self.day_dir }
     pub fn json_path(&self) -> &Path { &self.json_file }
     pub fn jsonl_path(&self) -> &Path { &self.jsonl_file }
 }
@@ -124,10 +135,10 @@ fn redact_json(mut v: serde_json::Value) -> Result<serde_json::Value> {
             *s = r;
         }
         serde_json::Value::Array(ref mut arr) => {
-            for x in arr.iter_mut() { *x = redact_json(std::mem::take(x)).unwrap_or_else(|_| serde_json::Value::Null); }
+            for x in arr.iter_mut() { *x = redact_json(std::mem::take(x)).unwrap_or(serde_json::Value::Null); }
         }
         serde_json::Value::Object(ref mut map) => {
-            for (_k, x) in map.iter_mut() { *x = redact_json(std::mem::take(x)).unwrap_or_else(|_| serde_json::Value::Null); }
+            for (_k, x) in map.iter_mut() { *x = redact_json(std::mem::take(x)).unwrap_or(serde_json::Value::Null); }
         }
         _ => {}
     }
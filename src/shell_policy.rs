@@ -0,0 +1,426 @@
+// annex/src/shell_policy.rs — allow / ask / deny for commands from `[shell]` config
+//
+// Every root binary of a command is checked: `FOO=1 cargo test | tee log && (cd x; make)` has the
+// roots cargo, tee, cd and make. `bash -lc '<script>'` (and sh/zsh/dash) is checked by its script,
+// `eval 'a b'` by the script `a b`, and wrappers such as `env`, `sudo` or `xargs` by the command they run.
+
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+use crate::layered_config::{ApprovalMode, ShellConfig};
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Decision { Allow, Ask, Deny }
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct RootCheck { pub root: String, pub decision: Decision }
+
+/// Verdict for a whole command: the strictest decision over its roots.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PolicyCheck {
+    pub decision: Decision,
+    pub roots: Vec<RootCheck>,
+    /// Constructs the parser can't see through (command substitution, heredocs, ...); each forces `Ask`.
+    pub opaque: Vec<String>,
+}
+
+impl PolicyCheck {
+    /// Why the command isn't simply allowed, e.g. "`rm` is denied; uses command substitution".
+    pub fn reason(&self) -> String {
+        let roots = self.roots.iter().filter_map(|r| match r.decision {
+            Decision::Allow => None,
+            Decision::Ask => Some(format!("`{}` needs approval", r.root)),
+            Decision::Deny => Some(format!("`{}` is denied", r.root)),
+        });
+        roots.chain(self.opaque.iter().map(|o| format!("uses {}", o))).collect::<Vec<_>>().join("; ")
+    }
+}
+
+/// Asked when a command needs approval; answering `false` refuses it.
+#[async_trait]
+pub trait ExecApproval: Send + Sync {
+    async fn approve(&self, cmd: &str, args: &[String], check: &PolicyCheck) -> Result<bool>;
+}
+
+/// `[shell]` allow/deny lists and approval mode.
+///
+/// A denylisted root is denied and an allowlisted one allowed. Other roots need approval under
+/// `unless_trusted` and are allowed otherwise; `never` turns every "needs approval" into a denial.
+#[derive(Clone, Debug)]
+pub struct ShellPolicy {
+    approval: ApprovalMode,
+    allow: Vec<String>,
+    deny: Vec<String>,
+}
+
+const SHELLS: &[&str] = &["bash", "sh", "zsh", "dash", "ksh"];
+/// Long shell options that take the next word as their value.
+const SHELL_VALUE_OPTIONS: &[&str] = &["--rcfile", "--init-file"];
+
+/// A command that runs the command following its options; it is not a root itself.
+struct Wrapper {
+    name: &'static str,
+    /// Short options that take a value: the rest of their cluster, or the next word.
+    short: &'static str,
+    /// Long options that take the next word as their value (unless written `--opt=value`).
+    long: &'static [&'static str],
+    /// Operands before the command, such as the duration of `timeout`.
+    operands: usize,
+}
+
+const WRAPPERS: &[Wrapper] = &[
+    Wrapper { name: "env", short: "uCS", long: &["--unset", "--chdir", "--split-string"], operands: 0 },
+    Wrapper { name: "command", short: "", long: &[], operands: 0 },
+    Wrapper { name: "exec", short: "a", long: &[], operands: 0 },
+    Wrapper { name: "nohup", short: "", long: &[], operands: 0 },
+    Wrapper { name: "time", short: "fo", long: &["--format", "--output"], operands: 0 },
+    Wrapper { name: "nice", short: "n", long: &["--adjustment"], operands: 0 },
+    Wrapper { name: "timeout", short: "sk", long: &["--signal", "--kill-after"], operands: 1 },
+    Wrapper { name: "stdbuf", short: "ioe", long: &["--input", "--output", "--error"], operands: 0 },
+    Wrapper {
+        name: "sudo",
+        short: "CDgpRrTtUu",
+        long: &["--close-from", "--chdir", "--group", "--host", "--prompt", "--chroot", "--role", "--type", "--command-timeout", "--other-user", "--user"],
+        operands: 0,
+    },
+    Wrapper { name: "doas", short: "Cu", long: &[], operands: 0 },
+    Wrapper {
+        name: "xargs",
+        short: "adEILnPs",
+        long: &["--arg-file", "--delimiter", "--max-args", "--max-procs", "--max-chars", "--process-slot-var"],
+        operands: 0,
+    },
+];
+const KEYWORDS: &[&str] = &["if", "then", "else", "elif", "fi", "do", "done", "while", "until", "!", "{", "}", "esac"];
+/// Nested `bash -c` scripts deeper than this are treated as opaque.
+const MAX_DEPTH: usize = 8;
+
+impl ShellPolicy {
+    pub fn new(cfg: &ShellConfig) -> Self {
+        Self { approval: cfg.approval.clone(), allow: cfg.allowlist_roots.clone(), deny: cfg.denylist_roots.clone() }
+    }
+
+    /// Check `cmd args...` as it would be spawned (no shell involved unless `cmd` is one).
+    pub fn check(&self, cmd: &str, args: &[String]) -> PolicyCheck {
+        let argv: Vec<String> = std::iter::once(cmd.to_string()).chain(args.iter().cloned()).collect();
+        let mut out = Walk::default();
+        self.walk_argv(&argv, &mut out, 0);
+        self.finish(out)
+    }
+
+    /// Check a shell script such as the argument of `bash -lc`.
+    pub fn check_script(&self, script: &str) -> PolicyCheck {
+        let mut out = Walk::default();
+        self.walk_script(script, &mut out, 0);
+        self.finish(out)
+    }
+
+    fn finish(&self, Walk { roots, opaque }: Walk) -> PolicyCheck {
+        let never = matches!(self.approval, ApprovalMode::Never);
+        let strict = |d: Decision| if never && d == Decision::Ask { Decision::Deny } else { d };
+        let roots: Vec<RootCheck> = roots.into_iter().map(|root| {
+            let decision = strict(self.root_decision(&root));
+            RootCheck { root, decision }
+        }).collect();
+        let floor = if opaque.is_empty() { Decision::Allow } else { strict(Decision::Ask) };
+        let decision = roots.iter().map(|r| r.decision).fold(floor, Decision::max);
+        PolicyCheck { decision, roots, opaque }
+    }
+
+    fn root_decision(&self, root: &str) -> Decision {
+        let listed = |list: &[String]| list.iter().any(|e| basename(e) == root);
+        if listed(&self.deny) { Decision::Deny }
+        else if listed(&self.allow) { Decision::Allow }
+        else if matches!(self.approval, ApprovalMode::UnlessTrusted) { Decision::Ask }
+        else { Decision::Allow }
+    }
+
+    fn walk_script(&self, script: &str, out: &mut Walk, depth: usize) {
+        if depth > MAX_DEPTH {
+            out.opaque.push("deeply nested shells".into());
+            return;
+        }
+        let (toks, opaque) = lex(script);
+        out.opaque.extend(opaque);
+        let mut words: Vec<String> = vec![];
+        let mut redirect = false;
+        for tok in toks.into_iter().chain([Tok::Op(";")]) {
+            match tok {
+                // The word after a redirection is its target, not an argument.
+                Tok::Word(_) if redirect => redirect = false,
+                Tok::Word(w) => words.push(w),
+                Tok::Redirect => redirect = true,
+                Tok::Op(_) => {
+                    self.walk_command(&std::mem::take(&mut words), out, depth);
+                    redirect = false;
+                }
+            }
+        }
+    }
+
+    /// One simple command of a script: drop keywords and loop headers, then walk its argv.
+    fn walk_command(&self, words: &[String], out: &mut Walk, depth: usize) {
+        let start = words.iter().position(|w| !KEYWORDS.contains(&w.as_str())).unwrap_or(words.len());
+        let words = &words[start..];
+        match words.first().map(String::as_str) {
+            None => {}
+            // `for x in a b` / `select x in a b`: the list is data; the body follows `do`.
+            Some("for" | "select") => {}
+            Some("case") => out.opaque.push("a case statement".into()),
+            Some("function") => out.opaque.push("a function definition".into()),
+            Some(w) if w.ends_with("()") || words.get(1).is_some_and(|n| n == "()") => out.opaque.push("a function definition".into()),
+            Some(_) => self.walk_argv(words, out, depth),
+        }
+    }
+
+    fn walk_argv(&self, argv: &[String], out: &mut Walk, depth: usize) {
+        let mut i = argv.iter().take_while(|w| is_assignment(w)).count();
+        while let Some(word) = argv.get(i) {
+            let name = basename(word);
+            if let Some(w) = WRAPPERS.iter().find(|w| w.name == name) {
+                match skip_wrapper_options(w, &argv[i + 1..]) {
+                    Some(n) if argv.len() > i + 1 + n => i += 1 + n,
+                    // Nothing left to run: the wrapper itself is the command (`sudo -s`).
+                    Some(_) => {
+                        out.roots.push(name.to_string());
+                        return;
+                    }
+                    None => {
+                        out.opaque.push(format!("`{} -S`", name));
+                        return;
+                    }
+                }
+                continue;
+            }
+            if name == "eval" {
+                self.walk_script(&argv[i + 1..].join(" "), out, depth + 1);
+                return;
+            }
+            if SHELLS.contains(&name) && let Some(script) = shell_script(&argv[i + 1..]) {
+                self.walk_script(script, out, depth + 1);
+                return;
+            }
+            out.roots.push(name.to_string());
+            return;
+        }
+    }
+}
+
+/// Number of words of `args` (what follows wrapper `w`) before the command it runs: options
+/// with their values, assignments and operands. `None` for `env -S`, which splits a string into
+/// the command.
+fn skip_wrapper_options(w: &Wrapper, args: &[String]) -> Option<usize> {
+    let mut i = 0;
+    while let Some(a) = args.get(i) {
+        i += 1;
+        if a == "--" { break; }
+        if let Some(long) = a.strip_prefix("--") {
+            if w.name == "env" && long.starts_with("split-string") { return None; }
+            if w.long.contains(&a.as_str()) { i += 1; }
+        } else if let Some(cluster) = a.strip_prefix('-') {
+            if let Some(at) = cluster.find(|c| w.short.contains(c)) {
+                if w.name == "env" && cluster[at..].starts_with('S') { return None; }
+                // The value is the rest of the cluster, or the next word when nothing is left.
+                if at + 1 == cluster.len() { i += 1; }
+            }
+        } else if !(matches!(w.name, "env" | "sudo") && is_assignment(a)) {
+            i -= 1;
+            break;
+        }
+    }
+    let operands = args[i.min(args.len())..].iter().take(w.operands).count();
+    Some((i + operands).min(args.len()))
+}
+
+/// The script a shell invoked with `args` runs: the first operand when `-c` is among the options.
+/// Options run up to the first operand; `-o`/`+o` (and `-O`/`+O`) take the next word.
+fn shell_script(args: &[String]) -> Option<&String> {
+    let mut has_c = false;
+    let mut i = 0;
+    while let Some(a) = args.get(i) {
+        i += 1;
+        if a == "--" || a == "-" { break; }
+        if a.starts_with("--") {
+            if SHELL_VALUE_OPTIONS.contains(&a.as_str()) { i += 1; }
+        } else if let Some(cluster) = a.strip_prefix(['-', '+']).filter(|c| !c.is_empty()) {
+            has_c |= a.starts_with('-') && cluster.contains('c');
+            i += cluster.chars().filter(|c| matches!(c, 'o' | 'O')).count();
+        } else {
+            i -= 1;
+            break;
+        }
+    }
+    if has_c { args.get(i) } else { None }
+}
+
+#[derive(Default)]
+struct Walk { roots: Vec<String>, opaque: Vec<String> }
+
+#[derive(Debug, PartialEq)]
+enum Tok { Word(String), Op(&'static str), Redirect }
+
+const OPS: &[&str] = &["&&", "||", "|&", ";;", "|", ";", "&", "(", ")", "\n"];
+
+/// Split a script into words, command separators and redirections. Returns the constructs it
+/// could not analyze next to the tokens.
+fn lex(script: &str) -> (Vec<Tok>, Vec<String>) {
+    let mut toks = vec![];
+    let mut opaque = vec![];
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut chars = script.chars().peekable();
+    let flush = |toks: &mut Vec<Tok>, word: &mut String, in_word: &mut bool| {
+        if *in_word { toks.push(Tok::Word(std::mem::take(word))); }
+        *in_word = false;
+    };
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                in_word = true;
+                for c in chars.by_ref() {
+                    if c == '\'' { break; }
+                    word.push(c);
+                }
+            }
+            '"' => {
+                in_word = true;
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' => word.extend(chars.next()),
+                        '`' => opaque.push("command substitution".into()),
+                        '$' if chars.peek() == Some(&'(') => opaque.push("command substitution".into()),
+                        c => word.push(c),
+                    }
+                }
+            }
+            '\\' => {
+                in_word = true;
+                // A backslash-newline continues the line.
+                if let Some(c) = chars.next() && c != '\n' { word.push(c); }
+            }
+            '`' => opaque.push("command substitution".into()),
+            '$' if chars.peek() == Some(&'(') => opaque.push("command substitution".into()),
+            '#' if !in_word => {
+                for c in chars.by_ref() { if c == '\n' { break; } }
+                flush(&mut toks, &mut word, &mut in_word);
+                toks.push(Tok::Op("\n"));
+            }
+            '<' | '>' => {
+                // `2>`, `2>&1`: a numeric word right before is the file descriptor.
+                if in_word && word.chars().all(|d| d.is_ascii_digit()) { word.clear(); in_word = false; }
+                flush(&mut toks, &mut word, &mut in_word);
+                if c == '<' && chars.peek() == Some(&'<') {
+                    chars.next();
+                    if chars.peek() == Some(&'<') { chars.next(); } else { opaque.push("a heredoc".into()); }
+                }
+                if chars.peek().is_some_and(|n| matches!(n, '>' | '&' | '|')) { chars.next(); }
+                toks.push(Tok::Redirect);
+            }
+            '&' if chars.peek() == Some(&'>') => {
+                chars.next();
+                flush(&mut toks, &mut word, &mut in_word);
+                if chars.peek() == Some(&'>') { chars.next(); }
+                toks.push(Tok::Redirect);
+            }
+            c if c.is_whitespace() && c != '\n' => flush(&mut toks, &mut word, &mut in_word),
+            c => {
+                let rest: String = std::iter::once(c).chain(chars.clone().take(1)).collect();
+                match OPS.iter().find(|op| rest.starts_with(**op)) {
+                    Some(op) => {
+                        flush(&mut toks, &mut word, &mut in_word);
+                        if op.len() == 2 { chars.next(); }
+                        toks.push(Tok::Op(op));
+                    }
+                    None => { in_word = true; word.push(c); }
+                }
+            }
+        }
+    }
+    flush(&mut toks, &mut word, &mut in_word);
+    (toks, opaque)
+}
+
+fn is_assignment(word: &str) -> bool {
+    word.split_once('=').is_some_and(|(name, _)| {
+        !name.is_empty() && !name.starts_with(|c: char| c.is_ascii_digit()) && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    })
+}
+
+fn basename(word: &str) -> &str {
+    word.rsplit('/').next().unwrap_or(word)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(approval: ApprovalMode) -> ShellPolicy {
+        ShellPolicy::new(&ShellConfig {
+            approval,
+            allowlist_roots: vec!["git".into(), "cargo".into(), "ls".into(), "grep".into()],
+            denylist_roots: vec!["rm".into(), "curl".into()],
+            ..Default::default()
+        })
+    }
+
+    fn roots(check: &PolicyCheck) -> Vec<&str> { check.roots.iter().map(|r| r.root.as_str()).collect() }
+
+    #[test]
+    fn scripts_are_split_into_root_binaries() {
+        let p = policy(ApprovalMode::OnRequest);
+        let script = "RUST_LOG=debug cargo test 2>&1 | grep -v noise > out.txt && (cd sub; /usr/bin/git status) || env FOO=1 nice -n 5 ls # rm -rf /";
+        let check = p.check("bash", &["-lc".into(), script.into()]);
+        assert_eq!(roots(&check), ["cargo", "grep", "cd", "git", "ls"]);
+        assert_eq!(check.decision, Decision::Allow);
+
+        let check = p.check_script("for f in *.rs; do rm \"$f\"; done");
+        assert_eq!((roots(&check), check.decision), (vec!["rm"], Decision::Deny));
+        assert_eq!(check.reason(), "`rm` is denied");
+        assert_eq!(roots(&p.check("sh", &["-c".into(), "timeout 10 bash -c 'curl x'".into()])), ["curl"]);
+        assert_eq!(roots(&p.check("bash", &["script.sh".into()])), ["bash"]);
+    }
+
+    #[test]
+    fn shell_options_and_wrappers_do_not_hide_commands() {
+        let p = policy(ApprovalMode::OnRequest);
+        let check = |cmd: &str, args: &[&str]| p.check(cmd, &args.iter().map(|a| a.to_string()).collect::<Vec<_>>());
+        for (cmd, args) in [
+            ("bash", &["-o", "posix", "-c", "rm -rf /"][..]),
+            ("bash", &["+o", "history", "-ec", "rm -rf /"]),
+            ("sh", &["-x", "-o", "errexit", "+O", "extglob", "-c", "rm -rf /"]),
+            ("bash", &["--rcfile", "x", "--norc", "-c", "rm -rf /"]),
+            ("sudo", &["rm", "-rf", "/"]),
+            ("sudo", &["-u", "root", "-E", "FOO=1", "rm", "-rf", "/"]),
+            ("doas", &["-u", "root", "rm", "-rf", "/"]),
+            ("xargs", &["-n", "1", "-I", "{}", "rm", "{}"]),
+            ("eval", &["rm", "-rf", "/"]),
+            ("env", &["-u", "HOME", "rm", "-rf", "/"]),
+            ("env", &["-iu", "HOME", "--", "rm", "-rf", "/"]),
+            ("timeout", &["-s", "KILL", "10", "rm", "-rf", "/"]),
+            ("nice", &["-n", "5", "rm", "-rf", "/"]),
+        ] {
+            let c = check(cmd, args);
+            assert_eq!((roots(&c), c.decision), (vec!["rm"], Decision::Deny), "{} {:?}", cmd, args);
+        }
+        assert_eq!(roots(&check("sudo", &["-s"])), ["sudo"]);
+        assert_eq!(roots(&check("bash", &["-o", "posix", "script.sh"])), ["bash"]);
+        let split = check("env", &["-S", "rm -rf /"]);
+        assert_eq!((split.decision, split.reason().as_str()), (Decision::Ask, "uses `env -S`"));
+    }
+
+    #[test]
+    fn approval_mode_decides_unlisted_and_opaque_commands() {
+        let args = |s: &str| vec!["-c".to_string(), s.to_string()];
+        assert_eq!(policy(ApprovalMode::OnRequest).check("python3", &[]).decision, Decision::Allow);
+        assert_eq!(policy(ApprovalMode::UnlessTrusted).check("python3", &[]).decision, Decision::Ask);
+        assert_eq!(policy(ApprovalMode::Never).check("python3", &[]).decision, Decision::Allow);
+
+        let subst = policy(ApprovalMode::OnRequest).check("bash", &args("ls $(cat list)"));
+        assert_eq!((subst.decision, subst.reason().as_str()), (Decision::Ask, "uses command substitution"));
+        assert_eq!(policy(ApprovalMode::Never).check("bash", &args("cat <<EOF\nx\nEOF")).decision, Decision::Deny);
+        assert_eq!(policy(ApprovalMode::OnRequest).check("bash", &args("echo \"it's $HOME\" && git log")).decision, Decision::Allow);
+    }
+}
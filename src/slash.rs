@@ -84,7 +84,6 @@ impl SlashRegistry {
         }
         let (name, rest) = input[1..]
             .split_once(' ')
-            .map(|(a, b)| (a, b))
             .unwrap_or((&input[1..], ""));
         if let Some(expands) = self.aliases.get(name) {
             return Ok(expands.replace("$ARGS", rest));
@@ -143,8 +142,10 @@ impl SlashRegistry {
                     .get("name")
                     .and_then(|x| x.as_str())
                     .ok_or_else(|| anyhow!("missing name"))?;
-                let mut m = crate::layered_config::McpServer::default();
-                m.enabled = true;
+                let mut m = crate::layered_config::McpServer {
+                    enabled: true,
+                    ..Default::default()
+                };
                 if let Some(stdio) = v.get("stdio") {
                     m.transport = "stdio".into();
                     m.command = stdio.get("cmd").and_then(|x| x.as_str()).map(|s| s.into());
@@ -217,7 +218,7 @@ impl SlashRegistry {
                             let id_owned = it.id.clone();
                             let _ = it;
                             store.save(&path)?;
-                            return Ok(format!("todo added: {} ({})", title_owned, id_owned));
+                            Ok(format!("todo added: {} ({})", title_owned, id_owned))
                         }
                     }
                     "list" if rest.trim().is_empty() => {
@@ -1,32 +1,395 @@
 // annex/src/taskset.rs
 
-use anyhow::Result;
-use futures::{future::join_all};
+use anyhow::{bail, Context, Result};
+use futures::stream::{FuturesUnordered, StreamExt};
 use serde::{Deserialize, Serialize};
-use std::{sync::Arc};
+use async_trait::async_trait;
+use std::{collections::BTreeMap, sync::Arc, time::{Duration, Instant}};
 use tokio::sync::mpsc;
+pub use tokio_util::sync::CancellationToken;
 
 use crate::{
-  layered_config::{ConfigManager, ModelRole},
-  hooks::{HookRegistry, HookContext, HookEvent},
+  layered_config::{ConfigManager, ModelRole, ModelTarget},
+  hooks::{GitEvent, HookRegistry, HookContext, HookDecision, HookEvent},
+  git_ops::{self, GitAction},
+  session_logs::{SessionEvent, SessionLogWriter},
+  template::{self, StepOutput},
+  checkpoint::{CheckpointStore, RunState},
+  report::{SetReport, StepReport, TaskOutcome, TaskReport, TaskSetReport},
+  worktree::{MergeResult, TaskWorktree, WorktreeMode},
+  when::{Condition, Scope as WhenScope},
+  matrix::MatrixAxis,
+  shell_policy::{Decision, ExecApproval, ShellPolicy},
+  child_env::ChildEnv,
+  exec::{ExecOutput, ExecRequest, LineSink, Stream},
+  sandbox::Sandbox,
+  limits::Limits,
+  layered_config::LimitsConfig,
 };
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(tag="type", rename_all="snake_case")]
 pub enum TaskStep {
     Chat { prompt: String, model_profile: Option<String> },
-    Exec { cmd: String, args: Vec<String> },
+    /// `env` is added to the child environment computed from `[shell]` (see [`ChildEnv`]).
+    Exec { cmd: String, args: Vec<String>, #[serde(default, skip_serializing_if = "BTreeMap::is_empty")] env: BTreeMap<String, String> },
     McpCall { server: String, method: String, payload: serde_json::Value },
-    Git { action: String, args: Vec<String> },
+    /// Also accepts the deprecated `args` form (see [`git_ops::deserialize_step`]).
+    Git { #[serde(flatten, deserialize_with = "git_ops::deserialize_step")] action: GitAction },
+    /// Run nested steps as a sub-agent: their chat steps talk to the `agent` model profile.
+    /// Shares the task's session and hook context; nested outputs stay inside the sub-agent.
+    SubAgent { agent: String, steps: Vec<StepSpec> },
 }
 
+impl TaskStep {
+    /// Copy of this step with `f` applied to every templatable string
+    /// (chat prompt, exec cmd/args/env values, MCP payload strings, git paths/messages/names, and the
+    /// steps and `when`s of a sub-agent).
+    pub fn try_map_strings(&self, f: &mut impl FnMut(&str) -> Result<String>) -> Result<TaskStep> {
+        let args = |args: &[String], f: &mut dyn FnMut(&str) -> Result<String>| args.iter().map(|a| f(a)).collect::<Result<Vec<_>>>();
+        Ok(match self {
+            TaskStep::Chat { prompt, model_profile } => TaskStep::Chat { prompt: f(prompt)?, model_profile: model_profile.clone() },
+            TaskStep::Exec { cmd, args: a, env } => TaskStep::Exec {
+                cmd: f(cmd)?,
+                args: args(a, f)?,
+                env: env.iter().map(|(k, v)| Ok((k.clone(), f(v)?))).collect::<Result<_>>()?,
+            },
+            TaskStep::McpCall { server, method, payload } => TaskStep::McpCall { server: server.clone(), method: method.clone(), payload: template::map_json_strings(payload, f)? },
+            TaskStep::Git { action } => TaskStep::Git { action: action.try_map_strings(f)? },
+            TaskStep::SubAgent { agent, steps } => TaskStep::SubAgent {
+                agent: agent.clone(),
+                steps: steps.iter().map(|s| Ok(StepSpec {
+                    step: s.step.try_map_strings(f)?,
+                    when: s.when.as_deref().map(&mut *f).transpose()?,
+                    ..s.clone()
+                })).collect::<Result<_>>()?,
+            },
+        })
+    }
+
+    /// The step's `type` tag.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            TaskStep::Chat { .. } => "chat",
+            TaskStep::Exec { .. } => "exec",
+            TaskStep::McpCall { .. } => "mcp_call",
+            TaskStep::Git { .. } => "git",
+            TaskStep::SubAgent { .. } => "sub_agent",
+        }
+    }
+
+    /// Hook event fired before the step runs; a `Deny` refuses the step.
+    pub fn pre_event(&self) -> HookEvent {
+        match self {
+            TaskStep::Chat { prompt, .. } => HookEvent::PreToolUse { tool: "chat".into(), args: serde_json::json!({ "prompt": prompt }) },
+            TaskStep::Exec { cmd, args, .. } => HookEvent::PreExec { cmd: cmd.clone(), argv: args.clone() },
+            TaskStep::McpCall { server, method, payload } => HookEvent::PreMcp { server: server.clone(), method: method.clone(), payload: payload.clone() },
+            TaskStep::Git { action: GitAction::Commit { .. } } => HookEvent::Git { kind: GitEvent::PreCommit },
+            TaskStep::Git { action } => HookEvent::PreToolUse { tool: "git".into(), args: serde_json::to_value(action).unwrap_or_default() },
+            TaskStep::SubAgent { agent, steps } => HookEvent::PreToolUse { tool: "sub_agent".into(), args: serde_json::json!({ "agent": agent, "steps": steps.len() }) },
+        }
+    }
+
+    /// This step as described by `event`, a rewritten version of its [`pre_event`](Self::pre_event).
+    pub fn with_pre_event(&self, event: &HookEvent) -> Result<TaskStep> {
+        Ok(match (self, event) {
+            (TaskStep::Chat { model_profile, .. }, HookEvent::PreToolUse { tool, args }) if tool == "chat" => TaskStep::Chat {
+                prompt: args.get("prompt").and_then(|p| p.as_str()).context("rewritten chat args have no string 'prompt'")?.into(),
+                model_profile: model_profile.clone(),
+            },
+            (TaskStep::Exec { env, .. }, HookEvent::PreExec { cmd, argv }) => TaskStep::Exec { cmd: cmd.clone(), args: argv.clone(), env: env.clone() },
+            (TaskStep::McpCall { .. }, HookEvent::PreMcp { server, method, payload }) => TaskStep::McpCall { server: server.clone(), method: method.clone(), payload: payload.clone() },
+            (TaskStep::Git { action: GitAction::Commit { .. } }, HookEvent::Git { kind: GitEvent::PreCommit }) => self.clone(),
+            (TaskStep::Git { .. }, HookEvent::PreToolUse { tool, args }) if tool == "git" => TaskStep::Git {
+                action: serde_json::from_value(args.clone()).context("rewritten git args are not a git action")?,
+            },
+            // The event only summarizes the sub-agent; its nested steps are checked by their own events.
+            (TaskStep::SubAgent { .. }, HookEvent::PreToolUse { tool, .. }) if tool == "sub_agent" => self.clone(),
+            _ => bail!("a {} step can't take a rewritten {} event", self.kind(), event.kind()),
+        })
+    }
+
+    /// Output fields this kind of step captures (see [`StepOutput::field`]).
+    fn output_fields(&self) -> &'static [&'static str] {
+        match self {
+            TaskStep::Chat { .. } => &["ok", "reply"],
+            TaskStep::Exec { .. } => &["ok", "exit_code", "stdout"],
+            TaskStep::Git { .. } => &["ok", "stdout", "files", "commit"],
+            TaskStep::McpCall { .. } => &["ok", "response"],
+            TaskStep::SubAgent { .. } => &["ok", "reply"],
+        }
+    }
+}
+
+/// A step together with its execution policy; policy fields sit next to `type` in JSON.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StepSpec {
+    /// Name under which later steps of the task can reference this step's output.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    /// Condition (see [`crate::when`]); the step is skipped when it is false.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub when: Option<String>,
+    #[serde(flatten)]
+    pub step: TaskStep,
+    #[serde(flatten)]
+    pub policy: StepPolicy,
+}
+
+impl From<TaskStep> for StepSpec {
+    fn from(step: TaskStep) -> Self { Self { id: None, when: None, step, policy: StepPolicy::default() } }
+}
+
+/// Timeout/retry knobs for a step; unset fields fall back to the task's `step_defaults`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct StepPolicy {
+    /// Per-attempt timeout.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_secs: Option<u64>,
+    /// Extra attempts after the first one fails, errors or times out.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retries: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backoff: Option<Backoff>,
+}
+
+impl StepPolicy {
+    fn or(&self, fallback: &StepPolicy) -> StepPolicy {
+        StepPolicy {
+            timeout_secs: self.timeout_secs.or(fallback.timeout_secs),
+            retries: self.retries.or(fallback.retries),
+            backoff: self.backoff.clone().or_else(|| fallback.backoff.clone()),
+        }
+    }
+}
+
+/// Delay before retry attempt `n` (1-based). Defaults to exponential from 500ms, capped at 30s.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Backoff {
+    Fixed { delay_ms: u64 },
+    Exponential { initial_ms: u64, #[serde(default)] max_ms: Option<u64> },
+}
+
+impl Default for Backoff {
+    fn default() -> Self { Self::Exponential { initial_ms: 500, max_ms: Some(30_000) } }
+}
+
+impl Backoff {
+    pub fn delay(&self, attempt: u32) -> Duration {
+        match self {
+            Backoff::Fixed { delay_ms } => Duration::from_millis(*delay_ms),
+            Backoff::Exponential { initial_ms, max_ms } => {
+                let ms = initial_ms.saturating_mul(1u64 << attempt.saturating_sub(1).min(32));
+                Duration::from_millis(max_ms.map_or(ms, |max| ms.min(max)))
+            }
+        }
+    }
+}
+
+/// When a task counts as succeeded. Written as an object or as a shorthand string:
+/// `exit_code:0,2`, `output:<regex>` or `mcp:<path>`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(try_from = "CriteriaRepr")]
+pub struct SuccessCriteria {
+    /// Exit codes accepted from exec steps; only 0 when empty. Git steps have no exit code: a git
+    /// action that errors fails the task whatever this says (run git from an exec step to use it).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exit_codes: Vec<i32>,
+    /// Regex that must match the task's output (exec stdout and chat replies, one per line).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output_matches: Option<String>,
+    /// Path into the last MCP result (e.g. `result.ok`) that must hold a value other than
+    /// null, false or "".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mcp_result: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum CriteriaRepr {
+    Shorthand(String),
+    Full {
+        #[serde(default)] exit_codes: Vec<i32>,
+        #[serde(default)] output_matches: Option<String>,
+        #[serde(default)] mcp_result: Option<String>,
+    },
+}
+
+impl TryFrom<CriteriaRepr> for SuccessCriteria {
+    type Error = anyhow::Error;
+    fn try_from(r: CriteriaRepr) -> Result<Self> {
+        Ok(match r {
+            CriteriaRepr::Full { exit_codes, output_matches, mcp_result } => Self { exit_codes, output_matches, mcp_result },
+            CriteriaRepr::Shorthand(s) => match s.split_once(':') {
+                Some(("exit_code", codes)) => Self {
+                    exit_codes: codes.split(',').map(|c| c.trim().parse().with_context(|| format!("bad exit code '{}' in success_criteria", c))).collect::<Result<_>>()?,
+                    ..Default::default()
+                },
+                Some(("output", re)) => Self { output_matches: Some(re.into()), ..Default::default() },
+                Some(("mcp", path)) => Self { mcp_result: Some(path.trim().into()), ..Default::default() },
+                _ => bail!("success_criteria '{}' is not one of exit_code:<codes>, output:<regex>, mcp:<path>", s),
+            },
+        })
+    }
+}
+
+impl SuccessCriteria {
+    pub fn accepts_exit(&self, code: i32) -> bool {
+        if self.exit_codes.is_empty() { code == 0 } else { self.exit_codes.contains(&code) }
+    }
+
+    /// Check the output and MCP conditions against the outputs of a task's steps.
+    pub fn check(&self, outputs: &[StepOutput]) -> Result<()> {
+        if let Some(re) = &self.output_matches {
+            let text: Vec<&str> = outputs.iter().flat_map(|o| [o.stdout.as_str(), o.reply.as_str()]).filter(|s| !s.is_empty()).collect();
+            if !regex::Regex::new(re)?.is_match(&text.join("\n")) { bail!("output does not match /{}/", re); }
+        }
+        if let Some(path) = &self.mcp_result {
+            let last = outputs.iter().rev().find(|o| !o.response.is_null()).context("no MCP result to check")?;
+            match last.field(&format!("response.{}", path)).as_deref() {
+                None | Some("" | "null" | "false") => bail!("MCP result has no truthy '{}'", path),
+                Some(_) => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What a failing step does to the rest of the run.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OnError {
+    /// Run the task's remaining steps; other tasks of the set keep going (dependents are skipped).
+    #[default]
+    Continue,
+    /// Stop the task, cancel the rest of its set and move on to the next set.
+    AbortSet,
+    /// Like `abort_set`, but no later sets run.
+    AbortPlan,
+}
+
+/// Fields a task's `status_line` can reference as `{{task.<field>}}`.
+const STATUS_FIELDS: &[&str] = &["id", "name", "model", "step", "steps", "kind"];
+
 /// One task inside a set
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct TaskSpec {
     pub id: String,
     pub name: String,
     pub model_profile: Option<String>,  // shown in UI; overrides per-step if present
-    pub steps: Vec<TaskStep>,
+    /// `Running` status text, e.g. `"{{task.step}}/{{task.steps}} {{task.kind}}"`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status_line: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub success_criteria: Option<SuccessCriteria>,
+    #[serde(default)]
+    pub on_error: OnError,
+    /// Checked before the task starts (`steps.*` are unset); when false the task and the tasks
+    /// depending on it are skipped without failing the set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub when: Option<String>,
+    /// Ids of tasks in the same set that must succeed before this one is scheduled.
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+    /// Run the task once per combination of axis values (see [`TaskSetSpec::expand_matrix`]).
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub matrix: BTreeMap<String, MatrixAxis>,
+    /// Resource limits for the task's exec steps; unset fields fall back to `[limits]` in config.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limits: Option<LimitsConfig>,
+    /// Policy applied to every step that doesn't set its own.
+    #[serde(flatten)]
+    pub step_defaults: StepPolicy,
+    pub steps: Vec<StepSpec>,
+}
+
+impl TaskSpec {
+    /// Every `{{steps.<id>.<field>}}` and `when` reference must name an earlier step of this task and
+    /// a field it captures; every `{{matrix.<axis>}}` must name an axis of its `matrix`.
+    pub fn validate(&self) -> Result<()> { self.validate_with(None) }
+
+    /// [`validate`](Self::validate), also requiring the `agent` of every sub-agent step to name
+    /// one of `profiles` (the `[models.profiles]` the task will run with) when they are given.
+    pub fn validate_with(&self, profiles: Option<&BTreeMap<String, ModelTarget>>) -> Result<()> {
+        let matrix = |s: &str| -> Result<String> {
+            for (ns, axis) in template::placeholders(s) {
+                if ns == "matrix" && !self.matrix.contains_key(&axis) {
+                    bail!("task '{}': {{{{matrix.{}}}}} names no axis of its matrix", self.id, axis);
+                }
+            }
+            // Any value parses in `when`, quoted or not; the real ones are checked after expansion.
+            template::render(s, "matrix", |_| Some("0".into()))
+        };
+        for s in [Some(&self.name), self.model_profile.as_ref(), self.status_line.as_ref()].into_iter().flatten() {
+            matrix(s)?;
+        }
+        if let Some(when) = &self.when {
+            let cond = Condition::parse(&matrix(when)?).with_context(|| format!("task '{}': when", self.id))?;
+            if let Some(path) = cond.step_refs().first() {
+                bail!("task '{}': when refers to steps.{} before any step ran", self.id, path);
+            }
+        }
+        self.validate_steps(&self.steps, BTreeMap::new(), "", &matrix, profiles)?;
+        if let Some(line) = &self.status_line {
+            template::render(line, "task", |f| STATUS_FIELDS.contains(&f).then(String::new))
+                .with_context(|| format!("task '{}': status_line", self.id))?;
+        }
+        if let Some(re) = self.success_criteria.as_ref().and_then(|c| c.output_matches.as_ref()) {
+            regex::Regex::new(re).with_context(|| format!("task '{}': success_criteria regex", self.id))?;
+        }
+        Ok(())
+    }
+
+    /// Check `steps` given the step ids `defined` before them. Sub-agent steps see the ids defined
+    /// before them plus their own; they are numbered `<parent>.<index>` in errors.
+    fn validate_steps<'s>(
+        &self,
+        steps: &'s [StepSpec],
+        mut defined: BTreeMap<&'s str, &'s TaskStep>,
+        prefix: &str,
+        matrix: &dyn Fn(&str) -> Result<String>,
+        profiles: Option<&BTreeMap<String, ModelTarget>>,
+    ) -> Result<()> {
+        for (idx, spec) in steps.iter().enumerate() {
+            let at = format!("{}{}", prefix, idx);
+            let check = |defined: &BTreeMap<&str, &TaskStep>, path: &str| -> Result<()> {
+                let (id, field) = path.split_once('.').unwrap_or((path, ""));
+                let Some(step) = defined.get(id) else {
+                    bail!("task '{}' step {}: steps.{} refers to no earlier step with id '{}'", self.id, at, path, id);
+                };
+                let head = field.split('.').next().unwrap_or_default();
+                if !step.output_fields().contains(&head) || (head != "response" && head != field) {
+                    bail!("task '{}' step {}: step '{}' has no output '{}'", self.id, at, id, field);
+                }
+                Ok(())
+            };
+            match &spec.step {
+                TaskStep::SubAgent { agent, steps } => {
+                    if profiles.is_some_and(|p| !p.contains_key(agent)) {
+                        bail!("task '{}' step {}: unknown agent profile '{}'", self.id, at, agent);
+                    }
+                    self.validate_steps(steps, defined.clone(), &format!("{}.", at), matrix, profiles)?;
+                }
+                step => {
+                    step.try_map_strings(&mut |s| {
+                        for (ns, path) in template::placeholders(s) {
+                            if ns == "steps" { check(&defined, &path)?; }
+                        }
+                        matrix(s)
+                    })?;
+                }
+            }
+            if let Some(when) = &spec.when {
+                let cond = Condition::parse(&matrix(when)?).with_context(|| format!("task '{}' step {}: when", self.id, at))?;
+                cond.step_refs().into_iter().try_for_each(|path| check(&defined, path))?;
+            }
+            if let Some(id) = &spec.id && defined.insert(id.as_str(), &spec.step).is_some() {
+                bail!("task '{}': duplicate step id '{}'", self.id, id);
+            }
+        }
+        Ok(())
+    }
 }
 
 /// A set of tasks; may run parallel or seq. Only after the set completes we notify main model.
@@ -35,9 +398,59 @@ pub struct TaskSetSpec {
     pub set_id: String,
     pub title: String,
     pub mode: String,  // "sequential" | "parallel"
+    /// Cap on concurrently running tasks in parallel mode; `tasks.max_parallel` in config still applies.
+    #[serde(default)]
+    pub max_parallel: Option<usize>,
+    /// Run each task in its own git worktree on a temporary branch; the mode decides whether the
+    /// branches are merged back or kept when the set ends.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub worktrees: Option<WorktreeMode>,
     pub tasks: Vec<TaskSpec>,
 }
 
+impl TaskSetSpec {
+    /// Reject duplicate ids, unknown `depends_on` ids, dependency cycles and undefined step references.
+    pub fn validate(&self) -> Result<()> { self.validate_with(None) }
+
+    /// [`validate`](Self::validate), checking sub-agents against `profiles` (see [`TaskSpec::validate_with`]).
+    pub fn validate_with(&self, profiles: Option<&BTreeMap<String, ModelTarget>>) -> Result<()> {
+        self.tasks.iter().try_for_each(|t| t.validate_with(profiles))?;
+        self.schedule_order().map(|_| ())
+    }
+
+    /// Topological order of task indices; ties keep declaration order.
+    pub fn schedule_order(&self) -> Result<Vec<usize>> {
+        let mut index = BTreeMap::new();
+        for (i, t) in self.tasks.iter().enumerate() {
+            if index.insert(t.id.as_str(), i).is_some() { bail!("set '{}': duplicate task id '{}'", self.set_id, t.id); }
+        }
+        let mut indegree = vec![0usize; self.tasks.len()];
+        let mut dependents = vec![vec![]; self.tasks.len()];
+        for (i, t) in self.tasks.iter().enumerate() {
+            for d in &t.depends_on {
+                let Some(&j) = index.get(d.as_str()) else { bail!("set '{}': task '{}' depends on unknown task '{}'", self.set_id, t.id, d); };
+                if j == i { bail!("set '{}': task '{}' depends on itself", self.set_id, t.id); }
+                indegree[i] += 1;
+                dependents[j].push(i);
+            }
+        }
+        let mut order = Vec::with_capacity(self.tasks.len());
+        let mut ready: std::collections::BTreeSet<usize> = (0..self.tasks.len()).filter(|&i| indegree[i] == 0).collect();
+        while let Some(i) = ready.pop_first() {
+            order.push(i);
+            for &k in &dependents[i] {
+                indegree[k] -= 1;
+                if indegree[k] == 0 { ready.insert(k); }
+            }
+        }
+        if order.len() != self.tasks.len() {
+            let cyclic: Vec<&str> = (0..self.tasks.len()).filter(|i| indegree[*i] > 0).map(|i| self.tasks[i].id.as_str()).collect();
+            bail!("set '{}': dependency cycle among tasks {:?}", self.set_id, cyclic);
+        }
+        Ok(order)
+    }
+}
+
 /// Execution plan: 1..N sets; we confirm between sets and can refine next set.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct TaskSetPlan {
@@ -45,11 +458,24 @@ pub struct TaskSetPlan {
     pub sets: Vec<TaskSetSpec>,
 }
 
+impl TaskSetPlan {
+    pub fn validate(&self) -> Result<()> { self.validate_with(None) }
+
+    /// [`validate`](Self::validate), checking sub-agents against `profiles` (see [`TaskSpec::validate_with`]).
+    pub fn validate_with(&self, profiles: Option<&BTreeMap<String, ModelTarget>>) -> Result<()> {
+        self.sets.iter().try_for_each(|s| s.validate_with(profiles))
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum TaskStatus {
     Pending,
     Running { status_line: String },
     Done { ok: bool },
+    /// Waiting for `depends_on` tasks to finish.
+    Blocked { waiting_on: Vec<String> },
+    /// Never started because an upstream task failed or was skipped.
+    Skipped { reason: String },
 }
 
 #[derive(Clone, Debug)]
@@ -57,8 +483,27 @@ pub enum UiEvent {
     TaskSetStart { set_id: String, title: String },
     TaskStart { set_id: String, task_id: String, model_label: String },
     TaskProgress { set_id: String, task_id: String, line: String },
-    TaskEnd { set_id: String, task_id: String, ok: bool },
-    TaskSetEnd { set_id: String, ok: bool },
+    TaskStatus { set_id: String, task_id: String, status: TaskStatus },
+    TaskEnd { set_id: String, task_id: String, ok: bool, cancelled: bool },
+    TaskSetEnd { set_id: String, ok: bool, cancelled: bool },
+    /// What became of a task's worktree branch in an isolated set, conflicts included.
+    TaskMerge { set_id: String, task_id: String, result: MergeResult },
+}
+
+/// What to do with the next set once the previous one finished.
+#[derive(Clone, Debug)]
+pub enum ConfirmDecision {
+    Continue,
+    Abort,
+    /// Run this set instead of the planned one (validated before it starts).
+    Replace(TaskSetSpec),
+}
+
+/// Host-side confirmation between sets (e.g. a TUI prompt). Not consulted after the last set.
+#[async_trait]
+pub trait SetConfirm: Send + Sync {
+    /// `summary` is the `TaskStatus` model's reply for `finished`, including its proposed refinements.
+    async fn confirm(&self, finished: &TaskSetSpec, summary: &str, next: &TaskSetSpec) -> Result<ConfirmDecision>;
 }
 
 pub struct TaskSetRunner<'a> {
@@ -68,93 +513,1686 @@ pub struct TaskSetRunner<'a> {
     pub plan: &'a TaskSetPlan,
 
     pub ui_tx: mpsc::UnboundedSender<UiEvent>,
+    /// Cancel to stop scheduling new tasks and abort running ones. In-flight bridge futures are
+    /// dropped, so `do_exec` should hold its child in a [`crate::exec::Terminating`], which sends
+    /// it `SIGTERM` and kills it after a grace period.
+    pub cancel: CancellationToken,
+    /// Optional session log; step retries are recorded here and the run's report is written next to it.
+    pub log: Option<SessionLogWriter>,
+    /// Asked before each set after the first; `None` always continues.
+    pub confirm: Option<Arc<dyn SetConfirm>>,
+    /// Progress records for crash recovery; tasks it marks as succeeded are not run again.
+    pub checkpoint: Option<Arc<CheckpointStore>>,
+    /// Asked when the `[shell]` policy wants approval for an exec step; `None` refuses such steps.
+    pub approve: Option<Arc<dyn ExecApproval>>,
 
     // bridges into your runtime (supply at call-site):
-    pub do_chat: Arc<dyn Fn(&str, &str, &str) -> TaskFut<()> + Send + Sync>, // (model_name, base_url, prompt)
-    pub do_exec: Arc<dyn Fn(&str, &[String]) -> TaskFut<(i32, String)> + Send + Sync>,
-    pub do_mcp:  Arc<dyn Fn(&str,&str,&serde_json::Value) -> TaskFut<serde_json::Value> + Send + Sync>,
+    pub do_chat: ChatFn, // (model_name, base_url, prompt) -> reply
+    pub do_exec: ExecFn,
+    pub do_mcp:  McpFn,
 }
-type TaskFut<T> = std::pin::Pin<Box<dyn std::future::Future<Output=anyhow::Result<T>> + Send>>;
+pub type TaskFut<T> = std::pin::Pin<Box<dyn std::future::Future<Output=anyhow::Result<T>> + Send>>;
+pub type ChatFn = Arc<dyn Fn(&str, &str, &str) -> TaskFut<String> + Send + Sync>;
+/// Runs one exec step; [`crate::exec::tokio_exec`] is the default implementation.
+pub type ExecFn = Arc<dyn Fn(ExecRequest) -> TaskFut<ExecOutput> + Send + Sync>;
+pub type McpFn = Arc<dyn Fn(&str, &str, &serde_json::Value) -> TaskFut<serde_json::Value> + Send + Sync>;
 
 impl<'a> TaskSetRunner<'a> {
-    pub async fn run(&self) -> Result<()> {
-        for (i, set) in self.plan.sets.iter().enumerate() {
+    /// Run the plan and report what every task did. The report is also written to
+    /// `taskset-report.json` in the session log directory when `log` is set.
+    pub async fn run(&self) -> Result<TaskSetReport> {
+        // Reject broken dependency graphs and unknown agents before anything is started.
+        let profiles = self.cfg.get().models.profiles;
+        self.plan.validate_with(Some(&profiles))?;
+        let started = Instant::now();
+        let mut report = TaskSetReport { session_id: self.plan.session_id.clone(), ok: true, ..Default::default() };
+        let mut sets = self.plan.sets.clone();
+        if let Some(cp) = &self.checkpoint {
+            for (i, set) in cp.replaced_sets() {
+                if let Some(slot) = sets.get_mut(i) { *slot = set; }
+            }
+        }
+        let mut i = 0;
+        while i < sets.len() {
+            let set = &sets[i].expand_matrix(&self.ctx.cwd)?;
+            set.validate_with(Some(&profiles))?;
+            if self.checkpoint.as_ref().is_some_and(|cp| cp.set_succeeded(&set.set_id)) {
+                i += 1;
+                continue;
+            }
             let _ = self.ui_tx.send(UiEvent::TaskSetStart { set_id: set.set_id.clone(), title: set.title.clone() });
-            let ok = match set.mode.as_str() {
+            let set_started = Instant::now();
+            let SetRun { ok, abort_plan, tasks } = match set.mode.as_str() {
                 "parallel" => self.run_parallel(set).await?,
                 _ => self.run_sequential(set).await?,
             };
-            let _ = self.ui_tx.send(UiEvent::TaskSetEnd { set_id: set.set_id.clone(), ok });
+            let cancelled = self.cancel.is_cancelled();
+            let _ = self.ui_tx.send(UiEvent::TaskSetEnd { set_id: set.set_id.clone(), ok, cancelled });
+            let mut set_report = SetReport {
+                set_id: set.set_id.clone(), title: set.title.clone(), ok, cancelled,
+                duration_ms: millis(set_started), tasks, summary: None, error: None, checkpoint_error: None,
+            };
+            report.ok &= ok;
+            if cancelled {
+                report.sets.push(set_report);
+                break;
+            }
+            if let Some(cp) = &self.checkpoint && let Err(e) = cp.record_set_end(&set.set_id, ok).await {
+                set_report.checkpoint_error = Some(format!("{:#}", e));
+            }
 
             // After a set completes, **notify main model** (summarize outcomes), then confirm before next set.
             let main = self.cfg.pick_model(ModelRole::TaskStatus);
-            let summary_prompt = format!("Task set '{}' finished. Summarize status of each task and propose refinements for the next set.", set.title);
-            (self.do_chat)(&main.name, main.base_url.as_deref().unwrap_or_default(), &summary_prompt).await?;
+            let summary_prompt = format!(
+                "Task set '{}' finished. Summarize status of each task and propose refinements for the next set.\n\nReport:\n{}",
+                set.title, serde_json::to_string_pretty(&set_report)?,
+            );
+            // The set already ran: a failed summary is reported, not fatal, and confirm sees none.
+            let summary = tokio::select! {
+                biased;
+                _ = self.cancel.cancelled() => None,
+                res = (self.do_chat)(&main.name, main.base_url.as_deref().unwrap_or_default(), &summary_prompt) => Some(res),
+            };
+            let Some(summary) = summary else {
+                report.sets.push(set_report);
+                break;
+            };
+            let summary = match summary {
+                Ok(summary) => {
+                    set_report.summary = Some(summary.clone());
+                    summary
+                }
+                Err(e) => {
+                    set_report.error = Some(format!("summary failed: {:#}", e));
+                    String::new()
+                }
+            };
+            report.sets.push(set_report);
+            if abort_plan {
+                report.ok = false;
+                break;
+            }
 
-            if i + 1 < self.plan.sets.len() {
-                // Ask user (through your TUI) to confirm/refine next set. You can block here with an oneshot.
-                // For simplicity, we simulate a continue; wire to your actual UI confirmation flow.
+            if i + 1 < sets.len() && let Some(confirm) = &self.confirm {
+                let decision = tokio::select! {
+                    biased;
+                    _ = self.cancel.cancelled() => None,
+                    decision = confirm.confirm(set, &summary, &sets[i + 1]) => Some(decision?),
+                };
+                let Some(decision) = decision else { break };
+                match decision {
+                    ConfirmDecision::Continue => {}
+                    ConfirmDecision::Abort => {
+                        report.ok = false;
+                        break;
+                    }
+                    ConfirmDecision::Replace(next) => {
+                        next.validate_with(Some(&profiles))?;
+                        if let Some(cp) = &self.checkpoint { cp.record_replaced_set(i + 1, &next).await?; }
+                        sets[i + 1] = next;
+                    }
+                }
             }
+            i += 1;
         }
-        Ok(())
+        // Async hooks (e.g. on the last `task_end`) finish before the run does.
+        self.hooks.drain().await;
+        report.cancelled = self.cancel.is_cancelled();
+        report.ok &= !report.cancelled;
+        report.duration_ms = millis(started);
+        if let Some(log) = &self.log { log.write_artifact("taskset-report.json", &report).ok(); }
+        Ok(report)
     }
 
-    async fn run_sequential(&self, set: &TaskSetSpec) -> Result<bool> {
-        let mut all_ok = true;
-        for t in &set.tasks {
-            let ok = self.run_one(set, t).await?;
-            all_ok &= ok;
-        }
-        Ok(all_ok)
+    /// Continue the plan recorded in `self.checkpoint`: finished sets and succeeded tasks are
+    /// skipped, everything else runs again. Fails if the plan changed since the checkpoint was written.
+    pub async fn resume(&self) -> Result<TaskSetReport> {
+        let cp = self.checkpoint.as_ref().context("resume needs a checkpoint")?;
+        cp.ensure_plan_matches(self.plan)?;
+        self.run().await
     }
 
-    async fn run_parallel(&self, set: &TaskSetSpec) -> Result<bool> {
-        let mut futs = vec![];
-        for t in &set.tasks {
-            futs.push(self.run_one(set, t));
+    async fn run_sequential(&self, set: &TaskSetSpec) -> Result<SetRun> {
+        self.run_graph(set, 1).await
+    }
+
+    async fn run_parallel(&self, set: &TaskSetSpec) -> Result<SetRun> {
+        let limit = [set.max_parallel, self.cfg.get().tasks.max_parallel].into_iter().flatten().min();
+        self.run_graph(set, limit.unwrap_or(usize::MAX).max(1)).await
+    }
+
+    /// Schedule the set as a DAG: a task starts once all of its `depends_on` tasks succeeded,
+    /// and is skipped as soon as one of them failed or was skipped. At most `limit` tasks run at once,
+    /// and no more than `max_concurrency` of them per model target. Ready tasks held back by a limit
+    /// are reported as `Pending`. A failed task with `on_error` set to abort cancels the rest of the set.
+    /// In isolated sets every started task gets a worktree, finished in declaration order at the end;
+    /// a worktree that can't be created or finished fails its task, not the set run.
+    async fn run_graph(&self, set: &TaskSetSpec, limit: usize) -> Result<SetRun> {
+        let order = set.schedule_order()?;
+        let index: BTreeMap<&str, usize> = set.tasks.iter().enumerate().map(|(i, t)| (t.id.as_str(), i)).collect();
+        let models: Vec<ModelTarget> = set.tasks.iter().map(|t| self.task_model(t)).collect();
+        let buckets: Vec<String> = models.iter().map(Self::model_bucket).collect();
+        let mut per_model: BTreeMap<String, usize> = BTreeMap::new();
+        let mut outcome: Vec<Option<bool>> = vec![None; set.tasks.len()];
+        let mut started = vec![false; set.tasks.len()];
+        let mut pending = vec![false; set.tasks.len()];
+        // Skipped by a false `when`, directly or through a dependency; does not fail the set.
+        let mut unneeded = vec![false; set.tasks.len()];
+        let mut reports: Vec<Option<TaskReport>> = vec![None; set.tasks.len()];
+        let mut worktrees: Vec<Option<TaskWorktree>> = set.tasks.iter().map(|_| None).collect();
+        let abort = self.cancel.child_token();
+        let mut aborted_by: Option<(&str, OnError)> = None;
+        if let Some(cp) = &self.checkpoint {
+            for (i, t) in set.tasks.iter().enumerate().filter(|(_, t)| cp.task_succeeded(&set.set_id, &t.id)) {
+                started[i] = true;
+                outcome[i] = Some(true);
+                reports[i] = Some(TaskReport { from_checkpoint: true, ..TaskReport::new(&t.id, &t.name, &models[i].name, TaskOutcome::Succeeded) });
+                self.send_status(set, &t.id, TaskStatus::Done { ok: true });
+            }
+        }
+        for t in set.tasks.iter().enumerate().filter(|(i, t)| !started[*i] && !t.depends_on.is_empty()).map(|(_, t)| t) {
+            self.send_status(set, &t.id, TaskStatus::Blocked { waiting_on: t.depends_on.clone() });
+        }
+
+        let mut running = FuturesUnordered::new();
+        loop {
+            for &i in &order {
+                if started[i] { continue; }
+                let t = &set.tasks[i];
+                if abort.is_cancelled() {
+                    started[i] = true;
+                    outcome[i] = Some(false);
+                    let reason = match aborted_by {
+                        Some((by, _)) if !self.cancel.is_cancelled() => format!("set aborted after task '{}' failed", by),
+                        _ => "cancelled".into(),
+                    };
+                    reports[i] = Some(TaskReport::new(&t.id, &t.name, &models[i].name, TaskOutcome::Skipped { reason: reason.clone() }));
+                    self.send_status(set, &t.id, TaskStatus::Skipped { reason });
+                    continue;
+                }
+                let failed_dep = t.depends_on.iter().find(|d| outcome[index[d.as_str()]] == Some(false) && !unneeded[index[d.as_str()]]);
+                let skipped_dep = t.depends_on.iter().find(|d| unneeded[index[d.as_str()]]);
+                if let Some(reason) = failed_dep.map(|d| format!("dependency '{}' did not succeed", d))
+                    .or_else(|| skipped_dep.map(|d| format!("dependency '{}' was skipped", d))) {
+                    started[i] = true;
+                    outcome[i] = Some(false);
+                    unneeded[i] = failed_dep.is_none();
+                    reports[i] = Some(TaskReport::new(&t.id, &t.name, &models[i].name, TaskOutcome::Skipped { reason: reason.clone() }));
+                    self.send_status(set, &t.id, TaskStatus::Skipped { reason });
+                    continue;
+                }
+                if !t.depends_on.iter().all(|d| outcome[index[d.as_str()]] == Some(true)) { continue; }
+                match when_holds(t.when.as_deref(), &self.ctx, &BTreeMap::new(), false) {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        started[i] = true;
+                        outcome[i] = Some(false);
+                        unneeded[i] = true;
+                        let reason = format!("when `{}` is false", t.when.as_deref().unwrap_or_default());
+                        reports[i] = Some(TaskReport::new(&t.id, &t.name, &models[i].name, TaskOutcome::Skipped { reason: reason.clone() }));
+                        self.send_status(set, &t.id, TaskStatus::Skipped { reason });
+                        continue;
+                    }
+                    Err(e) => {
+                        started[i] = true;
+                        outcome[i] = Some(false);
+                        reports[i] = Some(TaskReport { error: Some(format!("when: {:#}", e)), ..TaskReport::new(&t.id, &t.name, &models[i].name, TaskOutcome::Failed) });
+                        self.send_status(set, &t.id, TaskStatus::Done { ok: false });
+                        continue;
+                    }
+                }
+                let key = &buckets[i];
+                let model_full = models[i].max_concurrency.is_some_and(|max| per_model.get(key).copied().unwrap_or(0) >= max.max(1));
+                if running.len() >= limit || model_full {
+                    if !pending[i] {
+                        pending[i] = true;
+                        self.send_status(set, &t.id, TaskStatus::Pending);
+                    }
+                    continue;
+                }
+                started[i] = true;
+                let mut ctx = self.ctx.clone();
+                if set.worktrees.is_some() {
+                    let (dir, set_id, task_id) = (self.ctx.cwd.clone(), set.set_id.clone(), t.id.clone());
+                    let created = tokio::task::spawn_blocking(move || TaskWorktree::create(&dir, &set_id, &task_id)).await;
+                    match created.unwrap_or_else(|e| Err(e.into())) {
+                        Ok(wt) => {
+                            ctx.cwd = wt.cwd.clone();
+                            worktrees[i] = Some(wt);
+                        }
+                        Err(e) => {
+                            outcome[i] = Some(false);
+                            reports[i] = Some(TaskReport { error: Some(format!("worktree: {:#}", e)), ..TaskReport::new(&t.id, &t.name, &models[i].name, TaskOutcome::Failed) });
+                            self.send_status(set, &t.id, TaskStatus::Done { ok: false });
+                            continue;
+                        }
+                    }
+                }
+                *per_model.entry(key.clone()).or_default() += 1;
+                let (model, abort) = (&models[i], &abort);
+                let run = TaskRun { set, t, model, ctx, agent: None };
+                running.push(async move { (i, self.run_one(run, abort).await) });
+            }
+            // Running tasks observe cancellation themselves, so this returns promptly after `cancel()`.
+            match running.next().await {
+                Some((i, report)) => {
+                    let ok = report.succeeded();
+                    outcome[i] = Some(ok);
+                    reports[i] = Some(report);
+                    if let Some(n) = per_model.get_mut(&buckets[i]) { *n -= 1; }
+                    let t = &set.tasks[i];
+                    if !ok && t.on_error != OnError::Continue && aborted_by.is_none() {
+                        aborted_by = Some((t.id.as_str(), t.on_error));
+                        abort.cancel();
+                    }
+                }
+                None => break,
+            }
         }
-        let results = join_all(futs).await;
-        Ok(results.into_iter().all(|r| r.unwrap_or(false)))
+        // Every worktree is finished, even after one fails; a task whose worktree can't be
+        // finished (its work committed, merged or kept) fails.
+        if let Some(mode) = set.worktrees {
+            for (i, wt) in worktrees.into_iter().enumerate() {
+                let Some(wt) = wt else { continue };
+                let merge = mode == WorktreeMode::Merge && outcome[i] == Some(true) && !self.cancel.is_cancelled();
+                let finished = tokio::task::spawn_blocking(move || wt.finish(merge)).await;
+                match finished.unwrap_or_else(|e| Err(e.into())) {
+                    Ok(result) => {
+                        let _ = self.ui_tx.send(UiEvent::TaskMerge { set_id: set.set_id.clone(), task_id: set.tasks[i].id.clone(), result: result.clone() });
+                        if let Some(r) = &mut reports[i] { r.worktree = Some(result); }
+                    }
+                    Err(e) => {
+                        outcome[i] = Some(false);
+                        if let Some(r) = &mut reports[i] {
+                            r.outcome = TaskOutcome::Failed;
+                            r.error = Some(format!("worktree: {:#}", e));
+                        }
+                        self.send_status(set, &set.tasks[i].id, TaskStatus::Done { ok: false });
+                    }
+                }
+            }
+        }
+        Ok(SetRun {
+            ok: outcome.iter().zip(&unneeded).all(|(o, skipped)| *o == Some(true) || *skipped),
+            abort_plan: aborted_by.is_some_and(|(_, on_error)| on_error == OnError::AbortPlan),
+            tasks: reports.into_iter().flatten().collect(),
+        })
+    }
+
+    /// Model a task runs against: its `model_profile` if configured, else the default chat model.
+    pub(crate) fn task_model(&self, t: &TaskSpec) -> ModelTarget {
+        t.model_profile.as_deref()
+            .and_then(|p| self.cfg.get().models.profiles.get(p).cloned())
+            .unwrap_or_else(|| self.cfg.pick_model(ModelRole::Chat))
+    }
+
+    /// Concurrency bucket for a task: the model target it resolves to (name and base URL), so
+    /// profiles pointing at the same target share its `max_concurrency`.
+    fn model_bucket(target: &ModelTarget) -> String {
+        format!("{}@{}", target.name, target.base_url.as_deref().unwrap_or_default())
+    }
+
+    fn send_status(&self, set: &TaskSetSpec, task_id: &str, status: TaskStatus) {
+        let _ = self.ui_tx.send(UiEvent::TaskStatus { set_id: set.set_id.clone(), task_id: task_id.into(), status });
     }
 
-    async fn run_one(&self, set: &TaskSetSpec, t: &TaskSpec) -> Result<bool> {
-        // choose label/model
-        let model = if let Some(p) = t.model_profile.as_deref() {
-            self.cfg.get().models.profiles.get(p).cloned().unwrap_or(self.cfg.pick_model(ModelRole::Chat))
-        } else { self.cfg.pick_model(ModelRole::Chat) };
+    /// Run a single task; bridge and checkpoint errors count as a failed task rather than aborting
+    /// the set. A cancelled task ends as failed, so hooks see `TaskEnd { success: false }`.
+    /// `abort` is the set's token: a child of `self.cancel` that an aborting task also cancels.
+    async fn run_one(&self, run: TaskRun<'_>, abort: &CancellationToken) -> TaskReport {
+        let TaskRun { set, t, model, ref ctx, .. } = run;
         let label = t.model_profile.clone().unwrap_or_else(|| "default".into());
         let _ = self.ui_tx.send(UiEvent::TaskStart { set_id: set.set_id.clone(), task_id: t.id.clone(), model_label: label.clone() });
-        self.hooks.emit(&self.ctx, &HookEvent::TaskStart { task_name: t.name.clone() }).await.ok();
+        self.hooks.emit(ctx, &HookEvent::TaskStart { task_name: t.name.clone() }).await.ok();
+        let started = Instant::now();
+        let mut report = TaskReport::new(&t.id, &t.name, &model.name, TaskOutcome::Failed);
+        let recorded = match &self.checkpoint {
+            Some(cp) => cp.record_task_start(&set.set_id, &t.id).await,
+            None => Ok(()),
+        };
+        let (mut ok, cancelled) = match recorded {
+            Err(e) => {
+                self.task_error(set, t, &mut report, e.context("checkpoint"));
+                (false, false)
+            }
+            Ok(()) => tokio::select! {
+                biased;
+                _ = abort.cancelled() => (false, true),
+                res = self.run_steps(&run, BTreeMap::new(), &mut report) => match res {
+                    Ok((ok, _)) => (ok, false),
+                    Err(e) => {
+                        self.task_error(set, t, &mut report, e);
+                        (false, false)
+                    }
+                },
+            },
+        };
+        if let Some(cp) = &self.checkpoint {
+            let state = if cancelled { RunState::Cancelled } else if ok { RunState::Succeeded } else { RunState::Failed };
+            // Without its end record a task counts as unfinished, so resume would run it again.
+            if let Err(e) = cp.record_task_end(&set.set_id, &t.id, state).await {
+                self.task_error(set, t, &mut report, e.context("checkpoint"));
+                ok = false;
+            }
+        }
+        report.outcome = if cancelled { TaskOutcome::Cancelled } else if ok { TaskOutcome::Succeeded } else { TaskOutcome::Failed };
+        report.duration_ms = millis(started);
+        report.hook_denials = denials(&report.steps, "");
+        self.hooks.emit(ctx, &HookEvent::TaskEnd { task_name: t.name.clone(), success: ok }).await.ok();
+        let _ = self.ui_tx.send(UiEvent::TaskEnd { set_id: set.set_id.clone(), task_id: t.id.clone(), ok, cancelled });
+        report
+    }
+
+    /// Note the error that ended a task in its report and progress lines.
+    fn task_error(&self, set: &TaskSetSpec, t: &TaskSpec, report: &mut TaskReport, e: anyhow::Error) {
+        let _ = self.ui_tx.send(UiEvent::TaskProgress { set_id: set.set_id.clone(), task_id: t.id.clone(), line: format!("error: {:#}", e) });
+        report.error = Some(format!("{:#}", e));
+    }
 
+    /// Run the task's steps, then apply its `success_criteria`. With `on_error` other than
+    /// `continue`, the first failing step ends the task. Step results are added to `report` as they
+    /// finish; `outputs` holds what earlier steps produced. Returns whether the task succeeded and
+    /// the outputs of the steps that ran.
+    async fn run_steps(&self, run: &TaskRun<'_>, mut outputs: BTreeMap<String, StepOutput>, report: &mut TaskReport) -> Result<(bool, Vec<StepOutput>)> {
+        let &TaskRun { set, t, model, ref ctx, agent } = run;
         let mut ok = true;
-        for step in &t.steps {
-            match step {
-                TaskStep::Chat { prompt, model_profile } => {
-                    let chosen = if let Some(p) = model_profile {
-                        self.cfg.get().models.profiles.get(p).cloned().unwrap_or(model.clone())
-                    } else { model.clone() };
-                    (self.do_chat)(&chosen.name, chosen.base_url.as_deref().unwrap_or_default(), prompt).await?;
-                    let _ = self.ui_tx.send(UiEvent::TaskProgress { set_id: set.set_id.clone(), task_id: t.id.clone(), line: "chat sent".into() });
-                }
-                TaskStep::Exec { cmd, args } => {
-                    let (status, out_preview) = (self.do_exec)(cmd, args).await?;
-                    let _ = self.ui_tx.send(UiEvent::TaskProgress { set_id: set.set_id.clone(), task_id: t.id.clone(), line: format!("exec {} -> {}", cmd, status) });
-                    self.hooks.emit(&self.ctx, &HookEvent::PostExec{ cmd: cmd.clone(), argv: args.clone(), status, stdout_len: out_preview.len(), stderr_len: 0 }).await.ok();
-                    if status != 0 { ok = false; }
-                }
-                TaskStep::McpCall { server, method, payload } => {
-                    let _resp = (self.do_mcp)(server, method, payload).await?;
-                    let _ = self.ui_tx.send(UiEvent::TaskProgress { set_id: set.set_id.clone(), task_id: t.id.clone(), line: format!("mcp {}.{}", server, method) });
-                }
-                TaskStep::Git { action: _a, args } => {
-                    let (status, _) = (self.do_exec)("git", args).await?;
-                    if status != 0 { ok = false; }
+        let mut all = Vec::with_capacity(t.steps.len());
+        for (idx, spec) in t.steps.iter().enumerate() {
+            // After a failure that ends the task, only steps with a `when` (e.g. `failure()`) still run.
+            let stopped = !ok && t.on_error != OnError::Continue;
+            if stopped && spec.when.is_none() { continue; }
+            if !when_holds(spec.when.as_deref(), ctx, &outputs, !ok)? {
+                let line = format!("step {} skipped: when `{}` is false", idx, spec.when.as_deref().unwrap_or_default());
+                let _ = self.ui_tx.send(UiEvent::TaskProgress { set_id: set.set_id.clone(), task_id: t.id.clone(), line });
+                report.steps.push(StepReport { skipped: true, ..StepReport::new(idx, spec.id.clone(), spec.step.kind()) });
+                continue;
+            }
+            let status_line = status_line(t, model, idx)?;
+            self.send_status(set, &t.id, TaskStatus::Running { status_line: status_line.clone() });
+            self.hooks.emit(ctx, &HookEvent::TaskProgress { task_name: t.name.clone(), status_line }).await.ok();
+            let step = match &spec.step {
+                // Nested steps are rendered as they run, against the sub-agent's own outputs.
+                TaskStep::SubAgent { .. } => spec.step.clone(),
+                step => step.try_map_strings(&mut |s| template::render(s, "steps", |path| {
+                    let (id, field) = path.split_once('.')?;
+                    outputs.get(id)?.field(field)
+                }))?,
+            };
+            let mut step_report = StepReport::new(idx, spec.id.clone(), spec.step.kind());
+            let started = Instant::now();
+            let res = self.run_step(run, spec, &step, &outputs, &mut step_report).await;
+            step_report.duration_ms = millis(started);
+            if let Ok(out) = &res { step_report.fill_from(out); }
+            report.steps.push(step_report);
+            let out = res?;
+            if let Some(cp) = &self.checkpoint && agent.is_none() { cp.record_step(&set.set_id, &t.id, idx, spec.id.as_deref(), &out).await.context("checkpoint")?; }
+            ok &= out.ok;
+            all.push(out.clone());
+            if let Some(id) = &spec.id { outputs.insert(id.clone(), out); }
+        }
+        if ok && agent.is_none() && let Some(criteria) = &t.success_criteria && let Err(e) = criteria.check(&all) {
+            let line = format!("success criteria not met: {:#}", e);
+            let _ = self.ui_tx.send(UiEvent::TaskProgress { set_id: set.set_id.clone(), task_id: t.id.clone(), line: line.clone() });
+            report.error = Some(line);
+            return Ok((false, all));
+        }
+        Ok((ok, all))
+    }
+
+    /// Run a step under its timeout/retry policy. A failed attempt (non-zero exit, bridge error or
+    /// timeout) is retried after the backoff delay; the last attempt's result is returned.
+    /// A step refused by the shell policy or a pre-event hook fails without being attempted or
+    /// retried; a step whose pre-event hooks rewrite runs in its rewritten form, and rewritten exec
+    /// steps are checked against the shell policy again. Attempts and denials are noted in
+    /// `report`, whose `index` names the step.
+    /// Sub-agent steps are run once; their nested steps carry their own policies.
+    async fn run_step(&self, run: &TaskRun<'_>, spec: &StepSpec, step: &TaskStep, outputs: &BTreeMap<String, StepOutput>, report: &mut StepReport) -> Result<StepOutput> {
+        let &TaskRun { set, t, .. } = run;
+        let idx = report.index;
+        if self.shell_refused(run, step, report).await? { return Ok(StepOutput::default()); }
+        let rewritten;
+        let decision = self.emit_step_event(run, &step.pre_event()).await
+            // A hook that can't be consulted doesn't get to wave the step through.
+            .unwrap_or_else(|e| HookDecision::Deny { reason: format!("hook error: {:#}", e) });
+        let step = match decision {
+            HookDecision::Deny { reason } => {
+                let _ = self.ui_tx.send(UiEvent::TaskProgress { set_id: set.set_id.clone(), task_id: t.id.clone(), line: format!("step {} denied by hook: {}", idx, reason) });
+                report.denied = Some(reason);
+                return Ok(StepOutput::default());
+            }
+            HookDecision::Modify { event, rules, .. } => {
+                let before = step.pre_event();
+                rewritten = step.with_pre_event(&event).with_context(|| format!("step {} as rewritten by hook {}", idx, rules.join(", ")))?;
+                let _ = self.ui_tx.send(UiEvent::TaskProgress { set_id: set.set_id.clone(), task_id: t.id.clone(), line: format!("step {} rewritten by hook: {}", idx, rules.join(", ")) });
+                if let Some(log) = &self.log {
+                    let changes = crate::hooks::diff(&before, &event);
+                    log.append(&SessionEvent::HookModify { set_id: set.set_id.clone(), task_id: t.id.clone(), step: idx, rules, changes }).ok();
                 }
+                if self.shell_refused(run, &rewritten, report).await? { return Ok(StepOutput::default()); }
+                &rewritten
+            }
+            _ => step,
+        };
+        if let TaskStep::SubAgent { agent, steps } = step {
+            report.attempts = 1;
+            return self.run_sub_agent(run, agent, steps, outputs, report).await;
+        }
+        let policy = spec.policy.or(&t.step_defaults);
+        let retries = policy.retries.unwrap_or(0);
+        let mut attempt = 0;
+        loop {
+            report.attempts = attempt + 1;
+            let once = self.run_step_once(run, step);
+            let res = match policy.timeout_secs {
+                Some(secs) => tokio::time::timeout(Duration::from_secs(secs), once).await
+                    .unwrap_or_else(|_| Err(anyhow::anyhow!("step {} timed out after {}s", idx, secs))),
+                None => once.await,
+            };
+            let reason = match &res {
+                Ok(out) if out.ok => return res,
+                Ok(_) => "step failed".to_string(),
+                Err(e) => format!("{:#}", e),
+            };
+            if attempt >= retries { return res; }
+            attempt += 1;
+            let _ = self.ui_tx.send(UiEvent::TaskProgress { set_id: set.set_id.clone(), task_id: t.id.clone(), line: format!("retry {}/{} of step {}: {}", attempt, retries, idx, reason) });
+            if let Some(log) = &self.log {
+                log.append(&SessionEvent::StepRetry { set_id: set.set_id.clone(), task_id: t.id.clone(), step: idx, attempt, reason }).ok();
             }
+            tokio::time::sleep(policy.backoff.clone().unwrap_or_default().delay(attempt)).await;
         }
+    }
+
+    /// Emit a step's hook event; messages the hooks reply with become progress lines of the task.
+    async fn emit_step_event(&self, run: &TaskRun<'_>, event: &HookEvent) -> Result<HookDecision> {
+        let res = self.hooks.emit(&run.ctx, event).await;
+        if let Ok(HookDecision::Message { text } | HookDecision::Modify { text: Some(text), .. }) = &res {
+            for line in text.lines() {
+                let _ = self.ui_tx.send(UiEvent::TaskProgress { set_id: run.set.set_id.clone(), task_id: run.t.id.clone(), line: format!("hook: {}", line) });
+            }
+        }
+        res
+    }
+
+    /// Whether `step` is an exec step the `[shell]` policy refuses; the refusal is reported.
+    async fn shell_refused(&self, run: &TaskRun<'_>, step: &TaskStep, report: &mut StepReport) -> Result<bool> {
+        let TaskStep::Exec { cmd, args, .. } = step else { return Ok(false) };
+        let Some(reason) = self.shell_refusal(cmd, args).await? else { return Ok(false) };
+        let _ = self.ui_tx.send(UiEvent::TaskProgress { set_id: run.set.set_id.clone(), task_id: run.t.id.clone(), line: format!("step {} blocked by shell policy: {}", report.index, reason) });
+        report.denied = Some(format!("shell policy: {}", reason));
+        Ok(true)
+    }
+
+    /// Why the `[shell]` policy refuses `cmd args...`, asking `self.approve` when it needs approval.
+    async fn shell_refusal(&self, cmd: &str, args: &[String]) -> Result<Option<String>> {
+        let check = ShellPolicy::new(&self.cfg.get().shell).check(cmd, args);
+        Ok(match check.decision {
+            Decision::Allow => None,
+            Decision::Deny => Some(check.reason()),
+            Decision::Ask => match &self.approve {
+                Some(approve) if approve.approve(cmd, args, &check).await? => None,
+                Some(_) => Some(format!("not approved: {}", check.reason())),
+                None => Some(check.reason()),
+            },
+        })
+    }
+
+    /// Run `steps` under the `agent` model profile, which validation made sure exists.
+    /// Hooks see the sub-agent as a task named `<task>::<agent>`; its step reports are nested in `report`.
+    async fn run_sub_agent(&self, run: &TaskRun<'_>, agent: &str, steps: &[StepSpec], outputs: &BTreeMap<String, StepOutput>, report: &mut StepReport) -> Result<StepOutput> {
+        let &TaskRun { set, t, ref ctx, .. } = run;
+        let model = self.cfg.get().models.profiles.get(agent).cloned().with_context(|| format!("unknown agent profile '{}'", agent))?;
+        let nested = TaskSpec {
+            name: format!("{}::{}", t.name, agent),
+            model_profile: Some(agent.into()),
+            when: None,
+            depends_on: vec![],
+            matrix: BTreeMap::new(),
+            steps: steps.to_vec(),
+            ..t.clone()
+        };
+        let _ = self.ui_tx.send(UiEvent::TaskProgress { set_id: set.set_id.clone(), task_id: t.id.clone(), line: format!("sub-agent {} ({})", agent, model.name) });
+        self.hooks.emit(ctx, &HookEvent::TaskStart { task_name: nested.name.clone() }).await.ok();
+        let mut inner = TaskReport::new(&t.id, &nested.name, &model.name, TaskOutcome::Failed);
+        let sub = TaskRun { set, t: &nested, model: &model, ctx: ctx.clone(), agent: Some(agent) };
+        let res = Box::pin(self.run_steps(&sub, outputs.clone(), &mut inner)).await;
+        report.nested = inner.steps;
+        let ok = res.as_ref().is_ok_and(|(ok, _)| *ok);
+        self.hooks.emit(ctx, &HookEvent::TaskEnd { task_name: nested.name.clone(), success: ok }).await.ok();
+        let (_, all) = res?;
+        let reply = all.iter().rev().find(|o| !o.reply.is_empty()).map(|o| o.reply.clone()).unwrap_or_default();
+        Ok(StepOutput { ok, reply, ..Default::default() })
+    }
 
-        self.hooks.emit(&self.ctx, &HookEvent::TaskEnd { task_name: t.name.clone(), success: ok }).await.ok();
-        let _ = self.ui_tx.send(UiEvent::TaskEnd { set_id: set.set_id.clone(), task_id: t.id.clone(), ok });
-        Ok(ok)
+    async fn run_step_once(&self, run: &TaskRun<'_>, step: &TaskStep) -> Result<StepOutput> {
+        let &TaskRun { set, t, model, ref ctx, .. } = run;
+        let exit_ok = |code: i32| t.success_criteria.as_ref().map_or(code == 0, |c| c.accepts_exit(code));
+        match step {
+            TaskStep::Chat { prompt, model_profile } => {
+                let chosen = if let Some(p) = model_profile {
+                    self.cfg.get().models.profiles.get(p).cloned().unwrap_or(model.clone())
+                } else { model.clone() };
+                let reply = (self.do_chat)(&chosen.name, chosen.base_url.as_deref().unwrap_or_default(), prompt).await?;
+                let _ = self.ui_tx.send(UiEvent::TaskProgress { set_id: set.set_id.clone(), task_id: t.id.clone(), line: "chat sent".into() });
+                Ok(StepOutput { ok: true, reply, ..Default::default() })
+            }
+            TaskStep::Exec { cmd, args, env } => {
+                let cfg = self.cfg.get();
+                let env = ChildEnv::new(&cfg.shell)?.for_child(&ctx.env, [env]);
+                let lines: LineSink = {
+                    let (ui_tx, set_id, task_id) = (self.ui_tx.clone(), set.set_id.clone(), t.id.clone());
+                    Arc::new(move |stream, line| {
+                        let line = match stream { Stream::Stdout => line.to_string(), Stream::Stderr => format!("stderr: {}", line) };
+                        let _ = ui_tx.send(UiEvent::TaskProgress { set_id: set_id.clone(), task_id: task_id.clone(), line });
+                    })
+                };
+                let sandbox = Sandbox::new(&cfg.sandbox, &ctx.cwd)?;
+                let limits = Limits::new(&cfg.limits, t.limits.as_ref());
+                let out = (self.do_exec)(ExecRequest { cmd: cmd.clone(), args: args.clone(), cwd: ctx.cwd.clone(), env, sandbox, limits, lines }).await?;
+                let (status, limit) = (out.status, out.limit);
+                let line = match limit {
+                    Some(l) => format!("exec {} -> {} ({} limit exceeded)", cmd, status, l),
+                    None => format!("exec {} -> {}", cmd, status),
+                };
+                let _ = self.ui_tx.send(UiEvent::TaskProgress { set_id: set.set_id.clone(), task_id: t.id.clone(), line });
+                self.emit_step_event(run, &HookEvent::PostExec{ cmd: cmd.clone(), argv: args.clone(), status, stdout_len: out.stdout_len, stderr_len: out.stderr_len, limit }).await.ok();
+                Ok(StepOutput { ok: exit_ok(status) && limit.is_none(), exit_code: Some(status), stdout: out.stdout, limit, ..Default::default() })
+            }
+            TaskStep::McpCall { server, method, payload } => {
+                let response = (self.do_mcp)(server, method, payload).await?;
+                let _ = self.ui_tx.send(UiEvent::TaskProgress { set_id: set.set_id.clone(), task_id: t.id.clone(), line: format!("mcp {}.{}", server, method) });
+                Ok(StepOutput { ok: true, response, ..Default::default() })
+            }
+            TaskStep::Git { action } => {
+                let (dir, op) = (ctx.cwd.clone(), action.clone());
+                let res = tokio::task::spawn_blocking(move || git_ops::run(&dir, &op)).await??;
+                let line = match &res.commit { Some(c) => format!("git {} -> {}", action.name(), &c[..c.len().min(7)]), None => format!("git {} -> {} file(s)", action.name(), res.files.len()) };
+                let _ = self.ui_tx.send(UiEvent::TaskProgress { set_id: set.set_id.clone(), task_id: t.id.clone(), line });
+                let result = serde_json::json!({ "files": res.files, "commit": res.commit });
+                self.emit_step_event(run, &git_post_event(action, result)).await.ok();
+                Ok(StepOutput { ok: true, stdout: res.text, files: res.files, commit: res.commit, ..Default::default() })
+            }
+            TaskStep::SubAgent { .. } => unreachable!("sub-agent steps are run by run_sub_agent"),
+        }
+    }
+}
+
+/// A task while it runs: its set, the model it talks to and the hook context its steps use.
+struct TaskRun<'t> {
+    set: &'t TaskSetSpec,
+    t: &'t TaskSpec,
+    model: &'t ModelTarget,
+    /// `self.ctx`, with `cwd` in the task's worktree when the set isolates tasks.
+    ctx: HookContext,
+    /// Profile of the sub-agent running these steps; `None` for the task itself.
+    agent: Option<&'t str>,
+}
+
+/// How a set run ended.
+struct SetRun {
+    ok: bool,
+    /// A task with `on_error = "abort_plan"` failed; no later sets run.
+    abort_plan: bool,
+    tasks: Vec<TaskReport>,
+}
+
+/// Hook event fired after a git step: `PostCommit` for commits, `PostToolUse` otherwise.
+pub(crate) fn git_post_event(action: &GitAction, result: serde_json::Value) -> HookEvent {
+    match action {
+        GitAction::Commit { .. } => HookEvent::Git { kind: GitEvent::PostCommit },
+        _ => HookEvent::PostToolUse { tool: "git".into(), result },
+    }
+}
+
+/// `step <index>: <reason>` for every step a hook refused, including sub-agent steps (`step 2.0: ...`).
+fn denials(steps: &[StepReport], prefix: &str) -> Vec<String> {
+    steps.iter().flat_map(|s| {
+        let at = format!("{}{}", prefix, s.index);
+        let own = s.denied.as_ref().map(|r| format!("step {}: {}", at, r));
+        own.into_iter().chain(denials(&s.nested, &format!("{}.", at)))
+    }).collect()
+}
+
+fn millis(since: Instant) -> u64 {
+    since.elapsed().as_millis().try_into().unwrap_or(u64::MAX)
+}
+
+/// `Running` text before step `idx`: the task's `status_line` template, or `step N/M: kind`.
+fn status_line(t: &TaskSpec, model: &ModelTarget, idx: usize) -> Result<String> {
+    let kind = t.steps[idx].step.kind();
+    let Some(line) = &t.status_line else { return Ok(format!("step {}/{}: {}", idx + 1, t.steps.len(), kind)); };
+    template::render(line, "task", |field| Some(match field {
+        "id" => t.id.clone(),
+        "name" => t.name.clone(),
+        "model" => model.name.clone(),
+        "step" => (idx + 1).to_string(),
+        "steps" => t.steps.len().to_string(),
+        "kind" => kind.to_string(),
+        _ => return None,
+    }))
+}
+
+/// Whether a task or step with this `when` should run; no condition means it always does.
+fn when_holds(when: Option<&str>, ctx: &HookContext, steps: &BTreeMap<String, StepOutput>, failed: bool) -> Result<bool> {
+    let Some(when) = when else { return Ok(true) };
+    Condition::parse(when)?.eval(&WhenScope { steps, failed, env: &ctx.env, cwd: &ctx.cwd })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parking_lot::Mutex;
+    use std::{collections::BTreeMap, path::PathBuf};
+    use tempfile::tempdir;
+
+    fn task(id: &str, depends_on: &[&str], cmd: &str) -> TaskSpec {
+        TaskSpec {
+            id: id.into(),
+            name: id.into(),
+            model_profile: None,
+            status_line: None,
+            success_criteria: None,
+            on_error: OnError::Continue,
+            when: None,
+            depends_on: depends_on.iter().map(|d| d.to_string()).collect(),
+            matrix: BTreeMap::new(),
+            limits: None,
+            step_defaults: StepPolicy::default(),
+            steps: vec![TaskStep::Exec { cmd: cmd.into(), args: vec![], env: BTreeMap::new() }.into()],
+        }
+    }
+
+    fn set(mode: &str, tasks: Vec<TaskSpec>) -> TaskSetSpec {
+        TaskSetSpec { set_id: "s1".into(), title: "set".into(), mode: mode.into(), max_parallel: None, worktrees: None, tasks }
+    }
+
+    struct Outcome { ran: Vec<String>, events: Vec<UiEvent>, peak: usize }
+
+    /// Test bridges: exec records commands and fails any command named "fail"; chat answers
+    /// with a canned summary.
+    struct Harness {
+        _temp: tempfile::TempDir,
+        root: PathBuf,
+        cfg: Arc<ConfigManager>,
+        hooks: Arc<HookRegistry>,
+        ran: Arc<Mutex<Vec<String>>>,
+        /// Model of every chat call, in order.
+        chat_models: Arc<Mutex<Vec<String>>>,
+        active: Arc<Mutex<(usize, usize)>>, // (running now, peak)
+        cancel: CancellationToken,
+        ui_tx: mpsc::UnboundedSender<UiEvent>,
+        ui_rx: mpsc::UnboundedReceiver<UiEvent>,
+    }
+
+    impl Harness {
+        fn new() -> Result<Self> {
+            let temp = tempdir()?;
+            let root = temp.path().to_path_buf();
+            let cfg = Arc::new(ConfigManager::for_paths(root.join("system.toml"), root.join("user.toml"), root.join("workspace.toml"))?);
+            let hooks = Arc::new(HookRegistry::load_from_dirs(cfg.clone(), &[])?);
+            let (ui_tx, ui_rx) = mpsc::unbounded_channel();
+            Ok(Self {
+                _temp: temp, root, cfg, hooks,
+                ran: Arc::new(Mutex::new(vec![])),
+                chat_models: Arc::new(Mutex::new(vec![])),
+                active: Arc::new(Mutex::new((0, 0))),
+                cancel: CancellationToken::new(),
+                ui_tx, ui_rx,
+            })
+        }
+
+        fn runner<'a>(&self, plan: &'a TaskSetPlan) -> TaskSetRunner<'a> {
+            TaskSetRunner {
+                cfg: self.cfg.clone(),
+                hooks: self.hooks.clone(),
+                ctx: HookContext { cwd: self.root.clone(), session_id: "test".into(), env: BTreeMap::new() },
+                plan,
+                ui_tx: self.ui_tx.clone(),
+                cancel: self.cancel.clone(),
+                log: None,
+                confirm: None,
+                checkpoint: None,
+                approve: None,
+                do_chat: Arc::new({
+                    let chat_models = self.chat_models.clone();
+                    move |model, _, prompt| {
+                        chat_models.lock().push(model.to_string());
+                        let reply = format!("summary: {}", prompt);
+                        Box::pin(async move { Ok(reply) })
+                    }
+                }),
+                do_exec: Arc::new({
+                    let (ran, active, cancel) = (self.ran.clone(), self.active.clone(), self.cancel.clone());
+                    move |req: ExecRequest| {
+                        let ExecRequest { cmd, args, cwd, env, limits, lines, .. } = req;
+                        let cmd = cmd.as_str();
+                        ran.lock().push(cmd.to_string());
+                        // "echo" streams each argument as a line, odd ones to stderr.
+                        if cmd == "echo" {
+                            for (i, a) in args.iter().enumerate() { lines(if i % 2 == 0 { Stream::Stdout } else { Stream::Stderr }, a); }
+                        }
+                        // "write <file> <text>" edits the task's working directory.
+                        if cmd == "write" && let Err(e) = std::fs::write(cwd.join(&args[0]), &args[1]) {
+                            return Box::pin(async move { Err(e.into()) });
+                        }
+                        // "printenv" lists the names of the variables the child would get.
+                        let out = if cmd == "printenv" { env.keys().cloned().collect::<Vec<_>>().join(" ") } else { args.join(" ") };
+                        let status = if cmd == "fail" { 1 } else { 0 };
+                        let active = active.clone();
+                        // "hang" simulates a child that never exits and the user pressing Ctrl-C.
+                        if cmd == "hang" {
+                            cancel.cancel();
+                            return Box::pin(futures::future::pending());
+                        }
+                        // "flaky" fails on its first call only; "sleep" outlives any step timeout.
+                        let status = if cmd == "flaky" && ran.lock().len() == 1 { 1 } else { status };
+                        if cmd == "sleep" {
+                            return Box::pin(async { tokio::time::sleep(Duration::from_secs(3600)).await; Ok(ExecOutput::default()) });
+                        }
+                        // "hog" is killed by its memory limit, if it has one; its output lists the limits it got.
+                        if cmd == "hog" {
+                            let limit = limits.memory_bytes.map(|_| crate::limits::LimitKind::Memory);
+                            let out = format!("{:?}", limits);
+                            return Box::pin(async move { Ok(ExecOutput { status: if limit.is_some() { -1 } else { 0 }, stdout: out, limit, ..Default::default() }) });
+                        }
+                        Box::pin(async move {
+                            { let mut a = active.lock(); a.0 += 1; a.1 = a.1.max(a.0); }
+                            for _ in 0..4 { tokio::task::yield_now().await; }
+                            active.lock().0 -= 1;
+                            Ok(ExecOutput { status, stdout_len: out.len(), stdout: out, ..Default::default() })
+                        })
+                    }
+                }),
+                do_mcp: Arc::new(|_, _, _| Box::pin(async { Ok(serde_json::Value::Null) })),
+            }
+        }
+
+        fn outcome(mut self) -> Outcome {
+            let mut events = vec![];
+            while let Ok(ev) = self.ui_rx.try_recv() { events.push(ev); }
+            let ran = self.ran.lock().clone();
+            let peak = self.active.lock().1;
+            Outcome { ran, events, peak }
+        }
+    }
+
+    async fn run_plan(plan: &TaskSetPlan) -> Result<Outcome> {
+        let h = Harness::new()?;
+        h.runner(plan).run().await?;
+        Ok(h.outcome())
+    }
+
+    #[test]
+    fn schedule_order_respects_dependencies() -> Result<()> {
+        let s = set("parallel", vec![task("package", &["lint", "test"], "pkg"), task("lint", &[], "lint"), task("test", &[], "test")]);
+        assert_eq!(s.schedule_order()?, vec![1, 2, 0]);
+        Ok(())
+    }
+
+    #[test]
+    fn validate_rejects_cycles_and_unknown_ids() {
+        let cyclic = set("parallel", vec![task("a", &["b"], "x"), task("b", &["a"], "x")]);
+        assert!(cyclic.validate().unwrap_err().to_string().contains("cycle"));
+        let unknown = set("parallel", vec![task("a", &["missing"], "x")]);
+        assert!(unknown.validate().unwrap_err().to_string().contains("unknown task 'missing'"));
+    }
+
+    #[tokio::test]
+    async fn failed_dependency_skips_dependents() -> Result<()> {
+        let plan = TaskSetPlan {
+            session_id: "test".into(),
+            sets: vec![set("parallel", vec![
+                task("build", &[], "fail"),
+                task("test", &["build"], "test"),
+                task("package", &["test"], "pkg"),
+                task("lint", &[], "lint"),
+            ])],
+        };
+        let Outcome { ran, events, .. } = run_plan(&plan).await?;
+        assert_eq!(ran, vec!["fail".to_string(), "lint".to_string()]);
+        let skipped: Vec<&str> = events.iter().filter_map(|e| match e {
+            UiEvent::TaskStatus { task_id, status: TaskStatus::Skipped { .. }, .. } => Some(task_id.as_str()),
+            _ => None,
+        }).collect();
+        assert_eq!(skipped, vec!["test", "package"]);
+        assert!(events.iter().any(|e| matches!(e, UiEvent::TaskSetEnd { ok: false, .. })));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn run_rejects_invalid_plan_before_starting() -> Result<()> {
+        let plan = TaskSetPlan { session_id: "test".into(), sets: vec![set("sequential", vec![task("a", &["a"], "x")])] };
+        assert!(run_plan(&plan).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn max_parallel_queues_tasks_as_pending() -> Result<()> {
+        let mut s = set("parallel", (0..5).map(|i| task(&format!("t{}", i), &[], "run")).collect());
+        s.max_parallel = Some(2);
+        let plan = TaskSetPlan { session_id: "test".into(), sets: vec![s] };
+        let Outcome { ran, events, peak } = run_plan(&plan).await?;
+        assert_eq!(ran.len(), 5);
+        assert_eq!(peak, 2);
+        let pending = events.iter().filter(|e| matches!(e, UiEvent::TaskStatus { status: TaskStatus::Pending, .. })).count();
+        assert_eq!(pending, 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn max_concurrency_applies_per_model_target() -> Result<()> {
+        let h = Harness::new()?;
+        std::fs::write(h.root.join("workspace.toml"), r#"
+[models.profiles.a]
+name = "shared"
+base_url = "http://models.local"
+max_concurrency = 1
+
+[models.profiles.b]
+name = "shared"
+base_url = "http://models.local"
+max_concurrency = 1
+
+[models.profiles.c]
+name = "shared"
+base_url = "http://other.local"
+max_concurrency = 1
+"#)?;
+        h.cfg.reload_all()?;
+        let tasks = ["a1", "b1", "c1", "c2"].into_iter().map(|id| {
+            TaskSpec { model_profile: Some(id[..1].into()), ..task(id, &[], "run") }
+        }).collect();
+        let plan = TaskSetPlan { session_id: "test".into(), sets: vec![set("parallel", tasks)] };
+        h.runner(&plan).run().await?;
+        let Outcome { ran, events, peak } = h.outcome();
+        assert_eq!(ran.len(), 4);
+        assert_eq!(peak, 2, "a and b share one target, c has its own");
+        let pending: Vec<&str> = events.iter().filter_map(|e| match e {
+            UiEvent::TaskStatus { task_id, status: TaskStatus::Pending, .. } => Some(task_id.as_str()),
+            _ => None,
+        }).collect();
+        assert_eq!(pending, vec!["b1", "c2"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cancel_aborts_running_and_skips_queued_tasks() -> Result<()> {
+        let plan = TaskSetPlan {
+            session_id: "test".into(),
+            sets: vec![
+                set("sequential", vec![task("a", &[], "hang"), task("b", &[], "run")]),
+                set("sequential", vec![task("c", &[], "run")]),
+            ],
+        };
+        let Outcome { ran, events, .. } = run_plan(&plan).await?;
+        assert_eq!(ran, vec!["hang".to_string()]);
+        assert!(events.iter().any(|e| matches!(e, UiEvent::TaskEnd { task_id, ok: false, cancelled: true, .. } if task_id == "a")));
+        assert!(events.iter().any(|e| matches!(e, UiEvent::TaskStatus { task_id, status: TaskStatus::Skipped { .. }, .. } if task_id == "b")));
+        let set_ends: Vec<_> = events.iter().filter(|e| matches!(e, UiEvent::TaskSetEnd { .. })).collect();
+        assert!(matches!(set_ends.as_slice(), [UiEvent::TaskSetEnd { ok: false, cancelled: true, .. }]));
+        Ok(())
+    }
+
+    fn progress_lines(events: &[UiEvent]) -> Vec<&str> {
+        events.iter().filter_map(|e| match e { UiEvent::TaskProgress { line, .. } => Some(line.as_str()), _ => None }).collect()
+    }
+
+    #[tokio::test]
+    async fn exec_output_lines_stream_into_progress() -> Result<()> {
+        let t = steps_task(serde_json::json!([{ "type": "exec", "cmd": "echo", "args": ["building", "warning: slow", "done"] }]))?;
+        let plan = TaskSetPlan { session_id: "test".into(), sets: vec![set("sequential", vec![t])] };
+        let Outcome { events, .. } = run_plan(&plan).await?;
+        let lines = progress_lines(&events);
+        let at = lines.iter().position(|l| *l == "building").expect("streamed line");
+        assert_eq!(lines[at..at + 4], ["building", "stderr: warning: slow", "done", "exec echo -> 0"]);
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_attempt_is_retried_with_backoff() -> Result<()> {
+        let mut t = task("flaky", &[], "flaky");
+        t.step_defaults.retries = Some(2);
+        let plan = TaskSetPlan { session_id: "test".into(), sets: vec![set("sequential", vec![t])] };
+        let Outcome { ran, events, .. } = run_plan(&plan).await?;
+        assert_eq!(ran.len(), 2);
+        assert!(progress_lines(&events).contains(&"retry 1/2 of step 0: step failed"));
+        assert!(events.iter().any(|e| matches!(e, UiEvent::TaskEnd { ok: true, .. })));
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn step_timeout_counts_as_failed_attempt() -> Result<()> {
+        let mut t = task("slow", &[], "sleep");
+        t.steps[0].policy = StepPolicy { timeout_secs: Some(5), retries: Some(1), backoff: Some(Backoff::Fixed { delay_ms: 10 }) };
+        let plan = TaskSetPlan { session_id: "test".into(), sets: vec![set("sequential", vec![t])] };
+        let Outcome { ran, events, .. } = run_plan(&plan).await?;
+        assert_eq!(ran.len(), 2);
+        let lines = progress_lines(&events);
+        assert!(lines.contains(&"retry 1/1 of step 0: step 0 timed out after 5s"));
+        assert!(lines.contains(&"error: step 0 timed out after 5s"));
+        Ok(())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn step_timeouts_during_hooks_leave_later_hooks_working() -> Result<()> {
+        let mut h = Harness::new()?;
+        let hooks_dir = h.root.join("hooks");
+        std::fs::create_dir_all(&hooks_dir)?;
+        std::fs::write(hooks_dir.join("rules.toml"), r#"
+[[rule]]
+name = "slow-audit"
+when = ["post_exec"]
+match = { cmd = "run" }
+actions = [{ kind = "exec", cmd = "sleep", args = ["30"] }]
+
+[[rule]]
+name = "no-deploy"
+when = ["pre_exec"]
+match = { cmd = "deploy" }
+actions = [{ kind = "exec", cmd = "sh", args = ["-c", "echo '{\"decision\": \"deny\", \"reason\": \"release freeze\"}'"] }]
+"#)?;
+        h.hooks = Arc::new(HookRegistry::load_from_dirs(h.cfg.clone(), &[hooks_dir])?);
+        // Every attempt times out inside the post_exec hook, more often than the recursion limit.
+        let mut slow = task("slow", &[], "run");
+        slow.steps[0].policy = StepPolicy { timeout_secs: Some(1), retries: Some(4), backoff: Some(Backoff::Fixed { delay_ms: 10 }) };
+        let plan = TaskSetPlan { session_id: "test".into(), sets: vec![set("sequential", vec![slow, task("ship", &[], "deploy")])] };
+        let report = h.runner(&plan).run().await?;
+        let [slow, ship] = report.sets[0].tasks.as_slice() else { panic!("expected two task reports") };
+        assert_eq!((slow.outcome.clone(), slow.steps[0].attempts), (TaskOutcome::Failed, 5));
+        assert_eq!(ship.hook_denials, vec!["step 0: release freeze".to_string()]);
+        assert!(!h.outcome().ran.contains(&"deploy".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn step_policy_deserializes_beside_step_fields() -> Result<()> {
+        let spec: StepSpec = serde_json::from_value(serde_json::json!({
+            "type": "exec", "cmd": "cargo", "args": ["test"], "timeout_secs": 60, "retries": 3,
+            "backoff": { "kind": "fixed", "delay_ms": 250 }
+        }))?;
+        assert!(matches!(spec.step, TaskStep::Exec { ref cmd, .. } if cmd == "cargo"));
+        assert_eq!(spec.policy.timeout_secs, Some(60));
+        assert_eq!(spec.policy.backoff.map(|b| b.delay(3)), Some(Duration::from_millis(250)));
+        assert_eq!(Backoff::default().delay(3), Duration::from_millis(2000));
+        Ok(())
+    }
+
+    struct Scripted(Mutex<Vec<(String, ConfirmDecision)>>);
+
+    #[async_trait]
+    impl SetConfirm for Scripted {
+        async fn confirm(&self, _finished: &TaskSetSpec, summary: &str, _next: &TaskSetSpec) -> Result<ConfirmDecision> {
+            let mut script = self.0.lock();
+            let (expect, decision) = script.remove(0);
+            assert!(summary.contains(&expect), "summary {:?} lacks {:?}", summary, expect);
+            Ok(decision)
+        }
+    }
+
+    #[tokio::test]
+    async fn confirm_gate_can_replace_or_abort_next_set() -> Result<()> {
+        let mut second = set("sequential", vec![task("b", &[], "planned")]);
+        second.set_id = "s2".into();
+        let mut replacement = set("sequential", vec![task("b", &[], "refined")]);
+        replacement.set_id = "s2".into();
+        let mut third = set("sequential", vec![task("c", &[], "never")]);
+        third.set_id = "s3".into();
+        let plan = TaskSetPlan { session_id: "test".into(), sets: vec![set("sequential", vec![task("a", &[], "first")]), second, third] };
+
+        let h = Harness::new()?;
+        let mut runner = h.runner(&plan);
+        runner.confirm = Some(Arc::new(Scripted(Mutex::new(vec![
+            ("Task set 'set' finished".into(), ConfirmDecision::Replace(replacement)),
+            ("Task set 'set' finished".into(), ConfirmDecision::Abort),
+        ]))));
+        runner.run().await?;
+        drop(runner);
+        assert_eq!(h.outcome().ran, vec!["first".to_string(), "refined".to_string()]);
+        Ok(())
+    }
+
+    /// Cancels the run while asked, then never answers.
+    struct CancelWhileAsked(CancellationToken);
+
+    #[async_trait]
+    impl SetConfirm for CancelWhileAsked {
+        async fn confirm(&self, _finished: &TaskSetSpec, summary: &str, _next: &TaskSetSpec) -> Result<ConfirmDecision> {
+            assert!(summary.is_empty(), "a failed summary reaches confirm as empty");
+            self.0.cancel();
+            futures::future::pending().await
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_summary_is_reported_and_cancel_interrupts_confirm() -> Result<()> {
+        let mut second = set("sequential", vec![task("b", &[], "never")]);
+        second.set_id = "s2".into();
+        let plan = TaskSetPlan { session_id: "test".into(), sets: vec![set("sequential", vec![task("a", &[], "first")]), second] };
+        let h = Harness::new()?;
+        std::fs::write(h.root.join("workspace.toml"), format!("[sessions]\ndir = {:?}\n", h.root.join("sessions")))?;
+        h.cfg.reload_all()?;
+        let log = SessionLogWriter::new(&h.cfg, "test")?;
+        let mut runner = h.runner(&plan);
+        runner.log = Some(log.clone());
+        runner.do_chat = Arc::new(|_, _, _| Box::pin(async { Err(anyhow::anyhow!("model unavailable")) }));
+        runner.confirm = Some(Arc::new(CancelWhileAsked(h.cancel.clone())));
+        let report = runner.run().await?;
+        drop(runner);
+        assert!(report.cancelled && !report.ok);
+        assert_eq!(report.sets.len(), 1);
+        assert_eq!((report.sets[0].summary.as_deref(), report.sets[0].error.as_deref()), (None, Some("summary failed: model unavailable")));
+        assert!(log.dir().join("taskset-report.json").exists());
+        assert_eq!(h.outcome().ran, vec!["first".to_string()]);
+        Ok(())
+    }
+
+    fn steps_task(steps: serde_json::Value) -> Result<TaskSpec> {
+        Ok(serde_json::from_value(serde_json::json!({ "id": "t", "name": "t", "steps": steps }))?)
+    }
+
+    #[tokio::test]
+    async fn step_outputs_feed_later_steps() -> Result<()> {
+        let t = steps_task(serde_json::json!([
+            { "type": "exec", "id": "ver", "cmd": "echo", "args": ["v1.2"] },
+            { "type": "exec", "cmd": "tag-{{steps.ver.stdout}}", "args": ["{{ steps.ver.exit_code }}"] },
+        ]))?;
+        let plan = TaskSetPlan { session_id: "test".into(), sets: vec![set("sequential", vec![t])] };
+        assert_eq!(run_plan(&plan).await?.ran, vec!["echo".to_string(), "tag-v1.2".to_string()]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn stdout_of_real_commands_templates_without_its_newline() -> Result<()> {
+        let t = steps_task(serde_json::json!([
+            { "type": "exec", "id": "branch", "cmd": "echo", "args": ["main"] },
+            { "type": "exec", "cmd": "sh", "args": ["-c", "test \"$0\" = main", "{{steps.branch.stdout}}"] },
+        ]))?;
+        let plan = TaskSetPlan { session_id: "test".into(), sets: vec![set("sequential", vec![t])] };
+        let h = Harness::new()?;
+        let mut runner = h.runner(&plan);
+        runner.do_exec = crate::exec::tokio_exec(Default::default());
+        let report = runner.run().await?;
+        let steps = &report.sets[0].tasks[0].steps;
+        assert_eq!(steps[0].output, "main\n");
+        assert!(steps[1].ok, "sh saw {:?}", steps[1].output);
+        Ok(())
+    }
+
+    #[test]
+    fn validate_rejects_undefined_step_references() -> Result<()> {
+        let later = steps_task(serde_json::json!([
+            { "type": "chat", "prompt": "use {{steps.build.stdout}}" },
+            { "type": "exec", "id": "build", "cmd": "cargo", "args": ["build"] },
+        ]))?;
+        assert!(later.validate().unwrap_err().to_string().contains("no earlier step with id 'build'"));
+        let wrong_field = steps_task(serde_json::json!([
+            { "type": "exec", "id": "build", "cmd": "cargo", "args": ["build"] },
+            { "type": "mcp_call", "server": "s", "method": "m", "payload": { "log": "{{steps.build.reply}}" } },
+        ]))?;
+        assert!(wrong_field.validate().unwrap_err().to_string().contains("has no output 'reply'"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn resume_skips_finished_sets_and_rejects_changed_plans() -> Result<()> {
+        let ws = tempdir()?;
+        let mut second = set("sequential", vec![task("b", &[], "hang"), task("c", &[], "after")]);
+        second.set_id = "s2".into();
+        let mut plan = TaskSetPlan { session_id: "sess".into(), sets: vec![set("sequential", vec![task("a", &[], "first")]), second] };
+
+        let h = Harness::new()?;
+        let mut runner = h.runner(&plan);
+        runner.checkpoint = Some(Arc::new(CheckpointStore::create(ws.path(), &plan)?));
+        runner.run().await?;
+        drop(runner);
+        assert_eq!(h.outcome().ran, vec!["first".to_string(), "hang".to_string()]);
+
+        let cp = Arc::new(CheckpointStore::open(ws.path(), "sess")?.context("checkpoint written")?);
+        assert!(cp.dir().join("set-02.json").exists());
+        assert_eq!(cp.snapshot().tasks["s2"]["b"].state, RunState::Cancelled);
+        let h = Harness::new()?;
+        let mut runner = h.runner(&plan);
+        runner.checkpoint = Some(cp.clone());
+        runner.resume().await?;
+        drop(runner);
+        assert_eq!(h.outcome().ran, vec!["hang".to_string()]);
+
+        plan.sets[1].tasks[1].steps.clear();
+        let h = Harness::new()?;
+        let mut runner = h.runner(&plan);
+        runner.checkpoint = Some(cp);
+        assert!(runner.resume().await.unwrap_err().to_string().contains("plan changed"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn checkpoint_write_errors_fail_the_task_and_are_reported() -> Result<()> {
+        let ws = tempdir()?;
+        let plan = TaskSetPlan { session_id: "sess".into(), sets: vec![set("sequential", vec![task("a", &[], "first")])] };
+        let cp = CheckpointStore::create(ws.path(), &plan)?;
+        std::fs::create_dir(cp.dir().join("checkpoint.json.tmp"))?;
+
+        let h = Harness::new()?;
+        let mut runner = h.runner(&plan);
+        runner.checkpoint = Some(Arc::new(cp));
+        let report = runner.run().await?;
+        assert!(!report.ok);
+        let error = report.sets[0].checkpoint_error.as_deref().context("set end not written")?;
+        assert!(error.contains("Is a directory"), "{}", error);
+        drop(runner);
+        let Outcome { ran, events, .. } = h.outcome();
+        assert!(ran.is_empty());
+        assert!(progress_lines(&events).iter().any(|l| l.starts_with("error: checkpoint")));
+        assert!(events.iter().any(|e| matches!(e, UiEvent::TaskEnd { ok: false, .. })));
+        Ok(())
+    }
+
+    #[test]
+    fn dry_run_reports_models_and_hooks_without_running() -> Result<()> {
+        let mut h = Harness::new()?;
+        let hooks_dir = h.root.join("hooks");
+        std::fs::create_dir_all(&hooks_dir)?;
+        std::fs::write(hooks_dir.join("guard.toml"), r#"
+[[rule]]
+name = "guard"
+when = ["pre_exec"]
+deny_on_fail = true
+actions = [{ kind = "exec", cmd = "check", args = ["--strict"] }]
+"#)?;
+        h.hooks = Arc::new(HookRegistry::load_from_dirs(h.cfg.clone(), &[hooks_dir])?);
+        let mut t = task("build", &[], "cargo");
+        t.model_profile = Some("fast".into());
+        let plan = TaskSetPlan { session_id: "test".into(), sets: vec![set("parallel", vec![task("lint", &["build"], "clippy"), t])] };
+
+        let report = h.runner(&plan).dry_run()?;
+        let s = &report.sets[0];
+        assert_eq!(s.schedule, vec!["build".to_string(), "lint".to_string()]);
+        let build = &s.tasks[1];
+        assert_eq!(build.model_label, "fast");
+        assert!(build.warnings[0].contains("'fast' is not configured"));
+        assert_eq!(build.steps[0].summary, "cargo");
+        assert_eq!(build.steps[0].hooks[0].actions, vec!["exec: check --strict".to_string()]);
+        assert_eq!(build.steps[0].may_deny, vec!["guard".to_string()]);
+        assert!(h.outcome().ran.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn success_criteria_decide_task_outcome() -> Result<()> {
+        let task_json = |id: &str, cmd: &str, criteria: serde_json::Value| -> Result<TaskSpec> {
+            Ok(serde_json::from_value(serde_json::json!({
+                "id": id, "name": id, "success_criteria": criteria,
+                "steps": [{ "type": "exec", "cmd": cmd, "args": ["build", "done"] }],
+            }))?)
+        };
+        let plan = TaskSetPlan { session_id: "test".into(), sets: vec![set("parallel", vec![
+            task_json("exit", "fail", serde_json::json!("exit_code:0,1"))?,
+            task_json("regex", "run", serde_json::json!({ "output_matches": "^done" }))?,
+            task_json("shorthand", "run", serde_json::json!("output:build\\s+done$"))?,
+        ])] };
+        let Outcome { events, .. } = run_plan(&plan).await?;
+        let ends: Vec<(&str, bool)> = events.iter().filter_map(|e| match e {
+            UiEvent::TaskEnd { task_id, ok, .. } => Some((task_id.as_str(), *ok)),
+            _ => None,
+        }).collect();
+        assert!(ends.contains(&("exit", true)) && ends.contains(&("regex", false)) && ends.contains(&("shorthand", true)));
+        assert!(progress_lines(&events).contains(&"success criteria not met: output does not match /^done/"));
+        assert!(task_json("bad", "run", serde_json::json!("status:ok")).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn on_error_aborts_set_or_plan() -> Result<()> {
+        for (on_error, later_sets_run) in [(OnError::AbortSet, true), (OnError::AbortPlan, false)] {
+            let mut failing = task("a", &[], "fail");
+            failing.on_error = on_error;
+            failing.steps.push(TaskStep::Exec { cmd: "cleanup".into(), args: vec![], env: BTreeMap::new() }.into());
+            let mut next = set("sequential", vec![task("c", &[], "next")]);
+            next.set_id = "s2".into();
+            let plan = TaskSetPlan { session_id: "test".into(), sets: vec![set("sequential", vec![failing, task("b", &[], "run")]), next] };
+            let Outcome { ran, events, .. } = run_plan(&plan).await?;
+            let expected: &[&str] = if later_sets_run { &["fail", "next"] } else { &["fail"] };
+            assert_eq!(ran, expected);
+            assert!(events.iter().any(|e| matches!(e,
+                UiEvent::TaskStatus { task_id, status: TaskStatus::Skipped { reason }, .. } if task_id == "b" && reason == "set aborted after task 'a' failed")));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn when_conditions_skip_steps_and_tasks() -> Result<()> {
+        let h = Harness::new()?;
+        git2::Repository::init(&h.root)?;
+        std::fs::create_dir_all(h.root.join("src"))?;
+        std::fs::write(h.root.join("src/lib.rs"), "")?;
+        let mut t = steps_task(serde_json::json!([
+            { "type": "exec", "id": "build", "cmd": "fail", "args": [] },
+            { "type": "exec", "cmd": "logs", "args": [], "when": "failure() && steps.build.exit_code == 1" },
+            { "type": "exec", "cmd": "deploy", "args": [] },
+            { "type": "exec", "cmd": "notify", "args": [], "when": "success()" },
+        ]))?;
+        t.on_error = OnError::AbortSet;
+        let conditional = |id: &str, depends_on: &[&str], when: &str| TaskSpec { when: Some(when.into()), ..task(id, depends_on, id) };
+        let mut second = set("parallel", vec![
+            conditional("docs", &[], "changed('docs/**')"),
+            task("publish", &["docs"], "publish"),
+            conditional("code", &[], "changed('src/*.rs') && env.CI == 1"),
+        ]);
+        second.set_id = "s2".into();
+        let plan = TaskSetPlan { session_id: "test".into(), sets: vec![set("sequential", vec![t]), second] };
+        plan.sets.iter().try_for_each(TaskSetSpec::validate)?;
+
+        let mut runner = h.runner(&plan);
+        runner.ctx.env.insert("CI".into(), "1".into());
+        let report = runner.run().await?;
+        drop(runner);
+        let steps = &report.sets[0].tasks[0].steps;
+        assert_eq!(steps.iter().map(|s| (s.index, s.skipped)).collect::<Vec<_>>(), vec![(0, false), (1, false), (3, true)]);
+        let second = &report.sets[1];
+        assert!(second.ok);
+        assert_eq!(second.tasks[0].outcome, TaskOutcome::Skipped { reason: "when `changed('docs/**')` is false".into() });
+        assert_eq!(second.tasks[1].outcome, TaskOutcome::Skipped { reason: "dependency 'docs' was skipped".into() });
+        assert_eq!(second.tasks[2].outcome, TaskOutcome::Succeeded);
+        let Outcome { ran, events, .. } = h.outcome();
+        assert_eq!(ran, vec!["fail", "logs", "code"]);
+        assert!(progress_lines(&events).contains(&"step 3 skipped: when `success()` is false"));
+
+        let early = steps_task(serde_json::json!([{ "type": "exec", "cmd": "a", "args": [], "when": "steps.b.ok" }, { "type": "exec", "id": "b", "cmd": "b", "args": [] }]))?;
+        assert!(early.validate().unwrap_err().to_string().contains("no earlier step with id 'b'"));
+        let bad = TaskSpec { when: Some("steps.x.ok".into()), ..task("bad", &[], "run") };
+        assert!(bad.validate().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn matrix_tasks_are_expanded_before_scheduling() -> Result<()> {
+        let mut t = steps_task(serde_json::json!([{ "type": "exec", "cmd": "build-{{matrix.os}}", "args": [] }]))?;
+        t.matrix.insert("os".into(), MatrixAxis::Values(vec!["linux".into(), "macos".into()]));
+        let plan = TaskSetPlan { session_id: "test".into(), sets: vec![set("sequential", vec![t, task("pkg", &["t"], "pkg")])] };
+        let h = Harness::new()?;
+        let report = h.runner(&plan).run().await?;
+        let ids: Vec<&str> = report.sets[0].tasks.iter().map(|t| t.id.as_str()).collect();
+        assert_eq!(ids, ["t-linux", "t-macos", "pkg"]);
+        assert_eq!(h.outcome().ran, ["build-linux", "build-macos", "pkg"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn status_line_drives_running_status() -> Result<()> {
+        let mut t = steps_task(serde_json::json!([{ "type": "exec", "cmd": "a", "args": [] }, { "type": "chat", "prompt": "p" }]))?;
+        t.status_line = Some("{{task.name}} {{task.step}}/{{task.steps}} {{task.kind}}".into());
+        let plan = TaskSetPlan { session_id: "test".into(), sets: vec![set("sequential", vec![t, task("plain", &[], "run")])] };
+        let Outcome { events, .. } = run_plan(&plan).await?;
+        let lines: Vec<&str> = events.iter().filter_map(|e| match e {
+            UiEvent::TaskStatus { status: TaskStatus::Running { status_line }, .. } => Some(status_line.as_str()),
+            _ => None,
+        }).collect();
+        assert_eq!(lines, vec!["t 1/2 exec", "t 2/2 chat", "step 1/1: exec"]);
+
+        let mut bad = task("bad", &[], "run");
+        bad.status_line = Some("{{task.nope}}".into());
+        assert!(bad.validate().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn run_reports_steps_and_denials_and_writes_report() -> Result<()> {
+        let mut h = Harness::new()?;
+        let hooks_dir = h.root.join("hooks");
+        std::fs::create_dir_all(&hooks_dir)?;
+        std::fs::write(hooks_dir.join("guard.toml"), r#"
+[[rule]]
+name = "no-exec"
+when = ["pre_exec"]
+deny_on_fail = true
+actions = [{ kind = "exec", cmd = "false", args = [] }]
+"#)?;
+        h.hooks = Arc::new(HookRegistry::load_from_dirs(h.cfg.clone(), &[hooks_dir])?);
+        std::fs::write(h.root.join("workspace.toml"), format!("[sessions]\ndir = {:?}\n", h.root.join("sessions")))?;
+        h.cfg.reload_all()?;
+        let log = SessionLogWriter::new(&h.cfg, "sess")?;
+        let chat = steps_task(serde_json::json!([{ "type": "chat", "id": "ask", "prompt": "hi" }]))?;
+        let plan = TaskSetPlan { session_id: "sess".into(), sets: vec![set("sequential", vec![chat, task("build", &[], "cargo"), task("pkg", &["build"], "pkg")])] };
+
+        let mut runner = h.runner(&plan);
+        runner.log = Some(log.clone());
+        let report = runner.run().await?;
+        drop(runner);
+        let s = &report.sets[0];
+        assert!(!report.ok);
+        let [chat, build, pkg] = s.tasks.as_slice() else { panic!("expected three task reports") };
+        assert_eq!((chat.outcome.clone(), chat.steps[0].output.as_str(), chat.steps[0].attempts), (TaskOutcome::Succeeded, "summary: hi", 1));
+        assert_eq!(build.outcome, TaskOutcome::Failed);
+        assert_eq!(build.hook_denials, vec!["step 0: exec failed: no-exec".to_string()]);
+        assert_eq!(pkg.outcome, TaskOutcome::Skipped { reason: "dependency 'build' did not succeed".into() });
+        assert!(s.summary.as_deref().is_some_and(|r| r.contains("\"hook_denials\"") && r.contains("no-exec")));
+        let written: TaskSetReport = serde_json::from_str(&std::fs::read_to_string(log.dir().join("taskset-report.json"))?)?;
+        assert_eq!(written.sets[0].tasks.len(), 3);
+        assert!(h.outcome().ran.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn exec_hooks_read_the_event_and_reply_with_decisions() -> Result<()> {
+        let mut h = Harness::new()?;
+        let hooks_dir = h.root.join("hooks");
+        std::fs::create_dir_all(&hooks_dir)?;
+        std::fs::write(hooks_dir.join("reply.toml"), r#"
+[[rule]]
+name = "record"
+when = ["pre_exec"]
+actions = [{ kind = "exec", cmd = "sh", args = ["-c", "cat > event-$CODEX_HOOK_CMD.json; echo '{\"message\": \"saw '$CODEX_HOOK_ARGV'\"}'"] }]
+
+[[rule]]
+name = "no-deploy"
+when = ["pre_exec"]
+match = { cmd = "deploy" }
+actions = [{ kind = "exec", cmd = "sh", args = ["-c", "echo '{\"decision\": \"deny\", \"reason\": \"no deploys on fridays\"}'"] }]
+
+[[rule]]
+name = "lenient"
+when = ["pre_exec"]
+deny_on_fail = true
+actions = [{ kind = "exec", cmd = "sh", args = ["-c", "echo '{\"decision\": \"allow\"}'; exit 1"] }]
+"#)?;
+        h.hooks = Arc::new(HookRegistry::load_from_dirs(h.cfg.clone(), &[hooks_dir])?);
+        let mut build = task("build", &[], "cargo");
+        build.steps = vec![TaskStep::Exec { cmd: "cargo".into(), args: vec!["build".into(), "--release".into()], env: BTreeMap::new() }.into()];
+        let plan = TaskSetPlan { session_id: "test".into(), sets: vec![set("sequential", vec![build, task("ship", &[], "deploy")])] };
+        let report = h.runner(&plan).run().await?;
+        let [build, ship] = report.sets[0].tasks.as_slice() else { panic!("expected two task reports") };
+        assert_eq!(build.outcome, TaskOutcome::Succeeded);
+        assert_eq!(ship.hook_denials, vec!["step 0: no deploys on fridays".to_string()]);
+
+        let input: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(h.root.join("event-cargo.json"))?)?;
+        assert_eq!((input["rule"].as_str(), input["session_id"].as_str()), (Some("record"), Some("test")));
+        assert_eq!(input["event"], serde_json::json!({ "type": "pre_exec", "cmd": "cargo", "argv": ["build", "--release"] }));
+        let out = h.outcome();
+        assert_eq!(out.ran, vec!["cargo"]);
+        assert!(out.events.iter().any(|e| matches!(e, UiEvent::TaskProgress { line, .. } if line == "hook: saw build --release")));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn hook_rewrites_chain_and_the_rewritten_step_runs() -> Result<()> {
+        let mut h = Harness::new()?;
+        let hooks_dir = h.root.join("hooks");
+        std::fs::create_dir_all(&hooks_dir)?;
+        std::fs::write(hooks_dir.join("rewrite.toml"), r#"
+[[rule]]
+name = "locked"
+when = ["pre_exec"]
+match = { cmd = "cargo" }
+actions = [{ kind = "exec", cmd = "sh", args = ["-c", "echo '{\"event\": {\"type\": \"pre_exec\", \"cmd\": \"cargo\", \"argv\": [\"build\", \"--locked\"]}}'"] }]
+
+[[rule]]
+name = "offline"
+when = ["pre_exec"]
+match = { arg = "--locked" }
+actions = [{ kind = "exec", cmd = "sh", args = ["-c", "echo '{\"decision\": \"modify\", \"message\": \"going offline\", \"event\": {\"type\": \"pre_exec\", \"cmd\": \"cargo\", \"argv\": [\"build\", \"--locked\", \"--offline\"]}}'"] }]
+
+[[rule]]
+name = "confused"
+when = ["pre_exec"]
+match = { cmd = "make" }
+deny_on_fail = true
+actions = [{ kind = "exec", cmd = "sh", args = ["-c", "echo '{\"event\": {\"type\": \"task_start\", \"task_name\": \"x\"}}'"] }]
+"#)?;
+        h.hooks = Arc::new(HookRegistry::load_from_dirs(h.cfg.clone(), &[hooks_dir])?);
+        std::fs::write(h.root.join("workspace.toml"), format!("[sessions]\ndir = {:?}\n", h.root.join("sessions")))?;
+        h.cfg.reload_all()?;
+        let log = SessionLogWriter::new(&h.cfg, "sess")?;
+        let mut build = task("build", &[], "cargo");
+        build.steps = vec![TaskStep::Exec { cmd: "cargo".into(), args: vec!["build".into()], env: BTreeMap::new() }.into()];
+        let plan = TaskSetPlan { session_id: "sess".into(), sets: vec![set("sequential", vec![build, task("make", &[], "make")])] };
+        let mut runner = h.runner(&plan);
+        runner.log = Some(log.clone());
+        let report = runner.run().await?;
+        drop(runner);
+        let [build, make] = report.sets[0].tasks.as_slice() else { panic!("expected two task reports") };
+        assert_eq!(build.steps[0].output, "build --locked --offline");
+        assert_eq!(make.hook_denials, vec!["step 0: hook confused turned a pre_exec event into task_start".to_string()]);
+
+        let logged: Vec<serde_json::Value> = serde_json::from_str(&std::fs::read_to_string(log.dir().join("session.json"))?)?;
+        let modify = logged.iter().find(|e| e["type"] == "hook_modify").context("no hook_modify record")?;
+        assert_eq!(modify["rules"], serde_json::json!(["locked", "offline"]));
+        assert_eq!(modify["changes"], serde_json::json!([{ "path": "argv", "before": ["build"], "after": ["build", "--locked", "--offline"] }]));
+        let out = h.outcome();
+        assert_eq!(out.ran, vec!["cargo"]);
+        assert!(out.events.iter().any(|e| matches!(e, UiEvent::TaskProgress { line, .. } if line == "hook: going offline")));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn sub_agent_runs_nested_steps_under_its_profile_and_respects_denials() -> Result<()> {
+        let mut h = Harness::new()?;
+        let hooks_dir = h.root.join("hooks");
+        std::fs::create_dir_all(&hooks_dir)?;
+        std::fs::write(hooks_dir.join("guard.toml"), r#"
+[[rule]]
+name = "no-mcp"
+when = ["pre_mcp"]
+deny_on_fail = true
+actions = [{ kind = "exec", cmd = "false", args = [] }]
+
+[[rule]]
+name = "annotate"
+when = ["pre_tool_use"]
+match = { tool = "sub_agent" }
+actions = [{ kind = "exec", cmd = "sh", args = ["-c", "echo '{\"event\": {\"type\": \"pre_tool_use\", \"tool\": \"sub_agent\", \"args\": {\"agent\": \"reviewer\", \"steps\": 3, \"reviewed\": true}}}'"] }]
+"#)?;
+        h.hooks = Arc::new(HookRegistry::load_from_dirs(h.cfg.clone(), &[hooks_dir])?);
+        std::fs::write(h.root.join("workspace.toml"), "[models.profiles.reviewer]\nname = \"review-model\"\n")?;
+        h.cfg.reload_all()?;
+        let t = steps_task(serde_json::json!([
+            { "type": "exec", "id": "diff", "cmd": "diff", "args": ["patch"] },
+            { "type": "sub_agent", "id": "review", "agent": "reviewer", "steps": [
+                { "type": "chat", "id": "r", "prompt": "review {{steps.diff.stdout}}" },
+                { "type": "exec", "cmd": "lint-{{steps.r.ok}}", "args": [] },
+                { "type": "mcp_call", "server": "s", "method": "m", "payload": {} },
+            ] },
+            { "type": "exec", "cmd": "after", "args": ["{{steps.review.reply}}"] },
+        ]))?;
+        t.validate()?;
+        let plan = TaskSetPlan { session_id: "test".into(), sets: vec![set("sequential", vec![t])] };
+        let report = h.runner(&plan).run().await?;
+        let task = &report.sets[0].tasks[0];
+        assert_eq!(task.outcome, TaskOutcome::Failed);
+        assert_eq!(task.steps[1].nested.len(), 3);
+        assert_eq!(task.hook_denials, vec!["step 1.2: exec failed: no-mcp".to_string()]);
+        assert_eq!(h.chat_models.lock()[0], "review-model");
+        let out = h.outcome();
+        assert_eq!(out.ran, ["diff", "lint-true", "after"]);
+        assert!(progress_lines(&out.events).contains(&"step 1 rewritten by hook: annotate"));
+
+        let leaky = steps_task(serde_json::json!([
+            { "type": "sub_agent", "agent": "reviewer", "steps": [{ "type": "chat", "id": "r", "prompt": "hi" }] },
+            { "type": "exec", "cmd": "{{steps.r.reply}}", "args": [] },
+        ]))?;
+        assert!(leaky.validate().unwrap_err().to_string().contains("no earlier step with id 'r'"));
+
+        let typo = steps_task(serde_json::json!([
+            { "type": "exec", "cmd": "diff", "args": [] },
+            { "type": "sub_agent", "agent": "reveiwer", "steps": [{ "type": "chat", "prompt": "hi" }] },
+        ]))?;
+        let plan = TaskSetPlan { session_id: "test".into(), sets: vec![set("sequential", vec![typo])] };
+        let err = Harness::new()?.runner(&plan).run().await.unwrap_err();
+        assert_eq!(err.to_string(), "task 't' step 1: unknown agent profile 'reveiwer'");
+        Ok(())
+    }
+
+    struct ApproveOnly(&'static str);
+
+    #[async_trait]
+    impl ExecApproval for ApproveOnly {
+        async fn approve(&self, cmd: &str, _args: &[String], _check: &crate::shell_policy::PolicyCheck) -> Result<bool> {
+            Ok(cmd == self.0)
+        }
+    }
+
+    #[tokio::test]
+    async fn shell_policy_guards_exec_steps_and_hook_actions() -> Result<()> {
+        let mut h = Harness::new()?;
+        let hooks_dir = h.root.join("hooks");
+        std::fs::create_dir_all(&hooks_dir)?;
+        std::fs::write(hooks_dir.join("guard.toml"), r#"
+[[rule]]
+name = "mcp-cleanup"
+when = ["pre_mcp"]
+deny_on_fail = true
+actions = [{ kind = "exec", cmd = "rm", args = ["-rf", "cache"] }]
+"#)?;
+        h.hooks = Arc::new(HookRegistry::load_from_dirs(h.cfg.clone(), &[hooks_dir])?);
+        std::fs::write(h.root.join("workspace.toml"), "[shell]\napproval = \"unless_trusted\"\nallowlist_roots = [\"cargo\"]\ndenylist_roots = [\"rm\"]\n")?;
+        h.cfg.reload_all()?;
+        let t = steps_task(serde_json::json!([
+            { "type": "exec", "cmd": "cargo", "args": ["test"] },
+            { "type": "exec", "cmd": "bash", "args": ["-lc", "cargo build && rm -rf target"] },
+            { "type": "exec", "cmd": "python3", "args": [] },
+            { "type": "exec", "cmd": "make", "args": [] },
+            { "type": "mcp_call", "server": "s", "method": "m", "payload": {} },
+        ]))?;
+        let plan = TaskSetPlan { session_id: "test".into(), sets: vec![set("sequential", vec![t])] };
+        let mut runner = h.runner(&plan);
+        runner.approve = Some(Arc::new(ApproveOnly("python3")));
+        let report = runner.run().await?;
+        drop(runner);
+        assert_eq!(report.sets[0].tasks[0].hook_denials, vec![
+            "step 1: shell policy: `rm` is denied".to_string(),
+            "step 3: shell policy: not approved: `make` needs approval".to_string(),
+            "step 4: exec blocked by shell policy: `rm` is denied".to_string(),
+        ]);
+        assert_eq!(h.outcome().ran, ["cargo", "python3"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn exec_steps_get_the_filtered_child_environment() -> Result<()> {
+        let h = Harness::new()?;
+        std::fs::write(h.root.join("workspace.toml"), "[shell]\nenvironment_inherit = \"core\"\nenv_exclude_patterns = [\"*KEY*\"]\n")?;
+        h.cfg.reload_all()?;
+        let t = steps_task(serde_json::json!([
+            { "type": "exec", "id": "env", "cmd": "printenv", "args": [], "env": { "MODE": "release", "DEPLOY_KEY": "{{task.id}}" } },
+        ]))?;
+        let plan = TaskSetPlan { session_id: "test".into(), sets: vec![set("sequential", vec![t])] };
+        let mut runner = h.runner(&plan);
+        runner.ctx.env.insert("CI".into(), "1".into());
+        runner.ctx.env.insert("OPENAI_API_KEY".into(), "sk".into());
+        let report = runner.run().await?;
+        drop(runner);
+        let step = &report.sets[0].tasks[0].steps[0];
+        let names: Vec<&str> = step.output.split(' ').collect();
+        assert!(names.contains(&"PATH") && !names.contains(&"CI") && names.contains(&"DEPLOY_KEY") && names.contains(&"MODE"));
+        assert!(names.iter().all(|n| ["DEPLOY_KEY", "MODE"].contains(n) || crate::child_env::CORE_VARS.contains(n)), "{:?}", names);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn exec_limits_merge_task_over_config_and_report_hits() -> Result<()> {
+        let h = Harness::new()?;
+        std::fs::write(h.root.join("workspace.toml"), "[limits]\ncpu_secs = 60\nmax_output_bytes = 4096\n")?;
+        h.cfg.reload_all()?;
+        let capped = TaskSpec { limits: Some(LimitsConfig { memory_mb: Some(1), cpu_secs: Some(5), ..Default::default() }), ..task("capped", &[], "hog") };
+        let plan = TaskSetPlan { session_id: "test".into(), sets: vec![set("sequential", vec![task("free", &[], "hog"), capped])] };
+        let report = h.runner(&plan).run().await?;
+        let [free, capped] = report.sets[0].tasks.as_slice() else { panic!("expected two task reports") };
+        assert_eq!(free.outcome, TaskOutcome::Succeeded);
+        assert!(free.steps[0].output.contains("cpu_secs: Some(60)") && free.steps[0].output.contains("memory_bytes: None"), "{}", free.steps[0].output);
+        assert_eq!(capped.outcome, TaskOutcome::Failed);
+        assert_eq!(capped.steps[0].limit, Some(crate::limits::LimitKind::Memory));
+        assert!(capped.steps[0].output.contains("cpu_secs: Some(5)") && capped.steps[0].output.contains("max_output_bytes: Some(4096)"));
+        let out = h.outcome();
+        assert!(out.events.iter().any(|e| matches!(e, UiEvent::TaskProgress { line, .. } if line == "exec hog -> -1 (memory limit exceeded)")));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn git_steps_run_natively_and_expose_files_and_commit() -> Result<()> {
+        let h = Harness::new()?;
+        let repo = git2::Repository::init(&h.root)?;
+        repo.config()?.set_str("user.name", "Test")?;
+        repo.config()?.set_str("user.email", "test@example.com")?;
+        std::fs::write(h.root.join("notes.txt"), "hi\n")?;
+        let t = steps_task(serde_json::json!([
+            { "type": "git", "id": "add", "action": "add", "paths": ["notes.txt"] },
+            { "type": "git", "id": "commit", "action": "commit", "message": "add {{steps.add.files}}" },
+            { "type": "exec", "cmd": "tag-{{steps.commit.commit}}", "args": [] },
+        ]))?;
+        t.validate()?;
+        let plan = TaskSetPlan { session_id: "test".into(), sets: vec![set("sequential", vec![t])] };
+        let report = h.runner(&plan).run().await?;
+        let head = repo.head()?.peel_to_commit()?;
+        assert_eq!(head.message(), Some("add notes.txt"));
+        let steps = &report.sets[0].tasks[0].steps;
+        assert_eq!(steps[1].files, vec!["notes.txt".to_string()]);
+        assert_eq!(steps[1].commit, Some(head.id().to_string()));
+        assert_eq!(h.outcome().ran, vec![format!("tag-{}", head.id())]);
+        Ok(())
+    }
+
+    #[test]
+    fn deprecated_git_args_map_to_actions_or_are_rejected() -> Result<()> {
+        let t = steps_task(serde_json::json!([
+            { "type": "git", "action": "commit", "args": ["commit", "-am", "wip"] },
+            { "type": "git", "action": "anything", "args": ["checkout", "-b", "feature"] },
+            { "type": "git", "args": ["stash", "pop"], "retries": 1 },
+        ]))?;
+        let actions: Vec<&GitAction> = t.steps.iter().filter_map(|s| match &s.step { TaskStep::Git { action } => Some(action), _ => None }).collect();
+        assert_eq!(actions, [
+            &GitAction::Commit { message: "wip".into(), all: true },
+            &GitAction::Checkout { target: "feature".into(), create: true },
+            &GitAction::Stash { op: git_ops::StashOp::Pop, message: None },
+        ]);
+        assert_eq!(t.steps[2].policy.retries, Some(1));
+
+        let err = steps_task(serde_json::json!([{ "type": "git", "action": "push", "args": ["push", "origin", "main"] }])).unwrap_err();
+        assert!(err.to_string().contains(r#"git step args ["push", "origin", "main"] have no git action"#), "{}", err);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn worktrees_that_cannot_be_created_fail_their_tasks() -> Result<()> {
+        let h = Harness::new()?;
+        let mut s = set("parallel", vec![task("a", &[], "first"), task("b", &[], "second"), task("c", &["a"], "after")]);
+        s.worktrees = Some(WorktreeMode::Merge);
+        let plan = TaskSetPlan { session_id: "test".into(), sets: vec![s] };
+
+        let report = h.runner(&plan).run().await?;
+        let tasks = &report.sets[0].tasks;
+        assert_eq!(tasks.len(), 3);
+        for t in &tasks[..2] {
+            assert_eq!(t.outcome, TaskOutcome::Failed);
+            assert!(t.error.as_deref().is_some_and(|e| e.starts_with("worktree: worktree isolation needs a git repository")), "{:?}", t.error);
+        }
+        assert!(matches!(&tasks[2].outcome, TaskOutcome::Skipped { reason } if reason == "dependency 'a' did not succeed"));
+        assert!(h.outcome().ran.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn isolated_set_merges_task_worktrees_and_reports_conflicts() -> Result<()> {
+        let h = Harness::new()?;
+        let repo = git2::Repository::init(&h.root)?;
+        repo.config()?.set_str("user.name", "Test")?;
+        repo.config()?.set_str("user.email", "test@example.com")?;
+        std::fs::write(h.root.join("shared.txt"), "base\n")?;
+        git_ops::run(&h.root, &GitAction::Commit { message: "init".into(), all: true })?;
+        let write = |id: &str, file: &str, text: &str| -> Result<TaskSpec> {
+            Ok(serde_json::from_value(serde_json::json!({
+                "id": id, "name": id, "steps": [{ "type": "exec", "cmd": "write", "args": [file, text] }],
+            }))?)
+        };
+        let mut s = set("parallel", vec![write("a", "a.txt", "a\n")?, write("b", "shared.txt", "b\n")?, write("c", "shared.txt", "c\n")?]);
+        s.worktrees = Some(WorktreeMode::Merge);
+        let plan = TaskSetPlan { session_id: "test".into(), sets: vec![s] };
+
+        let report = h.runner(&plan).run().await?;
+        let results: Vec<&MergeResult> = report.sets[0].tasks.iter().filter_map(|t| t.worktree.as_ref()).collect();
+        assert!(matches!(results.as_slice(), [MergeResult::Merged { .. }, MergeResult::Merged { .. }, MergeResult::Conflict { files, .. }] if files == &["shared.txt"]));
+        assert_eq!(std::fs::read_to_string(h.root.join("a.txt"))?, "a\n");
+        assert_eq!(std::fs::read_to_string(h.root.join("shared.txt"))?, "b\n");
+        let Outcome { events, .. } = h.outcome();
+        assert!(events.iter().any(|e| matches!(e, UiEvent::TaskMerge { task_id, result: MergeResult::Conflict { .. }, .. } if task_id == "c")));
+        Ok(())
     }
 }
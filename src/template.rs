@@ -0,0 +1,126 @@
+// annex/src/template.rs — `{{namespace.path}}` placeholders inside task steps
+
+use anyhow::{Result, anyhow};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::sync::LazyLock;
+
+use crate::limits::LimitKind;
+
+static PLACEHOLDER: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"\{\{\s*([A-Za-z_][A-Za-z0-9_]*)\.([A-Za-z0-9_\-]+(?:\.[A-Za-z0-9_\-]+)*)\s*\}\}").unwrap()
+});
+
+/// What a step with an `id` produced; later steps of the same task read it as `{{steps.<id>.<field>}}`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct StepOutput {
+    pub ok: bool,
+    /// Exit code of exec/git steps.
+    pub exit_code: Option<i32>,
+    /// Output preview of exec/git steps.
+    pub stdout: String,
+    /// Model reply of chat steps.
+    pub reply: String,
+    /// Result of MCP calls.
+    pub response: serde_json::Value,
+    /// Files a git step staged, committed, stashed or reported as changed.
+    #[serde(default)]
+    pub files: Vec<String>,
+    /// Commit a git step created or moved to.
+    #[serde(default)]
+    pub commit: Option<String>,
+    /// Resource limit that stopped an exec step.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<LimitKind>,
+}
+
+impl StepOutput {
+    /// Resolve a field path: `ok`, `exit_code`, `stdout` (without its final line break), `reply`,
+    /// `files` (one per line), `commit`, `response` or `response.<key>...`.
+    pub fn field(&self, path: &str) -> Option<String> {
+        let (head, rest) = path.split_once('.').map_or((path, None), |(h, r)| (h, Some(r)));
+        match (head, rest) {
+            ("ok", None) => Some(self.ok.to_string()),
+            ("exit_code", None) => self.exit_code.map(|c| c.to_string()),
+            ("stdout", None) => {
+                let out = self.stdout.strip_suffix('\n').unwrap_or(&self.stdout);
+                Some(out.strip_suffix('\r').unwrap_or(out).to_string())
+            }
+            ("reply", None) => Some(self.reply.clone()),
+            ("files", None) => Some(self.files.join("\n")),
+            ("commit", None) => self.commit.clone(),
+            ("response", rest) => {
+                let mut v = &self.response;
+                for key in rest.into_iter().flat_map(|r| r.split('.')) {
+                    v = match v {
+                        serde_json::Value::Array(a) => a.get(key.parse::<usize>().ok()?)?,
+                        _ => v.get(key)?,
+                    };
+                }
+                Some(match v {
+                    serde_json::Value::String(s) => s.clone(),
+                    other => other.to_string(),
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Placeholders in `text` as `(namespace, path)` pairs, e.g. `("steps", "build.stdout")`.
+pub fn placeholders(text: &str) -> Vec<(String, String)> {
+    PLACEHOLDER.captures_iter(text).map(|c| (c[1].to_string(), c[2].to_string())).collect()
+}
+
+/// Substitute placeholders of `namespace` through `lookup(path)`. Placeholders of other
+/// namespaces are left untouched; a path `lookup` can't resolve is an error.
+pub fn render(text: &str, namespace: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<String> {
+    let mut out = String::with_capacity(text.len());
+    let mut last = 0;
+    for c in PLACEHOLDER.captures_iter(text) {
+        if &c[1] != namespace { continue; }
+        let m = c.get(0).unwrap();
+        let value = lookup(&c[2]).ok_or_else(|| anyhow!("undefined reference {{{{{}.{}}}}}", namespace, &c[2]))?;
+        out.push_str(&text[last..m.start()]);
+        out.push_str(&value);
+        last = m.end();
+    }
+    out.push_str(&text[last..]);
+    Ok(out)
+}
+
+/// Apply `f` to every string inside a JSON value (keys are left alone).
+pub fn map_json_strings(v: &serde_json::Value, f: &mut impl FnMut(&str) -> Result<String>) -> Result<serde_json::Value> {
+    Ok(match v {
+        serde_json::Value::String(s) => serde_json::Value::String(f(s)?),
+        serde_json::Value::Array(a) => serde_json::Value::Array(a.iter().map(|x| map_json_strings(x, f)).collect::<Result<_>>()?),
+        serde_json::Value::Object(m) => serde_json::Value::Object(
+            m.iter().map(|(k, x)| Ok((k.clone(), map_json_strings(x, f)?))).collect::<Result<_>>()?,
+        ),
+        other => other.clone(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_resolves_only_its_namespace() -> Result<()> {
+        let out = StepOutput { ok: true, response: serde_json::json!({ "items": [{ "id": 7 }] }), ..Default::default() };
+        let text = "id={{steps.q.response.items.0.id}} keep={{matrix.os}} raw={{ not a ref }}";
+        let rendered = render(text, "steps", |p| p.strip_prefix("q.").and_then(|f| out.field(f)))?;
+        assert_eq!(rendered, "id=7 keep={{matrix.os}} raw={{ not a ref }}");
+        let err = render("{{steps.missing.stdout}}", "steps", |_| None).unwrap_err();
+        assert_eq!(err.to_string(), "undefined reference {{steps.missing.stdout}}");
+        Ok(())
+    }
+
+    #[test]
+    fn stdout_drops_one_trailing_line_break() {
+        let field = |stdout: &str| StepOutput { stdout: stdout.into(), ..Default::default() }.field("stdout");
+        assert_eq!(field("main\n").as_deref(), Some("main"));
+        assert_eq!(field("main\r\n").as_deref(), Some("main"));
+        assert_eq!(field("a\nb\n\n").as_deref(), Some("a\nb\n"));
+    }
+}
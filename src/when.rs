@@ -0,0 +1,280 @@
+// annex/src/when.rs — `when` conditions on tasks and task steps
+//
+//   failure() && steps.test.exit_code != 0
+//   changed("src/**/*.rs", "Cargo.toml") || env.FORCE == "1"
+//
+// Operands: `steps.<id>.<field>` (see `StepOutput::field`), `env.<NAME>`, strings, integers,
+// `true`/`false`/`null` and the functions `success()`, `failure()`, `always()` and
+// `changed(<glob>, ...)`. Operators: `!`, `&&`, `||`, `==`, `!=`, `<`, `<=`, `>`, `>=` and parentheses.
+
+use anyhow::{anyhow, bail, Result};
+use globset::{Glob, GlobSetBuilder};
+use std::{collections::BTreeMap, path::Path};
+
+use crate::{git_ops, template::StepOutput};
+
+#[derive(Clone, Debug, PartialEq)]
+enum Value { Null, Bool(bool), Num(i64), Str(String) }
+
+impl Value {
+    /// Step fields and env vars arrive as text; read them back as the most specific value.
+    fn from_text(s: &str) -> Value {
+        match s {
+            "true" => Value::Bool(true),
+            "false" => Value::Bool(false),
+            _ => s.parse().map(Value::Num).unwrap_or_else(|_| Value::Str(s.into())),
+        }
+    }
+
+    fn truthy(&self) -> bool {
+        match self {
+            Value::Null => false,
+            Value::Bool(b) => *b,
+            Value::Num(n) => *n != 0,
+            Value::Str(s) => !s.is_empty(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum CmpOp { Eq, Ne, Lt, Le, Gt, Ge }
+
+#[derive(Clone, Debug, PartialEq)]
+enum Expr {
+    Lit(Value),
+    /// `(namespace, path)`, e.g. `("steps", "build.exit_code")`.
+    Ref(String, String),
+    Call(String, Vec<Expr>),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Cmp(CmpOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Tok { Ident(String), Str(String), Num(i64), Op(&'static str) }
+
+const OPS: &[&str] = &["&&", "||", "==", "!=", "<=", ">=", "<", ">", "!", "(", ")", ","];
+
+fn lex(src: &str) -> Result<Vec<Tok>> {
+    let mut toks = vec![];
+    let mut rest = src.trim_start();
+    while !rest.is_empty() {
+        let c = rest.chars().next().unwrap_or_default();
+        if let Some(op) = OPS.iter().find(|op| rest.starts_with(**op)) {
+            toks.push(Tok::Op(op));
+            rest = &rest[op.len()..];
+        } else if c == '"' || c == '\'' {
+            let end = rest[1..].find(c).ok_or_else(|| anyhow!("unterminated string in `{}`", src))?;
+            toks.push(Tok::Str(rest[1..=end].to_string()));
+            rest = &rest[end + 2..];
+        } else if c.is_ascii_digit() || (c == '-' && rest[1..].starts_with(|d: char| d.is_ascii_digit())) {
+            let end = rest[1..].find(|d: char| !d.is_ascii_digit()).map_or(rest.len(), |e| e + 1);
+            toks.push(Tok::Num(rest[..end].parse()?));
+            rest = &rest[end..];
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let end = rest.find(|d: char| !(d.is_ascii_alphanumeric() || matches!(d, '_' | '-' | '.'))).unwrap_or(rest.len());
+            toks.push(Tok::Ident(rest[..end].to_string()));
+            rest = &rest[end..];
+        } else {
+            bail!("unexpected '{}' in `{}`", c, src);
+        }
+        rest = rest.trim_start();
+    }
+    Ok(toks)
+}
+
+struct Parser { toks: Vec<Tok>, pos: usize }
+
+impl Parser {
+    fn peek(&self) -> Option<&Tok> { self.toks.get(self.pos) }
+    fn eat(&mut self, op: &str) -> bool {
+        let hit = matches!(self.peek(), Some(Tok::Op(o)) if *o == op);
+        if hit { self.pos += 1; }
+        hit
+    }
+    fn expect(&mut self, op: &str) -> Result<()> {
+        if self.eat(op) { Ok(()) } else { bail!("expected '{}'", op) }
+    }
+
+    fn or(&mut self) -> Result<Expr> {
+        let mut lhs = self.and()?;
+        while self.eat("||") { lhs = Expr::Or(Box::new(lhs), Box::new(self.and()?)); }
+        Ok(lhs)
+    }
+
+    fn and(&mut self) -> Result<Expr> {
+        let mut lhs = self.not()?;
+        while self.eat("&&") { lhs = Expr::And(Box::new(lhs), Box::new(self.not()?)); }
+        Ok(lhs)
+    }
+
+    fn not(&mut self) -> Result<Expr> {
+        if self.eat("!") { return Ok(Expr::Not(Box::new(self.not()?))); }
+        let lhs = self.operand()?;
+        let op = [("==", CmpOp::Eq), ("!=", CmpOp::Ne), ("<=", CmpOp::Le), (">=", CmpOp::Ge), ("<", CmpOp::Lt), (">", CmpOp::Gt)]
+            .into_iter().find(|(s, _)| self.eat(s));
+        Ok(match op {
+            Some((_, op)) => Expr::Cmp(op, Box::new(lhs), Box::new(self.operand()?)),
+            None => lhs,
+        })
+    }
+
+    fn operand(&mut self) -> Result<Expr> {
+        let tok = self.peek().cloned().ok_or_else(|| anyhow!("unexpected end of expression"))?;
+        self.pos += 1;
+        Ok(match tok {
+            Tok::Op("(") => {
+                let e = self.or()?;
+                self.expect(")")?;
+                e
+            }
+            Tok::Str(s) => Expr::Lit(Value::Str(s)),
+            Tok::Num(n) => Expr::Lit(Value::Num(n)),
+            Tok::Ident(id) if self.eat("(") => {
+                let mut args = vec![];
+                if !self.eat(")") {
+                    loop {
+                        args.push(self.or()?);
+                        if self.eat(")") { break; }
+                        self.expect(",")?;
+                    }
+                }
+                match (id.as_str(), args.len()) {
+                    ("success" | "failure" | "always", 0) => {}
+                    ("changed", n) if n > 0 && args.iter().all(|a| matches!(a, Expr::Lit(Value::Str(_)))) => {}
+                    ("changed", _) => bail!("changed() takes one or more glob strings"),
+                    _ => bail!("unknown function {}() with {} argument(s)", id, args.len()),
+                }
+                Expr::Call(id, args)
+            }
+            Tok::Ident(id) => match id.as_str() {
+                "true" => Expr::Lit(Value::Bool(true)),
+                "false" => Expr::Lit(Value::Bool(false)),
+                "null" => Expr::Lit(Value::Null),
+                _ => match id.split_once('.') {
+                    Some((ns @ ("steps" | "env"), path)) if !path.is_empty() => Expr::Ref(ns.into(), path.into()),
+                    _ => bail!("unknown name '{}' (expected steps.<id>.<field> or env.<NAME>)", id),
+                },
+            },
+            Tok::Op(op) => bail!("unexpected '{}'", op),
+        })
+    }
+}
+
+/// What a condition can see while a task runs.
+pub struct Scope<'a> {
+    /// Outputs of earlier steps that have an `id` and ran.
+    pub steps: &'a BTreeMap<String, StepOutput>,
+    /// An earlier step of the task failed.
+    pub failed: bool,
+    /// Checked before the process environment.
+    pub env: &'a BTreeMap<String, String>,
+    /// Where `changed()` looks for a git repository.
+    pub cwd: &'a Path,
+}
+
+/// A parsed `when` expression.
+#[derive(Clone, Debug)]
+pub struct Condition { src: String, expr: Expr }
+
+impl Condition {
+    pub fn parse(src: &str) -> Result<Self> {
+        let mut p = Parser { toks: lex(src)?, pos: 0 };
+        let expr = p.or().map_err(|e| anyhow!("in `{}`: {}", src, e))?;
+        if let Some(tok) = p.peek() { bail!("in `{}`: unexpected {:?}", src, tok); }
+        Ok(Self { src: src.into(), expr })
+    }
+
+    pub fn source(&self) -> &str { &self.src }
+
+    /// `steps.<path>` references, e.g. `"build.exit_code"`.
+    pub fn step_refs(&self) -> Vec<&str> {
+        fn walk<'e>(e: &'e Expr, out: &mut Vec<&'e str>) {
+            match e {
+                Expr::Ref(ns, path) if ns == "steps" => out.push(path),
+                Expr::Not(a) => walk(a, out),
+                Expr::And(a, b) | Expr::Or(a, b) | Expr::Cmp(_, a, b) => { walk(a, out); walk(b, out); }
+                Expr::Call(_, args) => args.iter().for_each(|a| walk(a, out)),
+                _ => {}
+            }
+        }
+        let mut out = vec![];
+        walk(&self.expr, &mut out);
+        out
+    }
+
+    pub fn eval(&self, scope: &Scope<'_>) -> Result<bool> {
+        let mut changed = None;
+        Ok(eval(&self.expr, scope, &mut changed)?.truthy())
+    }
+}
+
+fn eval(e: &Expr, scope: &Scope<'_>, changed: &mut Option<Vec<String>>) -> Result<Value> {
+    Ok(match e {
+        Expr::Lit(v) => v.clone(),
+        Expr::Ref(ns, path) => {
+            let text = if ns == "env" {
+                scope.env.get(path).cloned().or_else(|| std::env::var(path).ok())
+            } else {
+                path.split_once('.').and_then(|(id, field)| scope.steps.get(id)?.field(field))
+            };
+            text.map_or(Value::Null, |t| Value::from_text(&t))
+        }
+        Expr::Call(f, args) => Value::Bool(match f.as_str() {
+            "success" => !scope.failed,
+            "failure" => scope.failed,
+            "always" => true,
+            _ => {
+                let mut globs = GlobSetBuilder::new();
+                for a in args {
+                    if let Expr::Lit(Value::Str(g)) = a { globs.add(Glob::new(g)?); }
+                }
+                let globs = globs.build()?;
+                if changed.is_none() { *changed = Some(git_ops::changed_files(scope.cwd)?); }
+                changed.iter().flatten().any(|f| globs.is_match(f))
+            }
+        }),
+        Expr::Not(a) => Value::Bool(!eval(a, scope, changed)?.truthy()),
+        Expr::And(a, b) => Value::Bool(eval(a, scope, changed)?.truthy() && eval(b, scope, changed)?.truthy()),
+        Expr::Or(a, b) => Value::Bool(eval(a, scope, changed)?.truthy() || eval(b, scope, changed)?.truthy()),
+        Expr::Cmp(op, a, b) => {
+            let (a, b) = (eval(a, scope, changed)?, eval(b, scope, changed)?);
+            Value::Bool(match (op, &a, &b) {
+                (CmpOp::Eq, ..) => a == b,
+                (CmpOp::Ne, ..) => a != b,
+                (op, Value::Num(x), Value::Num(y)) => match op {
+                    CmpOp::Lt => x < y,
+                    CmpOp::Le => x <= y,
+                    CmpOp::Gt => x > y,
+                    _ => x >= y,
+                },
+                _ => bail!("cannot order {:?} and {:?}", a, b),
+            })
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conditions_read_steps_env_and_status() -> Result<()> {
+        let steps = BTreeMap::from([("build".to_string(), StepOutput { ok: false, exit_code: Some(101), ..Default::default() })]);
+        let env = BTreeMap::from([("CI".to_string(), "1".to_string())]);
+        let scope = Scope { steps: &steps, failed: true, env: &env, cwd: Path::new(".") };
+        let check = |src: &str| Condition::parse(src).and_then(|c| c.eval(&scope));
+        assert!(check("failure() && steps.build.exit_code >= 100")?);
+        assert!(check("!steps.build.ok && (env.CI == 1 || false)")?);
+        assert!(!check("success() || steps.missing.ok")?);
+        assert!(check("env.CI != 'yes'")?);
+        assert!(check("steps.build.exit_code > 'x'").is_err());
+
+        assert!(Condition::parse("steps.build.ok &&").is_err());
+        assert!(Condition::parse("changed()").is_err());
+        assert!(Condition::parse("outputs.x").is_err());
+        assert_eq!(Condition::parse("changed('a') || steps.b.ok == steps.c.exit_code")?.step_refs(), vec!["b.ok", "c.exit_code"]);
+        Ok(())
+    }
+}
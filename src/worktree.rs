@@ -0,0 +1,181 @@
+// annex/src/worktree.rs — per-task git worktrees for isolated task sets
+
+use anyhow::{Context, Result};
+use git2::{build::CheckoutBuilder, BranchType, Oid, Repository, WorktreeAddOptions, WorktreePruneOptions};
+use serde::{Deserialize, Serialize};
+use std::{fs, path::{Path, PathBuf}};
+
+use crate::git_ops::{self, GitAction};
+
+/// What happens to the task branches of an isolated set once it ends.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WorktreeMode {
+    /// Merge the branch of every succeeded task into HEAD, in declaration order.
+    Merge,
+    /// Leave every branch for review.
+    Keep,
+}
+
+/// Fate of one task branch.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum MergeResult {
+    /// The task changed nothing; its branch was deleted.
+    Unchanged,
+    Merged { commit: String },
+    /// Left as a branch (`keep` mode, or the task did not succeed).
+    Kept { branch: String },
+    /// Merging into HEAD would conflict in `files`; the branch is kept.
+    Conflict { branch: String, files: Vec<String> },
+}
+
+/// A worktree on its own temporary branch, checked out under `.git/codex-worktrees/`.
+pub struct TaskWorktree {
+    repo_dir: PathBuf,
+    name: String,
+    pub path: PathBuf,
+    /// Where the task runs: the directory `create` was given, at the same place in the worktree.
+    pub cwd: PathBuf,
+    pub branch: String,
+    task_id: String,
+    base: Oid,
+}
+
+impl TaskWorktree {
+    /// Branch `codex/<set>/<task>-<suffix>` off HEAD of the repository containing `dir` and check it out.
+    /// Blocking; call from `spawn_blocking`.
+    pub fn create(dir: &Path, set_id: &str, task_id: &str) -> Result<Self> {
+        let repo = Repository::discover(dir).with_context(|| format!("worktree isolation needs a git repository at {}", dir.display()))?;
+        let repo_dir = repo.workdir().context("worktree isolation needs a non-bare repository")?.to_path_buf();
+        let head = repo.head()?.peel_to_commit().context("worktree isolation needs a commit at HEAD")?;
+        let suffix = &uuid::Uuid::new_v4().simple().to_string()[..8];
+        let (set, task) = (sanitize(set_id), sanitize(task_id));
+        let name = format!("codex-{}-{}-{}", set, task, suffix);
+        let branch = format!("codex/{}/{}-{}", set, task, suffix);
+        let reference = repo.branch(&branch, &head, false)?.into_reference();
+        let path = repo.path().join("codex-worktrees").join(&name);
+        fs::create_dir_all(path.parent().unwrap_or(&path))?;
+        repo.worktree(&name, &path, Some(WorktreeAddOptions::new().reference(Some(&reference))))?;
+        // `dir` may be a subdirectory; one that isn't tracked has to be made in the worktree.
+        let subdir = dir.canonicalize()?.strip_prefix(repo_dir.canonicalize()?).map(Path::to_path_buf).unwrap_or_default();
+        let cwd = path.join(subdir);
+        fs::create_dir_all(&cwd)?;
+        Ok(Self { repo_dir, name, path, cwd, branch, task_id: task_id.into(), base: head.id() })
+    }
+
+    /// Commit what the task left uncommitted, remove the worktree, then merge the branch into
+    /// HEAD when `merge` is set. A branch that gained no commits is deleted. Blocking; call from
+    /// `spawn_blocking`.
+    pub fn finish(self, merge: bool) -> Result<MergeResult> {
+        if !git_ops::run(&self.path, &GitAction::Status { paths: vec![] })?.files.is_empty() {
+            let message = format!("codex task {}: uncommitted changes", self.task_id);
+            git_ops::run(&self.path, &GitAction::Commit { message, all: true })?;
+        }
+        let repo = Repository::open(&self.repo_dir)?;
+        repo.find_worktree(&self.name)?.prune(Some(WorktreePruneOptions::new().valid(true).working_tree(true)))?;
+        let tip = repo.find_branch(&self.branch, BranchType::Local)?.get().peel_to_commit()?;
+        if tip.id() == self.base {
+            repo.find_branch(&self.branch, BranchType::Local)?.delete()?;
+            return Ok(MergeResult::Unchanged);
+        }
+        if !merge { return Ok(MergeResult::Kept { branch: self.branch }); }
+
+        let head = repo.head()?;
+        let ours = head.peel_to_commit()?;
+        let merged = if repo.graph_descendant_of(tip.id(), ours.id())? {
+            tip.id()
+        } else {
+            let mut index = repo.merge_commits(&ours, &tip, None)?;
+            if index.has_conflicts() {
+                let files = index.conflicts()?.filter_map(|c| {
+                    let c = c.ok()?;
+                    let entry = c.our.or(c.their).or(c.ancestor)?;
+                    Some(String::from_utf8_lossy(&entry.path).into_owned())
+                }).collect();
+                return Ok(MergeResult::Conflict { branch: self.branch, files });
+            }
+            let tree = repo.find_tree(index.write_tree_to(&repo)?)?;
+            let sig = repo.signature().context("git user.name/user.email are not configured")?;
+            let message = format!("Merge task {} ({})", self.task_id, self.branch);
+            repo.commit(None, &sig, &sig, &message, &tree, &[&ours, &tip])?
+        };
+        // Bring the main working tree along first; local edits to the same files block the merge.
+        if repo.checkout_tree(&repo.find_object(merged, None)?, Some(CheckoutBuilder::new().safe())).is_err() {
+            let files = changed_files(&repo, self.base, tip.id())?;
+            return Ok(MergeResult::Conflict { branch: self.branch, files });
+        }
+        if head.is_branch() {
+            repo.head()?.set_target(merged, &format!("codex: merge task {}", self.task_id))?;
+        } else {
+            repo.set_head_detached(merged)?;
+        }
+        repo.find_branch(&self.branch, BranchType::Local)?.delete()?;
+        Ok(MergeResult::Merged { commit: merged.to_string() })
+    }
+}
+
+fn changed_files(repo: &Repository, from: Oid, to: Oid) -> Result<Vec<String>> {
+    let (a, b) = (repo.find_commit(from)?.tree()?, repo.find_commit(to)?.tree()?);
+    let diff = repo.diff_tree_to_tree(Some(&a), Some(&b), None)?;
+    Ok(diff.deltas().filter_map(|d| d.new_file().path().map(|p| p.to_string_lossy().into_owned())).collect())
+}
+
+/// Keep ids usable in branch and worktree names.
+fn sanitize(id: &str) -> String {
+    id.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '-' }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn repo_with_commit() -> Result<(tempfile::TempDir, Repository)> {
+        let dir = tempfile::tempdir()?;
+        let repo = Repository::init(dir.path())?;
+        repo.config()?.set_str("user.name", "Test")?;
+        repo.config()?.set_str("user.email", "test@example.com")?;
+        fs::write(dir.path().join("shared.txt"), "base\n")?;
+        git_ops::run(dir.path(), &GitAction::Commit { message: "init".into(), all: true })?;
+        Ok((dir, repo))
+    }
+
+    #[test]
+    fn branches_merge_back_or_report_conflicts() -> Result<()> {
+        let (dir, repo) = repo_with_commit()?;
+        let a = TaskWorktree::create(dir.path(), "s1", "a")?;
+        let b = TaskWorktree::create(dir.path(), "s1", "b")?;
+        let c = TaskWorktree::create(dir.path(), "s1", "c")?;
+        let idle = TaskWorktree::create(dir.path(), "s1", "idle")?;
+        fs::write(a.path.join("a.txt"), "from a\n")?;
+        fs::write(b.path.join("shared.txt"), "from b\n")?;
+        fs::write(c.path.join("shared.txt"), "from c\n")?;
+
+        assert!(matches!(a.finish(true)?, MergeResult::Merged { .. }));
+        assert!(matches!(b.finish(true)?, MergeResult::Merged { .. }));
+        let c_branch = c.branch.clone();
+        assert_eq!(c.finish(true)?, MergeResult::Conflict { branch: c_branch.clone(), files: vec!["shared.txt".into()] });
+        assert_eq!(idle.finish(true)?, MergeResult::Unchanged);
+
+        assert_eq!(fs::read_to_string(dir.path().join("a.txt"))?, "from a\n");
+        assert_eq!(fs::read_to_string(dir.path().join("shared.txt"))?, "from b\n");
+        assert!(repo.find_branch(&c_branch, BranchType::Local).is_ok());
+        assert_eq!(repo.worktrees()?.len(), 0);
+        assert!(git_ops::run(dir.path(), &GitAction::Status { paths: vec![] })?.files.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn tasks_run_in_the_same_subdirectory_of_the_worktree() -> Result<()> {
+        let (dir, _repo) = repo_with_commit()?;
+        fs::create_dir_all(dir.path().join("crates/core"))?;
+        let wt = TaskWorktree::create(&dir.path().join("crates/core"), "s1", "a")?;
+        assert_eq!(wt.cwd, wt.path.join("crates/core"));
+        assert!(wt.cwd.is_dir());
+        let top = TaskWorktree::create(dir.path(), "s1", "b")?;
+        assert_eq!(top.cwd, top.path);
+        wt.finish(false)?;
+        top.finish(false)?;
+        Ok(())
+    }
+}